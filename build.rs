@@ -1,6 +1,38 @@
-use std::io::Result;
+use std::{io::Result, path::{Path, PathBuf}, process::Command};
 
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["src/PreloadedUserSettings.proto"], &["src/"])?;
+    // Keep the descriptors too, so `dexcord proto` can show what the build supports
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    prost_build::Config::new()
+    .file_descriptor_set_path(out_dir.join("discord_protocols.bin"))
+    .compile_protos(&["src/PreloadedUserSettings.proto"], &["src/"])?;
+
+    // Embed the git hash and build time, which are shown by `--version`
+    let git_hash = Command::new("git")
+    .args(["rev-parse", "--short", "HEAD"])
+    .output()
+    .ok()
+    .filter(|o| o.status.success())
+    .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=DEXCORD_GIT_HASH={git_hash}");
+
+    // Respect SOURCE_DATE_EPOCH so reproducible builds stay reproducible
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+    .ok()
+    .and_then(|s| s.parse().ok())
+    .and_then(|s| chrono::DateTime::from_timestamp(s, 0))
+    .unwrap_or_else(chrono::Utc::now);
+    println!("cargo:rustc-env=DEXCORD_BUILD_TIME={}", build_time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // Rebuild when the checked out commit changes
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    println!("cargo:rerun-if-changed=src/PreloadedUserSettings.proto");
+
     Ok(())
 }
@@ -0,0 +1,232 @@
+//
+// Webhook alerts for out of range (or suspiciously flat) glucose readings
+//
+
+use chrono::{DateTime, Utc};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{error, trace};
+use crate::{dexcom::GlucoseUnit, http::RequestExt};
+
+/// A webhook that is notified about out of range readings and flatlines
+#[derive(Debug)]
+pub struct Webhook {
+    /// The HTTP client
+    client: reqwest::Client,
+    /// The URL of the webhook
+    url: String
+}
+impl Webhook {
+    /// Creates a webhook that posts to the URL
+    pub fn new(url: &str, proxy: Option<reqwest::Proxy>) -> Self {
+        let mut builder = reqwest::ClientBuilder::default();
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy);
+        }
+        let client = builder
+        .build()
+        .context("Failed to create HTTP client for the alert webhook").unwrap();
+
+        Self {
+            client,
+            url: url.to_string()
+        }
+    }
+
+    /// Sends an alert to the webhook
+    pub async fn send(&self, alert: &Alert) -> Result<()> {
+        let response = self.client.post(&self.url)
+        .json(alert)
+        .send_logged().await?;
+
+        if !response.status().is_success() {
+            let body = response.text().await?;
+            error!("Failed to send alert: {body}");
+            Err(Error::Rejected(body))?
+        }
+
+        trace!("Sent alert for {} mg/dL", alert.value);
+        Ok(())
+    }
+}
+
+/// The body of an alert. This shape is stable, so fields are only ever added.
+/// 
+/// ```json
+/// {
+///     "content": "Tell me to eat something, I'm a little low (3.4 mmol/L)",
+///     "value": 62,
+///     "unit": "mmol/L",
+///     "value_in_unit": 3.4,
+///     "level": -1,
+///     "previous_value": 95,
+///     "delta": -33,
+///     "elapsed_minutes": 10,
+///     "trend": "DoubleDown",
+///     "arrow": "⇊",
+///     "rate": -3.3,
+///     "flatline": false,
+///     "severity": "heads-up",
+///     "color": "#ffaa00"
+/// }
+/// ```
+/// 
+/// - NOTE: The text is sent as `content`, so discord webhooks can be used directly
+/// - NOTE: `value`, `previous_value`, `delta`, and `rate` are always in mg/dL, so the payload doesn't change with the unit
+/// - NOTE: `previous_value`, `delta`, and `elapsed_minutes` are `null` for the first alert, and `rate` is `null` until there are two readings
+/// - NOTE: `severity` and `color` come from the [`AlertRule`] that matched, so they're `null` for alerts from the bucket levels
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    /// The text of the alert (the formatted status)
+    pub content: String,
+    /// The glucose value (in mg/dL)
+    pub value: u32,
+    /// The configured unit (e.g. `"mmol/L"`)
+    pub unit: GlucoseUnit,
+    /// The glucose value in the configured unit
+    pub value_in_unit: f64,
+    /// The level of the bucket the reading is in
+    pub level: i8,
+    /// The glucose value of the last alert (in mg/dL)
+    pub previous_value: Option<u32>,
+    /// How much the glucose value changed since the last alert (in mg/dL)
+    pub delta: Option<i64>,
+    /// How long ago the last alert was (in minutes)
+    pub elapsed_minutes: Option<i64>,
+    /// The trend reported by the source (e.g. `"SingleDown"`)
+    pub trend: String,
+    /// The trend as an arrow (e.g. `"↓"`), or empty if there's no direction
+    pub arrow: String,
+    /// The rate of change (in mg/dL per minute)
+    pub rate: Option<f64>,
+    /// Whether the alert is about the readings being flat for too long, rather than being out of range
+    pub flatline: bool,
+    /// The severity of the alert rule that matched (e.g. `"urgent"`)
+    pub severity: Option<String>,
+    /// The color of the alert rule that matched (e.g. `"#ff0000"`)
+    pub color: Option<String>
+}
+
+/// Which side of its threshold an alert rule matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertDirection {
+    /// Readings at or below the threshold
+    Low,
+    /// Readings at or above the threshold
+    High
+}
+
+/// An alert that is sent when a reading crosses a threshold, so there can be tiers (e.g. a heads up at 80 and an urgent alert at 55)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    /// Whether the rule is for lows or highs
+    pub direction: AlertDirection,
+    /// The threshold (in mg/dL), which is included in the rule
+    pub threshold: u32,
+    /// The name of the tier, which is sent with the alert (e.g. `"urgent"`)
+    pub severity: String,
+    /// The text of the alert, with the same placeholders as the buckets. The status is sent if this is unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// The color sent with the alert (e.g. `"#ff0000"`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>
+}
+impl AlertRule {
+    /// Returns true if the glucose value is on the rule's side of the threshold
+    pub fn matches(&self, value: u32) -> bool {
+        match self.direction {
+            AlertDirection::Low => value <= self.threshold,
+            AlertDirection::High => value >= self.threshold
+        }
+    }
+}
+
+/// Returns the index of the most severe rule the glucose value matches (the one with the most extreme threshold)
+/// 
+/// - NOTE: If a value matches both low and high rules (i.e. they overlap), the low rules win
+pub fn matching_rule(rules: &[AlertRule], value: u32) -> Option<usize> {
+    let matching = || rules.iter().enumerate().filter(|(_, r)| r.matches(value));
+    matching().filter(|(_, r)| r.direction == AlertDirection::Low).min_by_key(|(_, r)| r.threshold)
+    .or_else(|| matching().max_by_key(|(_, r)| r.threshold))
+    .map(|(i, _)| i)
+}
+
+/// The reading of the last alert that was sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LastAlert {
+    /// The glucose value
+    pub value: u32,
+    /// The time of the reading
+    pub at: DateTime<Utc>
+}
+
+/// Alerts of the same severity are suppressed until the snooze expires
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snooze {
+    /// The level of the bucket that was alerted about
+    pub level: i8,
+    /// When the snooze expires
+    pub until: DateTime<Utc>
+}
+
+/// Returns true if an alert should be sent for a reading in a bucket of the provided level
+/// 
+/// - NOTE: A snoozed alert is still sent if the reading crosses into a more severe bucket (or to the other side of the range)
+pub fn should_alert(level: i8, snooze: Option<&Snooze>, now: DateTime<Utc>) -> bool {
+    // In range readings are never alerted about
+    if level == 0 {
+        return false;
+    }
+
+    match snooze {
+        Some(snooze) if now < snooze.until => {
+            level.signum() != snooze.level.signum() || level.abs() > snooze.level.abs()
+        },
+        _ => true
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("The webhook rejected the alert: {0}")]
+    Rejected(String)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+    use super::*;
+
+    fn snooze(level: i8) -> Snooze {
+        Snooze { level, until: DateTime::UNIX_EPOCH + TimeDelta::minutes(30) }
+    }
+
+    #[test]
+    fn snooze_suppresses_same_severity() {
+        assert!(!should_alert(-1, Some(&snooze(-1)), DateTime::UNIX_EPOCH));
+        assert!(!should_alert(-1, Some(&snooze(-2)), DateTime::UNIX_EPOCH));
+    }
+
+    #[test]
+    fn snooze_allows_more_severe_buckets() {
+        assert!(should_alert(-2, Some(&snooze(-1)), DateTime::UNIX_EPOCH));
+        assert!(should_alert(1, Some(&snooze(-1)), DateTime::UNIX_EPOCH));
+    }
+
+    #[test]
+    fn the_most_severe_rule_matches() {
+        let rule = |direction, threshold| AlertRule { direction, threshold, severity: String::new(), message: None, color: None };
+        let rules = [rule(AlertDirection::Low, 80), rule(AlertDirection::Low, 55), rule(AlertDirection::High, 250)];
+        assert_eq!(matching_rule(&rules, 81), None);
+        assert_eq!(matching_rule(&rules, 80), Some(0));
+        assert_eq!(matching_rule(&rules, 50), Some(1));
+        assert_eq!(matching_rule(&rules, 300), Some(2));
+    }
+
+    #[test]
+    fn snooze_expires() {
+        assert!(should_alert(-1, Some(&snooze(-1)), DateTime::UNIX_EPOCH + TimeDelta::minutes(30)));
+    }
+}
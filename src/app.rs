@@ -0,0 +1,1339 @@
+//
+// The state of the program between loop iterations, and the loop body itself
+//
+
+use std::{collections::{BTreeMap, VecDeque}, future::Future, panic::AssertUnwindSafe, time::{Duration, Instant}};
+use tokio::time::{Interval, MissedTickBehavior};
+use chrono::{DateTime, TimeDelta, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use anyhow::Result;
+use futures::{stream, FutureExt, StreamExt};
+use tracing::{debug, error, info, trace, warn};
+use crate::{alert::{self, Alert, LastAlert, Snooze, Webhook}, cli::Emit, config::Config, console, control, dexcom::{self, GlucoseMeasurement}, discord, error::{AppError, Recovery}, gateway::{self, Activity, ActivityType, Gateway}, health::Subsystem, hooks, http, mqtt, nightscout, script::{self, StatusScript}, source::{self, GlucoseSource}, sqlite, status::{self, Status}, systemd, trend};
+
+/// The maximum number of recent readings kept in the loop state (2 hours of readings)
+const READING_BUFFER_SIZE: usize = 24;
+/// How many minutes apart the oldest and newest readings in a full buffer are
+pub const MAX_BUFFER_MINUTES: u32 = (READING_BUFFER_SIZE as u32 - 1) * 5;
+/// How long to wait between loop iterations
+const POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The outcome of a single loop iteration
+#[derive(Debug, PartialEq, Eq)]
+pub enum TickOutcome {
+    /// The discord status was updated
+    Updated,
+    /// Nothing was updated (e.g. there was no measurement, or the status update failed)
+    Skipped,
+    /// The tick should be retried immediately (e.g. the dexcom session expired)
+    RetryNow,
+    /// The loop can't continue without manual intervention (e.g. discord wants a captcha to be solved)
+    Halt,
+    /// The dexcom session couldn't be renewed after every retry (what happens next depends on `on_max_retries`)
+    MaxRetriesReached
+}
+
+/// What to do once the dexcom session couldn't be renewed after every retry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnMaxRetries {
+    /// Exit the program, so a supervisor can restart it fresh
+    Exit,
+    /// Keep looping, backing off as if the fetches were failing
+    #[default]
+    Backoff
+}
+
+/// How the loop waits between iterations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoopTiming {
+    /// Sleep for what's left of the interval after each tick
+    Sleep,
+    /// Wait for the next tick of a fixed interval, so the ticks stay on the same cadence all day
+    #[default]
+    Interval
+}
+
+/// What the interval does when a tick is missed, because an iteration ran past the next one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedTicks {
+    /// Drop the missed ticks and keep the original cadence, so the next tick starts at the next multiple of the interval
+    #[default]
+    Skip,
+    /// Start the next tick right away, and shift the cadence so the one after it is a full interval later
+    Delay
+}
+impl From<MissedTicks> for MissedTickBehavior {
+    fn from(missed: MissedTicks) -> Self {
+        match missed {
+            MissedTicks::Skip => Self::Skip,
+            MissedTicks::Delay => Self::Delay
+        }
+    }
+}
+
+/// Waits between the loop iterations, according to `loop_timing`
+pub struct Schedule {
+    /// The fixed interval the ticks follow (`None` if the loop sleeps instead)
+    interval: Option<Interval>
+}
+impl Schedule {
+    pub fn new(timing: LoopTiming, missed: MissedTicks) -> Self {
+        Self::with_period(timing, missed, POLL_INTERVAL)
+    }
+
+    /// Creates a schedule whose first wait ends a period from now
+    fn with_period(timing: LoopTiming, missed: MissedTicks, period: Duration) -> Self {
+        let interval = (timing == LoopTiming::Interval).then(|| {
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(missed.into());
+            interval
+        });
+        Self { interval }
+    }
+
+    /// Waits until the next loop iteration should start, after one that took `elapsed`
+    /// 
+    /// - NOTE: `poll_interval` is rolled once by the caller with [`App::poll_interval`], since it's jittered and the caller may log it
+    /// - NOTE: While backing off, this sleeps like [`LoopTiming::Sleep`] and then restarts the cadence, since the interval can't stretch
+    pub async fn wait(&mut self, app: &App, elapsed: Duration, poll_interval: Duration) {
+        if let Some(sleep) = self.sleep_for(app, elapsed, poll_interval) {
+            tokio::time::sleep(sleep).await;
+            if let Some(interval) = &mut self.interval {
+                interval.reset();
+            }
+            return;
+        }
+        let Some(interval) = &mut self.interval else {
+            return;
+        };
+        if elapsed > interval.period() {
+            warn!("The last loop iteration took {elapsed:?}, which is longer than the interval ({:?})", interval.period());
+        }
+        interval.tick().await;
+    }
+
+    /// Returns how long to sleep before the next iteration, or `None` if it waits for the next tick of the interval instead
+    /// 
+    /// - NOTE: Whether we're backing off is decided before the jitter, since the jittered interval can be as short as the usual one
+    pub fn sleep_for(&self, app: &App, elapsed: Duration, poll_interval: Duration) -> Option<Duration> {
+        match &self.interval {
+            Some(_) if !app.is_backing_off() => None,
+            _ => Some(app.sleep_after(elapsed, poll_interval))
+        }
+    }
+}
+
+/// Everything the loop needs to update the status
+pub struct App {
+    /// The application configuration
+    pub config: Config,
+    /// The glucose sources, in order of priority
+    pub sources: Vec<Box<dyn GlucoseSource>>,
+    /// The discord API
+    pub discord_api: discord::Api,
+    /// The other discord accounts that show the same status (only for the user backend)
+    pub extra_discord_apis: Vec<ExtraAccount>,
+    /// The discord gateway connection (only for the bot backend)
+    pub gateway: Option<Gateway>,
+    /// The webhook that is alerted about out of range readings
+    pub webhook: Option<Webhook>,
+    /// The MQTT broker the readings are published to
+    pub mqtt: Option<mqtt::Publisher>,
+    /// The Nightscout site the readings are uploaded to
+    pub nightscout: Option<nightscout::Uploader>,
+    /// The local history of the readings
+    pub sqlite: Option<sqlite::Log>,
+    /// The sources of the followed dexcom accounts, labelled by their names
+    pub followed: Vec<(String, Box<dyn GlucoseSource>)>,
+    /// The script that replaces the status templates, if one is configured
+    pub script: Option<StatusScript>,
+    /// The state shared with the control endpoint
+    pub shared: control::Shared,
+    /// The format new readings are printed to stdout in (from `--emit`)
+    pub emit: Option<Emit>,
+    /// Notifies systemd about successful fetches (only when running under systemd)
+    pub systemd: Option<systemd::Notifier>,
+    /// State that is carried between loop iterations
+    pub state: LoopState
+}
+impl App {
+    /// Creates the app from already constructed APIs
+    pub fn new(config: Config, sources: Vec<Box<dyn GlucoseSource>>, discord_api: discord::Api) -> Self {
+        let webhook = config.alert_webhook_url
+        .as_deref()
+        .filter(|url| !url.trim().is_empty())
+        .map(|url| Webhook::new(url, config.proxy().ok().flatten()));
+        let nightscout = config.nightscout_url
+        .as_deref()
+        .filter(|url| !url.trim().is_empty())
+        .and_then(|url| {
+            nightscout::Uploader::new(url, config.nightscout_api_secret.as_deref(), config.proxy().ok().flatten())
+            .inspect_err(|e| warn!("Failed to create the nightscout uploader: {e:?}"))
+            .ok()
+        });
+
+        let sqlite = config.sqlite_path.as_deref().and_then(|path| {
+            sqlite::Log::open(path)
+            .inspect_err(|e| warn!("{e:?}. The readings won't be written to it"))
+            .ok()
+        });
+
+        // A broken script shouldn't stop the program, since the templates still work
+        let script = config.status_script.as_deref().and_then(|path| {
+            StatusScript::load(path)
+            .inspect_err(|e| warn!("{e:?}. Using the templates instead"))
+            .ok()
+        });
+
+        let (unit, decimals) = config.channel_unit(config.control_unit);
+        let shared = control::Shared {
+            unit,
+            decimals,
+            stale_after_minutes: config.stale_after_minutes,
+            timestamp_source: config.timestamp_source,
+            interpolate: config.interpolate,
+            trend_window: config.trend_window,
+            ..Default::default()
+        };
+
+        Self {
+            config,
+            sources,
+            discord_api,
+            extra_discord_apis: Vec::new(),
+            gateway: None,
+            webhook,
+            mqtt: None,
+            nightscout,
+            sqlite,
+            followed: Vec::new(),
+            script,
+            shared,
+            systemd: None,
+            emit: None,
+            state: LoopState::default()
+        }
+    }
+
+    /// Creates the app, connecting to the APIs selected in the config
+    pub async fn from_config(config: Config) -> Result<Self> {
+        let discord_api = discord::Api::new(&config.discord_token, config.discord_options()?).await;
+        let sources = source::from_config(&config).await?;
+
+        let mut app = Self::new(config, sources, discord_api);
+
+        // Bot accounts can only set their status over the gateway
+        if !app.config.discord_enabled {
+            info!("Discord is disabled, so the status won't be updated");
+        } else if app.config.discord_backend == discord::Backend::Bot {
+            // Answer the slash command with the latest reading the loop has
+            let options = app.config.discord_options()?;
+            let commands = app.config.glucose_command.then(|| {
+                let (shared, unit, decimals, trend_display) = (app.shared.clone(), app.config.unit, app.config.decimals(), app.config.trend_display);
+                gateway::Commands::new(&app.config.discord_token, options, move || shared.latest_summary(unit, decimals, trend_display))
+            });
+            app.gateway = Some(Gateway::connect(&app.config.discord_token, &app.config.discord_gateway_url, commands));
+        } else {
+            for token in app.config.extra_discord_tokens.iter().filter(|t| !t.trim().is_empty()) {
+                app.extra_discord_apis.push(ExtraAccount::new(discord::Api::new(token, app.config.discord_options()?).await));
+            }
+        }
+
+        // Serve the readings of the other accounts alongside the main one
+        app.followed = source::followed_from_config(&app.config).await?;
+
+        // Publish the readings to MQTT alongside discord
+        app.mqtt = mqtt::Publisher::connect(&app.config);
+
+        // Fill in the nightscout chart
+        app.backfill_nightscout().await;
+
+        // Let systemd know we're alive
+        app.systemd = systemd::Notifier::from_env();
+
+        // Let the loop be controlled locally
+        if let Some(addr) = &app.config.control_addr {
+            control::spawn(addr, app.shared.clone()).await?;
+        }
+
+        Ok(app)
+    }
+
+    /// Fetches the latest glucose measurement and updates the discord status with it
+    pub async fn tick(&mut self) -> TickOutcome {
+
+        // Leave everything alone while paused
+        if self.shared.is_paused() {
+            trace!("Status updates are paused");
+            // Pausing on purpose shouldn't get us restarted, but it doesn't make us ready either
+            if let Some(systemd) = &mut self.systemd {
+                systemd.keepalive();
+            }
+            return TickOutcome::Skipped;
+        }
+
+        // The followed accounts are only served by the control endpoint, so they don't affect the rest of the tick
+        self.fetch_followed().await;
+
+        // The timestamp of the reading being shown, which is remembered if the update works
+        let mut shown_at = None;
+
+        // Get a blood sugar measurement
+        let renew_sessions = self.state.session_retries < self.config.max_session_retries;
+        let result = fetch_glucose(&mut self.sources, renew_sessions).await;
+        self.record(Subsystem::Sources, &result);
+        let status = match result {
+            Ok(measurement) => {
+                self.state.consecutive_failures = 0;
+                self.state.session_retries = 0;
+                if let Some(systemd) = &mut self.systemd {
+                    systemd.ping();
+                }
+
+                // If the API returned an empty response, log a warning and continue
+                if measurement.is_none() {
+                    warn!("The API didn't return a glucose measurement");
+                    return self.handle_no_data().await;
+                }
+                // Shadow the measurement variable
+                let (measurement, source_name) = measurement.unwrap();
+                self.state.no_data_since = None;
+                self.state.without_reading_since = None;
+                self.state.showing_offline = false;
+                self.state.showing_warmup = false;
+                self.state.empty_responses = 0;
+                if !self.state.received_reading {
+                    self.state.startup_reading_at = measurement.timestamp();
+                }
+                self.state.received_reading = true;
+                trace!("Successfully got glucose measurement: {}", measurement.value);
+
+                // Ignore obviously bogus readings, unless the sensor keeps returning them
+                if !(self.config.sanity_min..=self.config.sanity_max).contains(&measurement.value) {
+                    self.state.consecutive_bad_readings += 1;
+                    self.state.previous_level = None;
+                    warn!(
+                        "Ignoring glucose measurement of {} mg/dL, since it's outside of the sanity range ({} in a row)",
+                        measurement.value, self.state.consecutive_bad_readings
+                    );
+                    if self.state.consecutive_bad_readings < self.config.sanity_error_threshold {
+                        return TickOutcome::Skipped;
+                    }
+                    self.state.last_shown_at = None;
+                    return self.update_status(&Status::text(status::decorate(&self.config, &self.config.sensor_error_status))).await;
+                }
+                self.state.consecutive_bad_readings = 0;
+
+                // Apply the calibration offset before anything uses the value
+                let measurement = measurement.calibrated(self.config.glucose_offset());
+                if let Some(raw_value) = measurement.raw_value {
+                    trace!("Calibrated the glucose measurement from {raw_value} to {} mg/dL", measurement.value);
+                }
+
+                // Skip readings we've already shown, since dexcom hasn't got a new one yet
+                // Stale readings still go through if their status changed, so the stale marker keeps counting up
+                shown_at = measurement.timestamp();
+                let tolerance = self.config.same_reading_tolerance();
+                let repeated = self.config.dedupe_readings && shown_at.zip(self.state.last_shown_at).is_some_and(|(a, b)| dexcom::same_time(a, b, tolerance));
+                let is_stale = measurement.age(self.config.timestamp_source)
+                .is_some_and(|age| self.config.stale_after_minutes > 0 && age.num_minutes() >= self.config.stale_after_minutes.into());
+                if repeated && !is_stale {
+                    trace!("Skipping the glucose measurement, since it was already shown");
+                    return TickOutcome::Skipped;
+                }
+
+                // Publish the reading, regardless of whether the discord update works
+                if let Some(mqtt) = self.mqtt.as_ref().filter(|_| !repeated) {
+                    let result = mqtt.publish(&measurement);
+                    if let Err(e) = &result {
+                        warn!("Failed to publish the glucose measurement to MQTT: {e:?}");
+                    }
+                    self.record(Subsystem::Mqtt, &result);
+                }
+
+                // Remember the reading so the trend can be calculated
+                let is_new = self.state.push_reading(measurement.clone(), tolerance);
+                if is_new {
+                    self.shared.readings.lock().unwrap().clone_from(&self.state.readings);
+                    self.state.a1c_values.push_back(measurement.value);
+                    while self.state.a1c_values.len() > self.config.a1c_window {
+                        self.state.a1c_values.pop_front();
+                    }
+                }
+                // Upload new readings to nightscout, regardless of whether the discord update works
+                if let Some(nightscout) = self.nightscout.as_ref().filter(|_| is_new) {
+                    let result = nightscout.upload(&measurement).await;
+                    if let Err(e) = &result {
+                        warn!("Failed to upload the glucose measurement to nightscout: {e:?}");
+                    }
+                    self.record(Subsystem::Nightscout, &result);
+                }
+
+                // Keep a local history of the new readings
+                if let Some(sqlite) = self.sqlite.as_ref().filter(|_| is_new) {
+                    sqlite.insert(&measurement, source_name);
+                }
+
+                // Remember which bucket we're in, so we can tell when it changes
+                let level = status::find_bucket(&self.config.buckets, measurement.value, self.config.bucket_boundary).map(|b| b.level);
+                let previous_level = std::mem::replace(&mut self.state.previous_level, level);
+
+                // Remind to retest after a low, until a reading is back in range
+                match level {
+                    Some(level) if level < 0 && self.state.low_detected_at.is_none() => {
+                        self.state.low_detected_at = Some(measurement.timestamp().unwrap_or_else(Utc::now));
+                    },
+                    Some(0) => self.state.low_detected_at = None,
+                    _ => {}
+                }
+
+                // Format the status
+                let mut context = status::Context::new(&self.config, &self.state, &measurement);
+                if let Some(script) = &self.script {
+                    match script.run(&script_inputs(&self.config, &self.state, &measurement, &context)) {
+                        Ok(text) => context.script_text = Some(text),
+                        Err(e) => warn!("{e:?}. Using the templates instead")
+                    }
+                }
+                let status = status::format_status(&self.config, &context);
+                self.state.template_rotation = self.state.template_rotation.wrapping_add(1);
+
+                // Keep track of how long the bucket's status has been shown, for the dwell time
+                let shown = context.held_bucket.or_else(|| status::find_bucket_index(&self.config.buckets, measurement.value, self.config.bucket_boundary));
+                if self.state.shown_bucket.map(|(i, _)| i) != shown {
+                    self.state.previous_shown_level = self.state.shown_bucket.and_then(|(i, _)| self.config.buckets.get(i)).map(|b| b.level);
+                    self.state.shown_bucket = shown.map(|i| (i, Utc::now()));
+                }
+
+                // Print the reading to stdout, keeping it clean if the readings are being emitted
+                match self.emit {
+                    Some(Emit::Ndjson) if is_new => println!("{}", mqtt::state_json(&measurement, self.config.unit, self.config.decimals())),
+                    Some(_) => {},
+                    None => console::print_summary(self.config.color, &measurement, self.config.unit, self.config.decimals(), level.unwrap_or(0), &status.text)
+                }
+
+                // Let the reading command know about new readings
+                if let Some(command) = self.config.on_reading_command.as_deref().filter(|_| is_new) {
+                    hooks::run(command, hook_vars(&measurement, level.unwrap_or(0), &status.text));
+                }
+
+                // The rest was already done when the reading was first shown, so only the status can change
+                if repeated && self.state.last_status.as_ref() == Some(&status.text) {
+                    trace!("Skipping the stale glucose measurement, since its status didn't change");
+                    return TickOutcome::Skipped;
+                }
+
+                // Let the webhook know if we're out of range, or if the sensor might be stuck
+                if !repeated {
+                    self.send_alert(level.unwrap_or(0), &measurement, &status.text, &context).await;
+                    match context.flatline_minutes {
+                        Some(minutes) if !self.state.flatline_alerted => {
+                            warn!("The readings have been flat for {minutes} minutes, which could be a compression low or a sensor problem");
+                            self.state.flatline_alerted = self.send_flatline_alert(level.unwrap_or(0), &measurement, &status.text).await;
+                        },
+                        Some(_) => {},
+                        None => self.state.flatline_alerted = false
+                    }
+                }
+
+                // Wait for a reading that's newer than the one dexcom had at startup, so restarts don't push an old reading again
+                let is_startup_reading = self.state.startup_reading_at.is_some_and(|first| shown_at.is_none_or(|t| t <= first + tolerance));
+                if !self.config.update_on_start && is_startup_reading {
+                    trace!("Skipping the glucose measurement, since it was already there at startup");
+                    return TickOutcome::Skipped;
+                }
+
+                // Keep the profile clean while in range
+                if self.config.only_alert_out_of_range && level == Some(0) {
+                    // The status was already cleared when we came back into range
+                    if previous_level == Some(0) {
+                        return TickOutcome::Skipped;
+                    }
+                    let outcome = self.clear_status().await;
+                    if outcome == TickOutcome::Updated {
+                        self.state.last_shown_at = shown_at;
+                    }
+                    return outcome;
+                }
+
+                status
+            },
+            Err(e) => {
+
+                // If the session expired, just continue
+                if e.recovery() == Recovery::RenewSession {
+                    // Give up on sessions that expire as soon as they're renewed
+                    if self.state.session_retries >= self.config.max_session_retries {
+                        error!("{} (the dexcom session expired {} times in a row)", dexcom::Error::MaxRetriesReached, self.state.session_retries + 1);
+                        self.state.session_retries = 0;
+                        self.state.previous_level = None;
+                        self.state.consecutive_failures = (self.state.consecutive_failures + 1).max(self.config.breaker_threshold);
+                        return TickOutcome::MaxRetriesReached;
+                    }
+
+                    self.state.session_retries += 1;
+
+                    // Don't renew sessions in a tight loop if the network keeps dropping them
+                    let now = Instant::now();
+                    let cap = Duration::from_secs(self.config.fast_retry_min_interval_secs);
+                    if self.state.last_fast_retry_at.is_some_and(|at| now.duration_since(at) < cap) {
+                        debug!("The dexcom session ID expired again. Retrying on the next iteration instead of right away...");
+                        return TickOutcome::Skipped;
+                    }
+                    self.state.last_fast_retry_at = Some(now);
+
+                    debug!("The dexcom session ID expired. Retrying with a new session ID...");
+                    return TickOutcome::RetryNow;
+                } else {
+                    self.state.session_retries = 0;
+                    error!("Failed to get latest glucose measurement from any source: {e:?}");
+                    self.state.previous_level = None;
+                    self.state.consecutive_failures += 1;
+                    if self.state.consecutive_failures == self.config.breaker_threshold {
+                        warn!("Failed to get a glucose measurement {} times in a row. Backing off until it succeeds again...", self.state.consecutive_failures);
+                    }
+                    if let Some(outcome) = self.handle_outage().await {
+                        return outcome;
+                    }
+
+                    // Keep the last status through one-off failures
+                    if self.state.consecutive_failures < self.config.error_status_threshold {
+                        return TickOutcome::Skipped;
+                    }
+                    let text = self.config.error_status.replace("{error_kind}", e.kind().label());
+                    Status::text(status::decorate(&self.config, &text))
+                }
+            }
+        };
+
+        let outcome = self.update_status(&status).await;
+        if outcome == TickOutcome::Updated {
+            self.state.last_shown_at = shown_at;
+            self.state.last_status = Some(status.text);
+        }
+        outcome
+    }
+
+    /// Fetches the latest readings of the followed accounts for the control endpoint
+    /// 
+    /// - NOTE: Failures are only logged, and the last reading of the account is kept
+    async fn fetch_followed(&mut self) {
+        for (label, source) in self.followed.iter_mut() {
+            match source.get_latest_glucose().await {
+                Ok(Some(measurement)) => {
+                    self.shared.accounts.lock().unwrap().insert(label.clone(), measurement);
+                },
+                Ok(None) => debug!("The followed account '{label}' didn't return a glucose measurement"),
+                Err(e) => warn!("Failed to get the latest glucose measurement of the followed account '{label}': {e:?}")
+            }
+        }
+    }
+
+    /// Uploads the recent history of the first source that has one to nightscout, if the backfill is enabled
+    /// 
+    /// - NOTE: Failures are only logged, since the loop uploads new readings either way
+    pub async fn backfill_nightscout(&mut self) {
+        let hours = self.config.nightscout_backfill_hours;
+        let Some(nightscout) = self.nightscout.as_ref().filter(|_| hours > 0) else {
+            return;
+        };
+
+        for source in self.sources.iter_mut() {
+            let history = match source.get_history(hours).await {
+                Ok(history) if history.is_empty() => continue,
+                Ok(history) => history,
+                Err(e) => {
+                    warn!("Failed to get the glucose history from the {} source: {e:?}", source.name());
+                    continue;
+                }
+            };
+
+            match nightscout.backfill(&history).await {
+                Ok(count) => debug!("Backfilled {count} of the last {} readings to nightscout", history.len()),
+                Err(e) => warn!("Failed to backfill nightscout: {e:?}")
+            }
+            return;
+        }
+    }
+
+    /// Runs the loop body, catching any panic if the config is resilient
+    /// 
+    /// - NOTE: A panic counts as a failed fetch, so the loop backs off if it keeps panicking
+    pub async fn tick_guarded(&mut self) -> TickOutcome {
+        if !self.config.resilient {
+            return self.tick().await;
+        }
+
+        match AssertUnwindSafe(self.tick()).catch_unwind().await {
+            Ok(outcome) => outcome,
+            Err(panic) => {
+                let message = panic.downcast_ref::<&str>().copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+                error!("The loop body panicked, so it'll be retried on the next iteration: {message}");
+                self.state.consecutive_failures += 1;
+                TickOutcome::Skipped
+            }
+        }
+    }
+
+    /// Returns how long to sleep after a tick that took `elapsed`, so the ticks start once per `interval` (from [`Self::poll_interval`]) without overlapping
+    /// 
+    /// - NOTE: Ticks can't overlap anyway, since they borrow the app mutably. This just keeps slow ticks from delaying the schedule.
+    pub fn sleep_after(&self, elapsed: Duration, interval: Duration) -> Duration {
+        if elapsed > interval {
+            warn!("The last loop iteration took {elapsed:?}, which is longer than the interval ({interval:?}). Starting the next one right away...");
+        }
+        remaining_interval(interval, elapsed)
+    }
+
+    /// Logs a heartbeat if it's been long enough since the last one, returning its message
+    /// 
+    /// - NOTE: The heartbeat says whether the status was updated by any tick since the last heartbeat, not just this one
+    pub fn heartbeat(&mut self, outcome: &TickOutcome) -> Option<String> {
+        self.state.updated_since_heartbeat |= *outcome == TickOutcome::Updated;
+        if self.config.heartbeat_interval_secs == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let interval = Duration::from_secs(self.config.heartbeat_interval_secs);
+        if self.state.last_heartbeat_at.is_some_and(|at| now.duration_since(at) < interval) {
+            return None;
+        }
+
+        let updated = match std::mem::take(&mut self.state.updated_since_heartbeat) {
+            true => "the status was updated",
+            false => "the status wasn't updated"
+        };
+        let message = format!("Heartbeat: {}, {updated}", self.shared.latest_summary(self.config.unit, self.config.decimals(), self.config.trend_display));
+        self.state.last_heartbeat_at = Some(now);
+        info!("{message}");
+        Some(message)
+    }
+
+    /// Records the result of using a subsystem in the health state
+    fn record<T, E: std::fmt::Display>(&self, subsystem: Subsystem, result: &Result<T, E>) {
+        self.shared.health.lock().unwrap().record(subsystem, result);
+    }
+
+    /// Returns how long to wait before the next loop iteration
+    /// 
+    /// - NOTE: This backs off exponentially once the glucose sources have failed too many times in a row
+    /// - NOTE: Only the extra wait of the backoff is jittered, so we never poll faster than usual
+    pub fn poll_interval(&self) -> Duration {
+        POLL_INTERVAL + http::jitter(self.backoff_interval().saturating_sub(POLL_INTERVAL), self.config.retry_jitter)
+    }
+
+    /// Returns true if the glucose sources failed often enough that the loop is backing off
+    pub fn is_backing_off(&self) -> bool {
+        self.backoff_interval() > POLL_INTERVAL
+    }
+
+    /// Returns the interval before the jitter, which is longer than usual while backing off
+    fn backoff_interval(&self) -> Duration {
+        backoff_interval(
+            POLL_INTERVAL,
+            self.state.consecutive_failures,
+            self.config.breaker_threshold,
+            Duration::from_secs(self.config.breaker_max_interval_secs)
+        )
+    }
+
+    /// Clears the status using the configured discord backend
+    async fn clear_status(&mut self) -> TickOutcome {
+        if !self.config.discord_enabled {
+            return TickOutcome::Updated;
+        }
+        if self.is_discord_paused() || self.is_manually_overridden().await {
+            return TickOutcome::Skipped;
+        }
+
+        let result = match &self.gateway {
+            Some(gateway) => gateway.clear_activity(),
+            None => self.for_each_account(|api| api.clear_status()).await
+        }.map_err(AppError::from);
+        self.record(Subsystem::Discord, &result);
+        self.track_rate_limits(&result);
+
+        // Forget the bucket so the clear is retried next time
+        if let Err(e) = result {
+            warn!("Failed to clear discord account status: {e:?}");
+            self.state.previous_level = None;
+            return outcome_of_discord_error(e);
+        }
+
+        self.state.sent_status = Some(String::new());
+        TickOutcome::Updated
+    }
+
+    /// Shows the warmup status once the sources haven't returned a reading for long enough
+    /// 
+    /// - NOTE: The warmup status is only sent once per stretch without readings
+    async fn handle_no_data(&mut self) -> TickOutcome {
+        let now = Utc::now();
+        let since = *self.state.no_data_since.get_or_insert(now);
+        self.state.empty_responses += 1;
+        if let Some(outcome) = self.handle_outage().await {
+            return outcome;
+        }
+
+        // Never getting a single reading is almost always Share being disabled, rather than a sensor gap
+        let threshold = self.config.share_disabled_after_empty;
+        if !self.state.received_reading && threshold > 0 && self.state.empty_responses >= threshold {
+            if self.state.showing_share_disabled {
+                return TickOutcome::Skipped;
+            }
+
+            warn!(
+                "The sources haven't returned a single reading since startup ({} empty responses). Make sure Share is enabled in the dexcom app with at least one follower added.",
+                self.state.empty_responses
+            );
+            self.state.previous_level = None;
+            self.state.last_shown_at = None;
+            self.state.showing_warmup = false;
+            let outcome = self.update_status(&Status::text(status::decorate(&self.config, &self.config.share_disabled_status))).await;
+            self.state.showing_share_disabled = outcome == TickOutcome::Updated;
+            return outcome;
+        }
+
+        let threshold = TimeDelta::minutes(self.config.warmup_after_minutes as i64);
+        if self.state.showing_warmup || now - since < threshold {
+            return TickOutcome::Skipped;
+        }
+
+        debug!("There haven't been any readings since {since}. Assuming the sensor is warming up...");
+        self.state.previous_level = None;
+        self.state.last_shown_at = None;
+        let outcome = self.update_status(&Status::text(status::decorate(&self.config, &self.config.warmup_status))).await;
+        self.state.showing_warmup = outcome == TickOutcome::Updated;
+        outcome
+    }
+
+    /// Clears the status (or shows the offline status) once the sources haven't returned a reading for `clear_after_no_data_secs`
+    /// 
+    /// - NOTE: This returns `None` until the outage is long enough, so the usual warmup and error statuses are shown until then
+    async fn handle_outage(&mut self) -> Option<TickOutcome> {
+        let now = Utc::now();
+        let since = *self.state.without_reading_since.get_or_insert(now);
+        let limit = self.config.clear_after_no_data_secs;
+        if limit == 0 || (now - since).num_seconds() < i64::try_from(limit).unwrap_or(i64::MAX) {
+            return None;
+        }
+        if self.state.showing_offline {
+            return Some(TickOutcome::Skipped);
+        }
+
+        warn!("The sources haven't returned a reading since {since}, so the status is taken down until they do");
+        self.state.previous_level = None;
+        self.state.last_shown_at = None;
+        self.state.showing_warmup = false;
+        let outcome = match self.config.offline_status.clone() {
+            Some(text) => self.update_status(&Status::text(status::decorate(&self.config, &text))).await,
+            None => self.clear_status().await
+        };
+        self.state.showing_offline = outcome == TickOutcome::Updated;
+        Some(outcome)
+    }
+
+    /// Sends an alert to the webhook for out of range readings, unless the alert is snoozed
+    async fn send_alert(&mut self, level: i8, measurement: &GlucoseMeasurement, text: &str, context: &status::Context) {
+        let value = measurement.value;
+        if self.webhook.is_none() && self.config.on_alert_command.is_none() {
+            return;
+        }
+        if !self.config.alert_rules.is_empty() {
+            return self.send_rule_alert(level, measurement, text, context).await;
+        }
+
+        // Coming back into range ends the snooze
+        if level == 0 {
+            self.state.alert_snooze = None;
+            return;
+        }
+
+        let now = Utc::now();
+        if !alert::should_alert(level, self.state.alert_snooze.as_ref(), now) {
+            debug!("Not sending an alert for {value} mg/dL, since it's snoozed");
+            return;
+        }
+
+        // Include how much we've changed since the last alert
+        let at = measurement.timestamp_from(self.config.timestamp_source).unwrap_or(now);
+        // It'll be retried on the next reading if this fails
+        if !self.deliver_alert(&self.alert(level, measurement, text, at), measurement).await {
+            return;
+        }
+
+        self.state.last_alert = Some(LastAlert { value, at });
+        self.state.alert_snooze = Some(Snooze {
+            level,
+            until: now + TimeDelta::minutes(self.config.alert_snooze_minutes as i64)
+        });
+    }
+
+    /// Sends an alert for the most severe alert rule the reading matches, unless that rule is snoozed
+    /// 
+    /// - NOTE: Each rule has its own snooze, which ends once a reading stops matching the rule
+    async fn send_rule_alert(&mut self, level: i8, measurement: &GlucoseMeasurement, text: &str, context: &status::Context) {
+        let value = measurement.value;
+        let rules = &self.config.alert_rules;
+        self.state.rule_snoozes.retain(|&i, _| rules.get(i).is_some_and(|r| r.matches(value)));
+        let Some(index) = alert::matching_rule(rules, value) else {
+            return;
+        };
+        let rule = rules[index].clone();
+
+        let now = Utc::now();
+        if self.state.rule_snoozes.get(&index).is_some_and(|&until| now < until) {
+            debug!("Not sending the {} alert for {value} mg/dL, since it's snoozed", rule.severity);
+            return;
+        }
+
+        let content = match &rule.message {
+            Some(message) => status::render(message, self.config.trend_display, context),
+            None => text.to_string()
+        };
+        let at = measurement.timestamp_from(self.config.timestamp_source).unwrap_or(now);
+        let alert = Alert { severity: Some(rule.severity), color: rule.color, ..self.alert(level, measurement, &content, at) };
+        // It'll be retried on the next reading if this fails
+        if !self.deliver_alert(&alert, measurement).await {
+            return;
+        }
+
+        self.state.last_alert = Some(LastAlert { value, at });
+        self.state.rule_snoozes.insert(index, now + TimeDelta::minutes(self.config.alert_snooze_minutes as i64));
+    }
+
+    /// Sends an alert about a flatline to the webhook, returning false if it failed (so it's retried on the next reading)
+    /// 
+    /// - NOTE: Flatline alerts aren't snoozed, and don't count as the last alert, since they're sent once per flatline
+    async fn send_flatline_alert(&mut self, level: i8, measurement: &GlucoseMeasurement, text: &str) -> bool {
+        if self.webhook.is_none() && self.config.on_alert_command.is_none() {
+            return true;
+        }
+
+        let at = measurement.timestamp_from(self.config.timestamp_source).unwrap_or_else(Utc::now);
+        let alert = Alert { flatline: true, ..self.alert(level, measurement, text, at) };
+        self.deliver_alert(&alert, measurement).await
+    }
+
+    /// Returns the alert for a reading, including how much it changed since the last alert
+    fn alert(&self, level: i8, measurement: &GlucoseMeasurement, text: &str, at: DateTime<Utc>) -> Alert {
+        let value = measurement.value;
+        let last = self.state.last_alert;
+        let (unit, decimals) = self.config.channel_unit(self.config.alert_unit);
+        Alert {
+            content: text.to_string(),
+            value,
+            unit,
+            value_in_unit: measurement.rounded_in(unit, decimals),
+            level,
+            previous_value: last.map(|l| l.value),
+            delta: last.map(|l| value as i64 - l.value as i64),
+            elapsed_minutes: last.map(|l| (at - l.at).num_minutes().max(0)),
+            trend: measurement.trend.clone(),
+            arrow: measurement.trend().arrow(self.config.trend_display).to_string(),
+            rate: trend::rate(&self.state.readings, self.config.trend_window, self.config.timestamp_source).map(|r| (r * 10.0).round() / 10.0),
+            flatline: false,
+            severity: None,
+            color: None
+        }
+    }
+
+    /// Runs the alert command and sends the alert to the webhook, returning false if the webhook failed
+    async fn deliver_alert(&self, alert: &Alert, measurement: &GlucoseMeasurement) -> bool {
+        if let Some(command) = &self.config.on_alert_command {
+            hooks::run(command, hook_vars(measurement, alert.level, &alert.content));
+        }
+
+        if let Some(webhook) = &self.webhook {
+            let result = webhook.send(alert).await;
+            self.record(Subsystem::Webhook, &result);
+            if let Err(e) = result {
+                warn!("Failed to send alert: {e:?}");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Updates the status (and the bio, if enabled), returning the outcome of the tick
+    /// 
+    /// - NOTE: The outcome only depends on the status, since a failed bio update shouldn't affect the loop
+    #[tracing::instrument(name = "update", skip_all)]
+    async fn update_status(&mut self, status: &Status) -> TickOutcome {
+        // Act like the update worked, so the rest of the loop behaves the same
+        if !self.config.discord_enabled {
+            trace!("Discord is disabled, so the status '{}' wasn't sent", status.text);
+            return TickOutcome::Updated;
+        }
+        if self.is_discord_paused() || self.is_manually_overridden().await {
+            return TickOutcome::Skipped;
+        }
+
+        if self.config.update_bio && self.gateway.is_none() {
+            if let Err(e) = self.discord_api.set_bio(status.bio()).await {
+                warn!("Failed to update discord account bio: {e:?}");
+            }
+        }
+
+        // Log a warning if the status update failed
+        let result = self.set_status(status).await.map_err(AppError::from);
+        self.record(Subsystem::Discord, &result);
+        self.track_rate_limits(&result);
+        if let Err(e) = result {
+            warn!("Failed to update discord account status: {e:?}");
+            return outcome_of_discord_error(e);
+        }
+
+        // Remember what discord should be showing, so manual changes can be told apart
+        let sent = status::truncate(&status.text, self.config.max_status_length, self.config.status_ellipsis);
+        self.state.sent_status = Some(sent.unwrap_or_else(|| status.text.clone()));
+        TickOutcome::Updated
+    }
+
+    /// Returns true if the discord updates are paused because of repeated rate limits
+    fn is_discord_paused(&mut self) -> bool {
+        match self.state.discord_paused_until {
+            Some(until) if Instant::now() < until => {
+                trace!("The discord updates are paused because of rate limits");
+                true
+            },
+            Some(_) => {
+                info!("Resuming the discord updates after the rate limit cooldown");
+                self.state.discord_paused_until = None;
+                false
+            },
+            None => false
+        }
+    }
+
+    /// Counts the rate limited discord updates in a row, pausing the updates once there are `rate_limit_pause_after` of them
+    fn track_rate_limits(&mut self, result: &Result<(), AppError>) {
+        if !result.as_ref().is_err_and(AppError::is_rate_limited) {
+            // Other errors don't say anything about the rate limits, so only a success resets the count
+            if result.is_ok() {
+                self.state.consecutive_rate_limits = 0;
+            }
+            return;
+        }
+
+        self.state.consecutive_rate_limits += 1;
+        let threshold = self.config.rate_limit_pause_after;
+        if threshold > 0 && self.state.consecutive_rate_limits >= threshold {
+            warn!(
+                "Discord rate limited {} updates in a row, so the updates are paused for {} seconds",
+                self.state.consecutive_rate_limits, self.config.rate_limit_cooldown_secs
+            );
+            self.state.discord_paused_until = Some(Instant::now() + Duration::from_secs(self.config.rate_limit_cooldown_secs));
+            self.state.consecutive_rate_limits = 0;
+        }
+    }
+
+    /// Returns true if the status was changed by hand since we last set it, and the bucket hasn't changed since then
+    /// 
+    /// - NOTE: This only applies to the user backend, since the status is read back from the main account
+    async fn is_manually_overridden(&mut self) -> bool {
+        if !self.config.respect_manual_status || self.gateway.is_some() {
+            return false;
+        }
+
+        // Keep respecting the manual status until the bucket changes
+        if let Some(level) = self.state.manual_status_level {
+            if level == self.state.previous_level {
+                trace!("Leaving the manual discord status alone");
+                return true;
+            }
+            info!("The glucose bucket changed, so the manual discord status is replaced");
+            self.state.manual_status_level = None;
+            return false;
+        }
+
+        let Some(sent) = &self.state.sent_status else {
+            return false;
+        };
+        match self.discord_api.get_status().await {
+            Ok(actual) if actual != *sent => {
+                info!("The discord status was changed by hand ('{actual}'), so it's left alone until the glucose bucket changes");
+                self.state.manual_status_level = Some(self.state.previous_level);
+                true
+            },
+            Ok(_) => false,
+            Err(e) => {
+                warn!("Failed to check whether the discord status was changed by hand: {e:?}");
+                false
+            }
+        }
+    }
+
+    /// Updates the status using the configured discord backend
+    /// 
+    /// - NOTE: The status is truncated if it's longer than discord allows
+    async fn set_status(&self, status: &Status) -> Result<()> {
+        let truncated = status::truncate(&status.text, self.config.max_status_length, self.config.status_ellipsis);
+        if truncated.is_some() {
+            warn!("The status '{}' is longer than {} characters, so it was truncated", status.text, self.config.max_status_length);
+        }
+        let status = &Status {
+            text: truncated.unwrap_or_else(|| status.text.clone()),
+            ..status.clone()
+        };
+
+        match &self.gateway {
+            Some(gateway) => {
+                let kind = match self.config.status_target {
+                    discord::StatusTarget::CustomStatus => ActivityType::Custom,
+                    discord::StatusTarget::Activity => self.config.activity_kind
+                };
+                gateway.set_activity(Activity { kind, text: status.text.clone() })
+            },
+            None => self.for_each_account(|api| self.set_user_status(api, status)).await
+        }
+    }
+
+    /// Updates the status of a user account
+    async fn set_user_status(&self, api: &discord::Api, status: &Status) -> Result<()> {
+        // Retry the update until the read back status matches (or we run out of retries)
+        let mut attempt = 0;
+        loop {
+            api.set_status(&status.text, status.emoji.as_ref()).await?;
+            if !self.config.verify_updates {
+                return Ok(());
+            }
+
+            let actual = api.get_status().await?;
+            if actual == status.text {
+                trace!("Verified the status update");
+                return Ok(());
+            }
+            warn!("The status update didn't take effect (expected '{}', got '{actual}')", status.text);
+
+            if attempt >= self.config.verify_retries {
+                return Ok(());
+            }
+            let delay = Duration::from_millis(self.config.verify_retry_delay_ms).saturating_mul(1 << attempt.min(16));
+            tokio::time::sleep(http::jitter(delay, self.config.retry_jitter)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Runs an update on every user account, up to `discord_concurrency` at a time
+    /// 
+    /// - NOTE: Only the result of the main account is returned. The other accounts' failures are only logged, and the ones that are paused because of rate limits are skipped.
+    async fn for_each_account<'a, F, Fut>(&'a self, update: F) -> Result<()>
+    where
+        F: Fn(&'a discord::Api) -> Fut,
+        Fut: Future<Output = Result<()>> + 'a
+    {
+        // The accounts are numbered from the main one, so the logs match the order of the config
+        let extras = self.extra_discord_apis.iter()
+        .enumerate()
+        .filter(|(i, account)| !account.is_paused(i + 2))
+        .map(|(i, account)| (i + 2, &account.api));
+        let accounts = std::iter::once((1, &self.discord_api)).chain(extras);
+        let total = 1 + self.extra_discord_apis.len();
+        let mut results: Vec<(usize, Result<()>)> = stream::iter(accounts)
+        .map(|(n, api)| update(api).map(move |result| (n, result)))
+        .buffer_unordered(self.config.discord_concurrency.max(1))
+        .collect().await;
+
+        // Log how the other accounts went
+        results.sort_by_key(|(n, _)| *n);
+        let mut results = results.into_iter();
+        let (_, main) = results.next().unwrap();
+        if total > 1 {
+            let mut updated = usize::from(main.is_ok());
+            for (n, result) in results {
+                let result = result.map_err(AppError::from);
+                self.extra_discord_apis[n - 2].track_rate_limits(n, &result, &self.config);
+                match result {
+                    Ok(()) => updated += 1,
+                    Err(e) => warn!("Failed to update discord account {n}: {e:?}")
+                }
+            }
+            debug!("Updated {updated} of {total} discord accounts");
+        }
+
+        main
+    }
+}
+
+/// Another user account that shows the same status as the main one
+pub struct ExtraAccount {
+    /// The discord API of the account
+    pub api: discord::Api,
+    /// The rate limits of the account, which are tracked apart from the main account's
+    rate_limits: std::sync::Mutex<RateLimits>
+}
+impl ExtraAccount {
+    /// Wraps the API of another account
+    pub fn new(api: discord::Api) -> Self {
+        Self { api, rate_limits: Default::default() }
+    }
+
+    /// Returns true if the updates of the account (numbered `n` in the logs) are paused because of repeated rate limits
+    fn is_paused(&self, n: usize) -> bool {
+        let mut rate_limits = self.rate_limits.lock().unwrap();
+        match rate_limits.paused_until {
+            Some(until) if Instant::now() < until => {
+                trace!("The updates of discord account {n} are paused because of rate limits");
+                true
+            },
+            Some(_) => {
+                info!("Resuming the updates of discord account {n} after the rate limit cooldown");
+                rate_limits.paused_until = None;
+                false
+            },
+            None => false
+        }
+    }
+
+    /// Counts the rate limited updates of the account in a row, pausing them once there are `rate_limit_pause_after` of them
+    fn track_rate_limits(&self, n: usize, result: &Result<(), AppError>, config: &Config) {
+        let mut rate_limits = self.rate_limits.lock().unwrap();
+        if !result.as_ref().is_err_and(AppError::is_rate_limited) {
+            if result.is_ok() {
+                rate_limits.consecutive = 0;
+            }
+            return;
+        }
+
+        rate_limits.consecutive += 1;
+        if config.rate_limit_pause_after > 0 && rate_limits.consecutive >= config.rate_limit_pause_after {
+            warn!(
+                "Discord rate limited {} updates of account {n} in a row, so its updates are paused for {} seconds",
+                rate_limits.consecutive, config.rate_limit_cooldown_secs
+            );
+            rate_limits.paused_until = Some(Instant::now() + Duration::from_secs(config.rate_limit_cooldown_secs));
+            rate_limits.consecutive = 0;
+        }
+    }
+}
+
+/// The rate limits of a discord account
+#[derive(Debug, Default)]
+struct RateLimits {
+    /// How many updates in a row were rate limited
+    consecutive: u32,
+    /// When the updates resume after too many rate limits
+    paused_until: Option<Instant>
+}
+
+/// State that is carried between loop iterations
+#[derive(Debug, Default)]
+pub struct LoopState {
+    /// When the tick last asked to be retried right away after the dexcom session expired
+    pub last_fast_retry_at: Option<Instant>,
+    /// The most recent glucose readings, from oldest to newest
+    pub readings: VecDeque<GlucoseMeasurement>,
+    /// The values of the readings the A1C is estimated from, from oldest to newest (this is longer than `readings`)
+    pub a1c_values: VecDeque<u32>,
+    /// How many times in a row every glucose source has failed
+    pub consecutive_failures: u32,
+    /// How many times in a row the dexcom session was renewed without a successful fetch
+    pub session_retries: u32,
+    /// How many readings in a row were outside of the sanity range
+    pub consecutive_bad_readings: u32,
+    /// The level of the bucket the previous reading was in (`None` if there was no bucket or the fetch failed)
+    pub previous_level: Option<i8>,
+    /// The snooze of the last alert, if one was sent
+    pub alert_snooze: Option<Snooze>,
+    /// The reading of the last alert, if one was sent
+    pub last_alert: Option<LastAlert>,
+    /// When the sources started returning no readings (`None` if the last fetch returned a reading)
+    pub no_data_since: Option<DateTime<Utc>>,
+    /// Whether the warmup status is being shown
+    pub showing_warmup: bool,
+    /// How many successful fetches in a row didn't return a reading
+    pub empty_responses: u32,
+    /// Whether any reading was received since startup
+    pub received_reading: bool,
+    /// Whether the Share disabled status is being shown
+    pub showing_share_disabled: bool,
+    /// The timestamp of the reading the status is showing (`None` if it's showing something else, like an error)
+    pub last_shown_at: Option<DateTime<Utc>>,
+    /// The index of the bucket whose status is shown, and when it was first shown
+    pub shown_bucket: Option<(usize, DateTime<Utc>)>,
+    /// The level of the bucket that was shown before the current one, used to tell when we're back in range
+    pub previous_shown_level: Option<i8>,
+    /// How many reading statuses were formatted, used to rotate through the templates of a bucket
+    pub template_rotation: usize,
+    /// The text of the last status that was shown for a reading
+    pub last_status: Option<String>,
+    /// When the current low was first detected (`None` once a reading is back in range)
+    pub low_detected_at: Option<DateTime<Utc>>,
+    /// The text discord should be showing, according to the last successful update (empty if it was cleared)
+    pub sent_status: Option<String>,
+    /// The bucket level when a manual status was detected, which is left alone until the level changes
+    pub manual_status_level: Option<Option<i8>>,
+    /// The timestamp of the first reading since startup
+    pub startup_reading_at: Option<DateTime<Utc>>,
+    /// When the last heartbeat was logged
+    pub last_heartbeat_at: Option<Instant>,
+    /// Whether the status was updated since the last heartbeat
+    pub updated_since_heartbeat: bool,
+    /// Whether the current flatline was alerted about
+    pub flatline_alerted: bool,
+    /// How many discord updates in a row were rate limited
+    pub consecutive_rate_limits: u32,
+    /// When the discord updates paused by rate limits resume (`None` if they aren't paused)
+    pub discord_paused_until: Option<Instant>,
+    /// When the sources stopped returning readings, whether they were empty or failing (`None` if the last fetch returned a reading)
+    pub without_reading_since: Option<DateTime<Utc>>,
+    /// Whether the status was taken down because of a long outage
+    pub showing_offline: bool,
+    /// When the snooze of each alert rule expires, by the index of the rule
+    pub rule_snoozes: BTreeMap<usize, DateTime<Utc>>
+}
+impl LoopState {
+    /// Adds a reading to the buffer, ignoring it if it's the same as the newest reading (within the tolerance)
+    /// 
+    /// - NOTE: This returns true if the reading is new
+    pub fn push_reading(&mut self, measurement: GlucoseMeasurement, tolerance: TimeDelta) -> bool {
+        if self.readings.back().is_some_and(|r| r.is_same_reading(&measurement, tolerance)) {
+            return false;
+        }
+
+        self.readings.push_back(measurement);
+        if self.readings.len() > READING_BUFFER_SIZE {
+            self.readings.pop_front();
+        }
+        true
+    }
+}
+
+/// Returns the environment variables passed to the reading and alert commands
+fn hook_vars(measurement: &GlucoseMeasurement, level: i8, text: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("DEXCORD_VALUE", measurement.value.to_string()),
+        ("DEXCORD_TREND", measurement.trend.clone()),
+        ("DEXCORD_LEVEL", level.to_string()),
+        ("DEXCORD_STATUS", text.to_string())
+    ]
+}
+
+/// Returns the values the status script can use
+fn script_inputs(config: &Config, state: &LoopState, measurement: &GlucoseMeasurement, context: &status::Context) -> script::Inputs {
+    let timezone = config.timezone.unwrap_or(chrono_tz::UTC);
+
+    script::Inputs {
+        value: measurement.value,
+        value_in_unit: measurement.value_in(config.unit),
+        unit: config.unit.label().to_string(),
+        trend: measurement.trend.clone(),
+        rate: trend::rate(&state.readings, config.trend_window, config.timestamp_source),
+        age: measurement.age(config.timestamp_source).map(|age| age.num_minutes()),
+        time: context.time.clone(),
+        hour: measurement.timestamp().map(|t| t.with_timezone(&timezone).hour())
+    }
+}
+
+/// Returns the outcome of a tick whose discord update failed
+fn outcome_of_discord_error(e: AppError) -> TickOutcome {
+    // Retrying won't help until the account is verified
+    match e.recovery() {
+        Recovery::Halt => TickOutcome::Halt,
+        _ => TickOutcome::Skipped
+    }
+}
+
+/// Returns how much of the interval is left after the work took `elapsed` (never negative)
+fn remaining_interval(interval: Duration, elapsed: Duration) -> Duration {
+    interval.saturating_sub(elapsed)
+}
+
+/// Doubles the interval for every failure after the threshold is reached, up to the cap
+/// 
+/// - NOTE: A threshold of 0 disables the backoff
+fn backoff_interval(base: Duration, failures: u32, threshold: u32, cap: Duration) -> Duration {
+    if threshold == 0 || failures < threshold {
+        return base;
+    }
+
+    // Limit the exponent so the multiplication can't overflow
+    let exponent = (failures - threshold + 1).min(16);
+    base.saturating_mul(1 << exponent).min(cap.max(base))
+}
+
+/// Queries the glucose sources in order until one of them returns a measurement, returning it with the name of its source
+/// 
+/// - NOTE: An expired dexcom session is returned immediately while `renew_sessions` is set, since retrying the same source is faster than falling back
+/// - NOTE: This only returns an error if every source failed
+#[tracing::instrument(name = "fetch", skip_all)]
+async fn fetch_glucose(sources: &mut [Box<dyn GlucoseSource>], renew_sessions: bool) -> Result<Option<(GlucoseMeasurement, &'static str)>, AppError> {
+    let mut last_error = None;
+    let mut any_empty = false;
+
+    for source in sources.iter_mut() {
+        match source.get_latest_glucose().await {
+            Ok(Some(measurement)) => return Ok(Some((measurement, source.name()))),
+            Ok(None) => {
+                debug!("The {} source didn't return a glucose measurement", source.name());
+                any_empty = true;
+            },
+            Err(e) => {
+                let e = AppError::from(e);
+                if renew_sessions && e.recovery() == Recovery::RenewSession {
+                    return Err(e);
+                }
+                warn!("Failed to get latest glucose measurement from the {} source: {e:?}", source.name());
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if !any_empty => Err(e),
+        _ => Ok(None)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAP: Duration = Duration::from_secs(3600);
+
+    #[tokio::test]
+    async fn intervals_keep_the_cadence_regardless_of_the_work() {
+        let period = Duration::from_millis(50);
+        let mut schedule = Schedule::with_period(LoopTiming::Interval, MissedTicks::Skip, period);
+        let interval = schedule.interval.as_mut().unwrap();
+        let started = tokio::time::Instant::now();
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            interval.tick().await;
+        }
+        // The work happens within the intervals, so it doesn't add up
+        let elapsed = started.elapsed();
+        assert!(elapsed >= period * 3 && elapsed < period * 3 + Duration::from_millis(40), "{elapsed:?}");
+
+        assert!(Schedule::with_period(LoopTiming::Sleep, MissedTicks::Skip, period).interval.is_none());
+    }
+
+    #[test]
+    fn slow_ticks_shorten_the_sleep() {
+        assert_eq!(remaining_interval(POLL_INTERVAL, Duration::from_secs(20)), Duration::from_secs(280));
+        assert_eq!(remaining_interval(POLL_INTERVAL, Duration::from_secs(400)), Duration::ZERO);
+    }
+
+    #[test]
+    fn backoff_waits_for_the_threshold() {
+        assert_eq!(backoff_interval(POLL_INTERVAL, 2, 3, CAP), POLL_INTERVAL);
+    }
+
+    #[test]
+    fn backoff_doubles_per_failure() {
+        assert_eq!(backoff_interval(POLL_INTERVAL, 3, 3, CAP), Duration::from_secs(600));
+        assert_eq!(backoff_interval(POLL_INTERVAL, 4, 3, CAP), Duration::from_secs(1200));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(backoff_interval(POLL_INTERVAL, 100, 3, CAP), CAP);
+    }
+}
@@ -0,0 +1,58 @@
+//
+// A mode that tests the credentials in the config without starting the loop
+//
+
+use crate::{config::Config, discord, error::AppError, source};
+
+/// Tests every glucose source and the discord token once, printing a pass or fail for each
+/// 
+/// - NOTE: This returns true if every check passed
+pub async fn run(config: &Config) -> bool {
+    let mut passed = true;
+
+    // Authenticate to every source and fetch a single measurement
+    for kind in &config.sources {
+        let result = async {
+            let mut source = source::create(config, *kind).await?;
+            source.get_latest_glucose().await
+        }.await;
+
+        match result {
+            Ok(Some(m)) => println!("PASS {kind:?}: got a glucose measurement of {} mg/dL", m.value),
+            Ok(None) => println!("PASS {kind:?}: authenticated, but there was no recent glucose measurement"),
+            Err(e) => {
+                println!("FAIL {kind:?}: {e}");
+                if let Some(guidance) = AppError::from(e).guidance() {
+                    println!("     {guidance}");
+                }
+                passed = false;
+            }
+        }
+    }
+
+    // Read the settings, which doesn't change anything on the account
+    match config.discord_backend {
+        _ if !config.discord_enabled => println!("SKIP Discord: discord_enabled is false"),
+        discord::Backend::User => {
+            let result = async {
+                let api = discord::Api::new(&config.discord_token, config.discord_options()?).await;
+                api.get_status().await
+            }.await;
+
+            match result {
+                Ok(_) => println!("PASS Discord: the token is valid"),
+                Err(e) => {
+                    println!("FAIL Discord: {e}");
+                    if let Some(guidance) = AppError::from(e).guidance() {
+                        println!("     {guidance}");
+                    }
+                    passed = false;
+                }
+            }
+        },
+        // Bots can't read the user settings, and connecting to the gateway would change the presence
+        discord::Backend::Bot => println!("SKIP Discord: bot tokens can't be checked without connecting to the gateway")
+    }
+
+    passed
+}
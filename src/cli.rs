@@ -0,0 +1,92 @@
+//
+// The command line arguments
+//
+
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use crate::dexcom;
+
+/// Shows your blood sugar in your discord status
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Test the credentials in the config and exit (same as the `check` command)
+    #[arg(long)]
+    pub check: bool,
+    /// Ask for the credentials, test them, and save them to the config file before starting (same as the `setup` command)
+    #[arg(long)]
+    pub setup: bool,
+    /// Print every new reading to stdout in this format (the logs go to stderr instead)
+    #[arg(long, value_enum)]
+    pub emit: Option<Emit>,
+    /// The command to run. If unset, the status is updated until the program is stopped.
+    #[command(subcommand)]
+    pub command: Option<Command>
+}
+impl Cli {
+    /// Parses the command line arguments, exiting if they're invalid
+    /// 
+    /// - NOTE: `--version` also prints the build info, which can't be known at compile time by clap
+    pub fn parse_with_build_info() -> Self {
+        let matches = <Self as CommandFactory>::command()
+        .long_version(long_version())
+        .get_matches();
+
+        Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Returns the command selected by the arguments
+    pub fn command(&self) -> Command {
+        match &self.command {
+            Some(command) => command.clone(),
+            None if self.check => Command::Check,
+            None if self.setup => Command::Setup,
+            None => Command::Run
+        }
+    }
+}
+
+/// The commands the program can run
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Update the status until the program is stopped
+    Run,
+    /// Test the credentials in the config and exit
+    Check,
+    /// Ask for the credentials, test them, and save them to the config file before starting
+    Setup,
+    /// Inspect or clear the dexcom API cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction
+    },
+    /// Print the fields of the discord settings messages this build was compiled with
+    Proto
+}
+
+/// The actions of the `cache` command
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum CacheAction {
+    /// Print the cache (with the session IDs redacted)
+    Show,
+    /// Delete the cache, so the next start authenticates from scratch
+    Clear
+}
+
+/// The formats readings can be printed to stdout in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Emit {
+    /// One JSON object per line, e.g. `{"value":112.0,"value_mgdl":112,"unit":"mg/dL","trend":"Flat","timestamp":"..."}`
+    Ndjson
+}
+
+/// Returns the output of `--version`, identifying exactly which build is running
+fn long_version() -> String {
+    format!(
+        "{}\ngit hash: {}\nbuilt at: {}\ndexcom application ID: {}\ndexcom base URL: {} (US)",
+        env!("CARGO_PKG_VERSION"),
+        env!("DEXCORD_GIT_HASH"),
+        env!("DEXCORD_BUILD_TIME"),
+        dexcom::APPLICATION_ID,
+        dexcom::DEFAULT_BASE_URL
+    )
+}
@@ -0,0 +1,73 @@
+//
+// A colored summary line printed for every reading when running interactively
+// This is separate from the logs, which are meant to be read by machines as much as by people
+//
+
+use std::io::IsTerminal;
+use serde::{Deserialize, Serialize};
+use crate::dexcom::{GlucoseMeasurement, GlucoseUnit};
+
+// ANSI ESCAPE CODES
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// When the summary line is printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMode {
+    /// Only when stdout is a terminal
+    #[default]
+    Auto,
+    /// Even when stdout isn't a terminal (e.g. when piped into `less -R`)
+    Always,
+    /// Never
+    Never
+}
+impl ColorMode {
+    /// Returns whether the summary line should be printed
+    pub fn enabled(self) -> bool {
+        match self {
+            Self::Auto => std::io::stdout().is_terminal(),
+            Self::Always => true,
+            Self::Never => false
+        }
+    }
+}
+
+/// Prints the summary line of a reading, if enabled
+pub fn print_summary(mode: ColorMode, measurement: &GlucoseMeasurement, unit: GlucoseUnit, decimals: usize, level: i8, text: &str) {
+    if mode.enabled() {
+        println!("{}", summary(measurement, unit, decimals, level, text));
+    }
+}
+
+/// Returns the summary line of a reading, colored by the level of its bucket
+/// 
+/// - NOTE: Lows are red, highs are yellow, and everything in range is green
+fn summary(measurement: &GlucoseMeasurement, unit: GlucoseUnit, decimals: usize, level: i8, text: &str) -> String {
+    let color = match level {
+        ..=-1 => RED,
+        0 => GREEN,
+        1.. => YELLOW
+    };
+    format!("{color}{} {}{RESET} {text}", unit.format(measurement.value, decimals), unit.label())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(value: u32) -> GlucoseMeasurement {
+        let date = "Date(1723472400000)".to_string();
+        GlucoseMeasurement { wt: date.clone(), st: date.clone(), dt: date, value, trend: "Flat".to_string(), raw_value: None }
+    }
+
+    #[test]
+    fn summary_is_colored_by_level() {
+        assert_eq!(summary(&measurement(112), GlucoseUnit::MgDl, 0, 0, "We chillin"), "\x1b[32m112 mg/dL\x1b[0m We chillin");
+        assert!(summary(&measurement(50), GlucoseUnit::MgDl, 0, -2, "").starts_with(RED));
+        assert!(summary(&measurement(250), GlucoseUnit::MgDl, 0, 2, "").starts_with(YELLOW));
+    }
+}
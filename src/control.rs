@@ -0,0 +1,283 @@
+//
+// A local HTTP endpoint for controlling the loop while it's running (e.g. pausing updates)
+//
+
+use std::{collections::{BTreeMap, VecDeque}, net::SocketAddr, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
+use axum::{extract::{Query, State}, http::StatusCode, routing::{get, post}, Json, Router};
+use chrono::TimeDelta;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use anyhow::{Context, Result};
+use tracing::{error, info};
+use crate::{dexcom::{GlucoseMeasurement, GlucoseUnit, TimestampSource}, health::HealthState, mqtt, trend::{self, TrendDisplay}};
+
+/// The most hours of history that can be requested (the loop only keeps 2 hours of readings)
+const MAX_HISTORY_HOURS: u32 = 2;
+/// The furthest past the latest reading (in minutes) the estimated value is extrapolated (one reading interval)
+const MAX_ESTIMATE_MINUTES: f64 = 5.0;
+
+/// The state shared between the loop and the control endpoint
+#[derive(Debug, Clone, Default)]
+pub struct Shared {
+    /// Whether the loop is paused (i.e. it doesn't fetch glucose or update the status)
+    pub paused: Arc<AtomicBool>,
+    /// The health of every subsystem, which the loop updates as it goes
+    pub health: Arc<Mutex<HealthState>>,
+    /// The recent readings, from oldest to newest (a copy of the loop's buffer)
+    pub readings: Arc<Mutex<VecDeque<GlucoseMeasurement>>>,
+    /// The latest readings of the followed accounts, keyed by their labels
+    pub accounts: Arc<Mutex<BTreeMap<String, GlucoseMeasurement>>>,
+    /// The unit the readings are returned in
+    pub unit: GlucoseUnit,
+    /// How many decimals the readings are rounded to
+    pub decimals: usize,
+    /// How old the latest reading can be (in minutes) before it's reported as stale (it never is if this is 0)
+    pub stale_after_minutes: u32,
+    /// The field the age of the readings is calculated from
+    pub timestamp_source: TimestampSource,
+    /// Whether `/glucose` includes an estimate of the current value (see [`Shared::estimate`])
+    pub interpolate: bool,
+    /// How many of the most recent readings the rate of change for the estimate is calculated from
+    pub trend_window: usize
+}
+impl Shared {
+    /// Returns true if the loop is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Returns a short description of the latest reading in the provided unit (e.g. "112 mg/dL → (3 min ago)")
+    /// 
+    /// - NOTE: This is shown on discord, so it takes the unit of the status rather than the one of the endpoint
+    pub fn latest_summary(&self, unit: GlucoseUnit, decimals: usize, trend_display: TrendDisplay) -> String {
+        let Some(reading) = self.readings.lock().unwrap().back().cloned() else {
+            return "There's no glucose reading yet".to_string();
+        };
+        let mut summary = format!("{} {}", unit.format(reading.value, decimals), unit.label());
+        let arrow = reading.trend().arrow(trend_display);
+        if !arrow.is_empty() {
+            summary = format!("{summary} {arrow}");
+        }
+        match reading.age(self.timestamp_source) {
+            Some(age) => format!("{summary} ({} min ago)", age.num_minutes()),
+            None => summary
+        }
+    }
+
+    /// Returns the value (in mg/dL) the latest reading is heading toward by now at the current rate, and how many minutes ahead that is
+    /// 
+    /// - NOTE: The estimate stops moving once it's [`MAX_ESTIMATE_MINUTES`] past the reading, so a stale reading can't run away
+    /// - NOTE: This returns `None` if there aren't enough readings for a rate
+    fn estimate(&self) -> Option<(u32, f64)> {
+        let readings = self.readings.lock().unwrap();
+        let rate = trend::rate(&readings, self.trend_window, self.timestamp_source)?;
+        let latest = readings.back()?;
+        let minutes = (latest.age(self.timestamp_source)?.num_seconds() as f64 / 60.0).clamp(0.0, MAX_ESTIMATE_MINUTES);
+        let value = (latest.value as f64 + rate * minutes).round().max(0.0) as u32;
+        Some((value, (minutes * 10.0).round() / 10.0))
+    }
+
+    /// Returns how old a reading is (in seconds), and whether that's old enough to be stale
+    fn freshness(&self, reading: &GlucoseMeasurement) -> (Option<i64>, bool) {
+        let age = reading.age(self.timestamp_source);
+        let is_stale = age.is_some_and(|age| self.stale_after_minutes > 0 && age.num_minutes() >= self.stale_after_minutes.into());
+        (age.map(|age| age.num_seconds()), is_stale)
+    }
+}
+
+/// Starts the control endpoint in the background, returning the address it's listening on
+/// 
+/// - `POST /pause` pauses the loop
+/// - `POST /resume` resumes the loop
+/// - `GET /status` returns whether the loop is paused
+/// - `GET /healthz` returns the health of every subsystem (with a 503 if any of them is failing)
+/// - `GET /glucose` returns the latest reading and how fresh it is (with a 404 if there isn't one yet)
+/// - `GET /history?hours=N` returns the recent readings
+/// - `GET /accounts` returns the latest readings of the followed accounts
+/// - `GET /metrics` returns the latest reading in the Prometheus text format
+pub async fn spawn(addr: &str, shared: Shared) -> Result<SocketAddr> {
+    let listener = tokio::net::TcpListener::bind(addr).await
+    .with_context(|| format!("Failed to listen on control_addr ({addr})"))?;
+    let local_addr = listener.local_addr()?;
+
+    let router = Router::new()
+    .route("/pause", post(pause))
+    .route("/resume", post(resume))
+    .route("/status", get(status))
+    .route("/healthz", get(healthz))
+    .route("/glucose", get(glucose))
+    .route("/history", get(history))
+    .route("/accounts", get(accounts))
+    .route("/metrics", get(metrics))
+    .with_state(shared);
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            error!("The control endpoint stopped: {e:?}");
+        }
+    });
+
+    info!("Listening for control requests on {local_addr}");
+    Ok(local_addr)
+}
+
+/// Pauses the loop
+async fn pause(State(shared): State<Shared>) -> Json<Value> {
+    shared.paused.store(true, Ordering::Relaxed);
+    info!("Paused status updates");
+    status(State(shared)).await
+}
+
+/// Resumes the loop
+async fn resume(State(shared): State<Shared>) -> Json<Value> {
+    shared.paused.store(false, Ordering::Relaxed);
+    info!("Resumed status updates");
+    status(State(shared)).await
+}
+
+/// Returns the state of the loop
+async fn status(State(shared): State<Shared>) -> Json<Value> {
+    Json(json!({ "paused": shared.is_paused() }))
+}
+
+/// Returns the latest reading, in the same shape as the readings in `/history` plus its freshness
+/// 
+/// ```json
+/// { "value": 112, "value_mgdl": 112, "unit": "mg/dL", "trend": "Flat", "timestamp": "2024-08-12T14:20:00+00:00", "age_seconds": 95, "is_stale": false }
+/// ```
+/// 
+/// - NOTE: If interpolation is enabled, this also has the estimated current value (e.g. `"estimate": { "value": 115, "value_mgdl": 115, "minutes_ahead": 1.6 }`)
+async fn glucose(State(shared): State<Shared>) -> (StatusCode, Json<Value>) {
+    let reading = shared.readings.lock().unwrap().back().cloned();
+    match reading {
+        Some(reading) => {
+            let mut body = reading_json(&shared, &reading);
+            if let Some((value, minutes)) = shared.estimate().filter(|_| shared.interpolate) {
+                let estimated = GlucoseMeasurement { value, raw_value: None, ..reading };
+                body["estimate"] = json!({
+                    "value": estimated.rounded_in(shared.unit, shared.decimals),
+                    "value_mgdl": value,
+                    "minutes_ahead": minutes
+                });
+            }
+            (StatusCode::OK, Json(body))
+        },
+        None => (StatusCode::NOT_FOUND, Json(Value::Null))
+    }
+}
+
+/// Returns the latest readings of the followed accounts, in the same shape as `/glucose`
+/// 
+/// ```json
+/// { "kid": { "value": 112, "value_mgdl": 112, "unit": "mg/dL", "trend": "Flat", "timestamp": "2024-08-12T14:20:00+00:00", "age_seconds": 95, "is_stale": false } }
+/// ```
+async fn accounts(State(shared): State<Shared>) -> Json<Value> {
+    let accounts = shared.accounts.lock().unwrap().iter()
+    .map(|(label, reading)| (label.clone(), reading_json(&shared, reading)))
+    .collect();
+    Json(Value::Object(accounts))
+}
+
+/// Returns a reading and its freshness
+fn reading_json(shared: &Shared, reading: &GlucoseMeasurement) -> Value {
+    let (age_seconds, is_stale) = shared.freshness(reading);
+    let mut body = mqtt::state_json(reading, shared.unit, shared.decimals);
+    body["age_seconds"] = json!(age_seconds);
+    body["is_stale"] = json!(is_stale);
+    body
+}
+
+/// Returns the latest readings as Prometheus metrics, with the followed accounts labelled by `account`
+/// 
+/// - NOTE: `glucose_is_stale` is 1 if there's no reading yet, and the other metrics are left out
+async fn metrics(State(shared): State<Shared>) -> String {
+    let mut readings = vec![(String::new(), shared.readings.lock().unwrap().back().cloned())];
+    readings.extend(shared.accounts.lock().unwrap().iter().map(|(label, reading)| {
+        let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+        (format!("{{account=\"{label}\"}}"), Some(reading.clone()))
+    }));
+
+    let mut values = String::new();
+    let mut raw_values = String::new();
+    let mut ages = String::new();
+    let mut stale = String::new();
+    for (labels, reading) in &readings {
+        let (age_seconds, is_stale) = match reading {
+            Some(reading) => shared.freshness(reading),
+            None => (None, true)
+        };
+        if let Some(reading) = reading {
+            values.push_str(&format!("glucose_value_mgdl{labels} {}\n", reading.value));
+            raw_values.push_str(&format!("glucose_raw_value_mgdl{labels} {}\n", reading.raw_value.unwrap_or(reading.value)));
+        }
+        if let Some(age_seconds) = age_seconds {
+            ages.push_str(&format!("glucose_age_seconds{labels} {age_seconds}\n"));
+        }
+        stale.push_str(&format!("glucose_is_stale{labels} {}\n", u8::from(is_stale)));
+    }
+
+    let mut metrics = String::new();
+    for (name, help, samples) in [
+        ("glucose_value_mgdl", "The latest glucose value in mg/dL", values),
+        ("glucose_raw_value_mgdl", "The latest glucose value in mg/dL as reported by the sensor (before the calibration offset)", raw_values),
+        ("glucose_age_seconds", "How long ago the latest glucose reading was taken", ages),
+        ("glucose_is_stale", "Whether the latest glucose reading is too old to be live", stale)
+    ] {
+        if !samples.is_empty() {
+            metrics.push_str(&format!("# HELP {name} {help}\n# TYPE {name} gauge\n{samples}"));
+        }
+    }
+    metrics
+}
+
+/// The query of the history endpoint
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// How many hours of readings to return, counting back from the newest one (this is capped at 2)
+    hours: Option<u32>
+}
+
+/// Returns the recent readings, from oldest to newest
+/// 
+/// ```json
+/// [
+///     { "value": 6.0, "value_mgdl": 108, "unit": "mmol/L", "trend": "FortyFiveUp", "timestamp": "2024-08-12T14:15:00+00:00" },
+///     { "value": 6.2, "value_mgdl": 112, "unit": "mmol/L", "trend": "Flat", "timestamp": "2024-08-12T14:20:00+00:00" }
+/// ]
+/// ```
+async fn history(State(shared): State<Shared>, Query(query): Query<HistoryQuery>) -> Json<Value> {
+    let hours = query.hours.unwrap_or(MAX_HISTORY_HOURS).min(MAX_HISTORY_HOURS);
+    let readings = shared.readings.lock().unwrap();
+
+    // Count back from the newest reading, rather than from now, so a stale buffer still has a history
+    let since = readings.back()
+    .and_then(GlucoseMeasurement::timestamp)
+    .map(|t| t - TimeDelta::hours(hours as i64));
+
+    let history: Vec<Value> = readings.iter()
+    .filter(|r| since.is_none_or(|since| r.timestamp().is_some_and(|t| t >= since)))
+    .map(|r| mqtt::state_json(r, shared.unit, shared.decimals))
+    .collect();
+    Json(Value::Array(history))
+}
+
+/// Returns the health of every subsystem
+/// 
+/// ```json
+/// {
+///     "healthy": false,
+///     "subsystems": {
+///         "sources": { "last_success": "2024-08-12T14:20:00Z", "last_error_at": null, "last_error": null },
+///         "discord": { "last_success": null, "last_error_at": "2024-08-12T14:20:01Z", "last_error": "401 Unauthorized" }
+///     }
+/// }
+/// ```
+async fn healthz(State(shared): State<Shared>) -> (StatusCode, Json<Value>) {
+    let health = shared.health.lock().unwrap().clone();
+    let code = match health.is_healthy() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (code, Json(json!({ "healthy": health.is_healthy(), "subsystems": health.subsystems })))
+}
@@ -2,65 +2,157 @@
 // An interface to the (undocumented) Dexcom Share API
 //
 
-use std::{env::current_exe, fs::File, path::PathBuf};
+use std::{collections::{BTreeMap, BTreeSet}, env::current_exe, fs::File, path::{Path, PathBuf}, time::Duration};
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 use anyhow::{Context, Result};
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, trace, warn};
+use crate::{http::{self, RequestExt}, trend::Trend};
 
 /// The application ID
-const APPLICATION_ID: &str = "d89443d2-327c-4a6f-89e5-496bbb0317db";
-/// The URL to fetch the account ID
-const ACCOUNT_ID_URL: &str = "https://share2.dexcom.com/ShareWebServices/Services/General/AuthenticatePublisherAccount";
-/// The URL to fetch the session ID
-const SESSION_ID_URL: &str = "https://share2.dexcom.com/ShareWebServices/Services/General/LoginPublisherAccountById";
-/// The URL to fetch glucose measurements
-const MEASURE_GLUCOSE_URL: &str = "https://share2.dexcom.com/ShareWebServices/Services/Publisher/ReadPublisherLatestGlucoseValues";
+pub(crate) const APPLICATION_ID: &str = "d89443d2-327c-4a6f-89e5-496bbb0317db";
+/// The default base URL of the API
+pub const DEFAULT_BASE_URL: &str = "https://share2.dexcom.com";
+/// The path to fetch the account ID
+pub(crate) const ACCOUNT_ID_PATH: &str = "/ShareWebServices/Services/General/AuthenticatePublisherAccount";
+/// The path to fetch the session ID
+pub(crate) const SESSION_ID_PATH: &str = "/ShareWebServices/Services/General/LoginPublisherAccountById";
+/// The path to fetch glucose measurements
+pub(crate) const MEASURE_GLUCOSE_PATH: &str = "/ShareWebServices/Services/Publisher/ReadPublisherLatestGlucoseValues";
 /// The oldest glucose measurement to fetch
 const DEFAULT_MINUTES: usize = 60;
 /// The maximum number of glucose measurements to fetch
 const DEFAULT_MAX_COUNT: usize = 1;
+/// The oldest glucose measurement the API allows us to fetch (24 hours)
+const MAX_LOOKBACK_MINUTES: usize = 1440;
+/// The maximum number of glucose measurements to fetch for the history (one every 5 minutes for 24 hours)
+const MAX_HISTORY_COUNT: usize = 288;
+/// The fields of a glucose measurement that are parsed
+const KNOWN_FIELDS: [&str; 5] = ["WT", "ST", "DT", "Value", "Trend"];
+
+/// Options for connecting to the API
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The base URL of the API (e.g. `https://share2.dexcom.com`)
+    pub base_url: String,
+    /// The path to the API cache file
+    pub cache_path: PathBuf,
+    /// The proxy the requests are sent through
+    pub proxy: Option<reqwest::Proxy>,
+    /// The directory the (redacted) response bodies are written to. Nothing is written if this is unset.
+    pub dump_dir: Option<PathBuf>,
+    /// How many times authenticating is retried if the network isn't ready
+    pub auth_retries: u32,
+    /// How long to wait before the first retry (this doubles after every retry)
+    pub auth_retry_delay: Duration,
+    /// How long the account and session ID requests can take before they time out
+    pub auth_timeout: Duration,
+    /// Whether the retry delays are randomized (see [`http::jitter`])
+    pub retry_jitter: bool,
+    /// Whether the requests are only sent over IPv4 (see [`http::ipv4_only`])
+    pub force_ipv4: bool,
+    /// How old a session can get before we authenticate from scratch, even if dexcom still accepts it. Sessions never get too old if this is unset.
+    pub max_session_age: Option<Duration>
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            cache_path: default_cache_path(),
+            proxy: None,
+            dump_dir: None,
+            auth_retries: 5,
+            auth_retry_delay: Duration::from_secs(5),
+            auth_timeout: Duration::from_secs(30),
+            retry_jitter: true,
+            force_ipv4: false,
+            max_session_age: None
+        }
+    }
+}
+
+/// Returns the default path to the API cache file (next to the executable)
+pub fn default_cache_path() -> PathBuf {
+    current_exe()
+    .unwrap()
+    .parent()
+    .unwrap()
+    .to_path_buf()
+    .join("api_cache.json")
+}
+
+/// Returns the default directory for debug dumps (next to the executable)
+pub fn default_dump_dir() -> PathBuf {
+    current_exe()
+    .unwrap()
+    .parent()
+    .unwrap()
+    .join("debug_dump")
+}
 
 #[derive(Debug)]
 pub struct Api {
     /// The HTTP client
     client: reqwest::Client,
+    /// The base URL of the API
+    base_url: String,
     /// The password of the account
     password: String,
     /// Cachable information regarding the API connection
-    cache: ApiCache
+    cache: ApiCache,
+    /// The directory the response bodies are dumped to
+    dump_dir: Option<PathBuf>,
+    /// How long the auth requests can take before they time out
+    auth_timeout: Duration,
+    /// How many times authenticating is retried, how long to wait before the first retry, and whether the wait is randomized
+    auth_retry: (u32, Duration, bool),
+    /// How old a session can get before we authenticate from scratch
+    max_session_age: Option<Duration>
 }
 impl Api {
-    pub async fn new(username: &str, password: &str) -> Result<Self> {
+    pub async fn new(username: &str, password: &str, options: Options) -> Result<Self> {
 
         // Ensure the username and password are not empty
         if username.is_empty() { Err(Error::ArgUsername)? };
         if password.is_empty() { Err(Error::ArgPassword)? };
 
         // Create the HTTP client
-        let mut client = reqwest::Client::new();
+        let mut builder = http::ipv4_only(reqwest::ClientBuilder::default(), options.force_ipv4);
+        if let Some(proxy) = options.proxy {
+            builder = builder.proxy(proxy);
+        }
+        let mut client = builder.build()?;
 
         // Try to load the cache if it exists, otherwise create a new one
         let mut should_refresh_cache = false;
-        let cache = ApiCache::try_load_cache(username).unwrap_or_else(|| {
+        let cache = ApiCache::try_load_cache(&options.cache_path, username).unwrap_or_else(|| {
             // Update the cache refresh flag
             should_refresh_cache = true;
-            ApiCache::default()
+            ApiCache::new(options.cache_path.clone())
         });
+        if !should_refresh_cache && cache.is_older_than(options.max_session_age) {
+            debug!("The cached session is older than max_session_age_secs, so it'll be replaced");
+            should_refresh_cache = true;
+        }
 
         // Create an instance of self
         let mut s = Self {
             client,
+            base_url: options.base_url.trim_end_matches('/').to_string(),
             password: password.to_string(),
-            cache
+            cache,
+            dump_dir: options.dump_dir,
+            auth_timeout: options.auth_timeout,
+            auth_retry: (options.auth_retries, options.auth_retry_delay, options.retry_jitter),
+            max_session_age: options.max_session_age
         };
 
         // Update the username
-        s.cache.username = username.to_string();
+        s.cache.set_username(username);
 
         // Update the account and session ID cache if necessary
         if should_refresh_cache {
-            s.cache.account_id = s.get_account_id().await?;
-            s.cache.session_id = s.get_session_id().await?;
+            s.authenticate(options.auth_retries, options.auth_retry_delay, options.retry_jitter).await?;
             // Save the cache
             s.cache.save();
         }
@@ -68,19 +160,111 @@ impl Api {
         Ok(s)
     }
 
+    /// Fetches the account and session IDs, retrying with backoff if the network isn't ready
+    /// 
+    /// - NOTE: Errors from the API itself (e.g. an invalid password) are returned immediately, since retrying won't help
+    #[tracing::instrument(name = "auth", skip_all)]
+    async fn authenticate(&mut self, retries: u32, delay: Duration, jitter: bool) -> Result<()> {
+        let mut attempt = 0;
+
+        loop {
+            let result = async {
+                // The IDs only go in the cache together, so a failed session request can't leave a half updated cache behind
+                let account_id = self.get_account_id().await?;
+                let session_id = self.get_session_id(&account_id).await?;
+                self.cache.set_account_id(account_id);
+                self.cache.set_session_id(session_id);
+                self.cache.set_authenticated_at(Utc::now());
+                anyhow::Ok(())
+            }.await;
+
+            let Err(e) = result else {
+                return Ok(());
+            };
+
+            // Only retry network errors and unexpected responses (e.g. a captive portal)
+            let retryable = e.downcast_ref::<reqwest::Error>().is_some()
+            || matches!(e.downcast_ref::<Error>(), Some(Error::Unknown(_)));
+            if !retryable || attempt >= retries {
+                return Err(e);
+            }
+
+            let wait = http::jitter(delay.saturating_mul(1 << attempt.min(16)), jitter);
+            attempt += 1;
+            warn!("Failed to authenticate to dexcom ({e}). Retrying in {wait:?} ({attempt}/{retries})...");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Authenticates from scratch (new account and session IDs) if the session is older than the max session age
+    /// 
+    /// - NOTE: This catches sessions that keep being accepted but stop returning new readings, which never expire on their own
+    async fn renew_old_session(&mut self) -> Result<()> {
+        if !self.cache.is_older_than(self.max_session_age) {
+            return Ok(());
+        }
+
+        info!("The dexcom session is older than max_session_age_secs, so it's being replaced...");
+        let (retries, delay, jitter) = self.auth_retry;
+        self.authenticate(retries, delay, jitter).await?;
+        self.cache.save();
+        Ok(())
+    }
+
+    /// Returns the full URL of an API endpoint
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Writes a response body to a timestamped file in the dump directory, with the credentials redacted
+    /// 
+    /// - NOTE: This only logs a warning if the file can't be written
+    fn dump(&self, path: &str, body: &str) {
+        let Some(dir) = &self.dump_dir else {
+            return;
+        };
+
+        // The auth endpoints respond with just the ID, so the whole body is sensitive
+        let mut redacted = if (path == ACCOUNT_ID_PATH || path == SESSION_ID_PATH) && serde_json::from_str::<String>(body).is_ok() {
+            "\"[REDACTED]\"".to_string()
+        } else {
+            body.to_string()
+        };
+        for secret in [&self.password, &self.cache.account_id, &self.cache.session_id, &self.cache.username] {
+            if !secret.is_empty() {
+                redacted = redacted.replace(secret.as_str(), "[REDACTED]");
+            }
+        }
+        // Also scrub anything else that identifies the user, if that's enabled
+        let redacted = crate::redact::redact(&redacted);
+
+        // Name the file after the time and the endpoint (e.g. `dexcom-20240812T142000.000Z-ReadPublisherLatestGlucoseValues.json`)
+        let endpoint = path.rsplit('/').next().unwrap_or_default();
+        let file_name = format!("dexcom-{}-{endpoint}.json", Utc::now().format("%Y%m%dT%H%M%S%.3fZ"));
+
+        let result = std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(dir.join(&file_name), redacted.as_bytes()));
+        match result {
+            Ok(()) => trace!("Dumped the response body to {file_name}"),
+            Err(e) => warn!("Failed to dump the response body: {e:?}")
+        }
+    }
+
     /// Queries the API for the ID of the account
     async fn get_account_id(&self) -> Result<String> {
         debug!("Getting account ID...");
 
         // Send the request to the API and get the response body
-        let body = self.client.post(ACCOUNT_ID_URL)
+        let body = self.client.post(self.url(ACCOUNT_ID_PATH))
         .json(&AccountIdRequest {
             username: &self.cache.username,
             password: &self.password,
             application_id: APPLICATION_ID
         })
-        .send().await?
+        .timeout(self.auth_timeout)
+        .send_logged().await?
         .text().await?;
+        self.dump(ACCOUNT_ID_PATH, &body);
 
         // Parse the response body into an account ID string
         if let Ok(account_id) = serde_json::from_str::<String>(&body) {
@@ -97,18 +281,20 @@ impl Api {
         }
     }
 
-    async fn get_session_id(&self) -> Result<String> {
+    async fn get_session_id(&self, account_id: &str) -> Result<String> {
         debug!("Getting session ID...");
 
         // Send the request to the API and get the response body
-        let body = self.client.post(SESSION_ID_URL)
+        let body = self.client.post(self.url(SESSION_ID_PATH))
         .json(&SessionIdRequest {
-            account_id: &self.cache.account_id,
+            account_id,
             password: &self.password,
             application_id: APPLICATION_ID
         })
-        .send().await?
+        .timeout(self.auth_timeout)
+        .send_logged().await?
         .text().await?;
+        self.dump(SESSION_ID_PATH, &body);
 
         // Parse the response body into a session ID string
         if let Ok(session_id) = serde_json::from_str::<String>(&body) {
@@ -125,32 +311,60 @@ impl Api {
         }
     }
 
+    /// Queries the API for the latest glucose measurement
     pub async fn get_latest_glucose(&mut self) -> Result<Option<GlucoseMeasurement>> {
+        self.renew_old_session().await?;
+        let mut measurements = self.get_glucose(DEFAULT_MINUTES, DEFAULT_MAX_COUNT).await?;
+
+        if measurements.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(measurements.remove(0)))
+        }
+    }
+
+    /// Queries the API for the glucose measurements over the last `hours` hours, sorted from oldest to newest
+    /// 
+    /// - NOTE: The API only allows looking back 24 hours, so larger values are clamped
+    pub async fn get_history(&mut self, hours: u8) -> Result<Vec<GlucoseMeasurement>> {
+        let minutes = (hours as usize * 60).min(MAX_LOOKBACK_MINUTES);
+        let mut measurements = self.get_glucose(minutes, MAX_HISTORY_COUNT).await?;
+
+        // The API returns the newest measurement first, but consumers want a time series
+        measurements.sort_by_key(|m| m.timestamp());
+
+        Ok(measurements)
+    }
+
+    /// Queries the API for up to `max_count` glucose measurements over the last `minutes` minutes
+    async fn get_glucose(&mut self, minutes: usize, max_count: usize) -> Result<Vec<GlucoseMeasurement>> {
 
         // Send the request to the API and get the response body
-        let body = self.client.post(MEASURE_GLUCOSE_URL)
+        let body = self.client.post(self.url(MEASURE_GLUCOSE_PATH))
         .json(&MeasureGlucoseRequest {
             session_id: &self.cache.session_id,
-            minutes: DEFAULT_MINUTES,
-            max_count: DEFAULT_MAX_COUNT
+            minutes,
+            max_count
         })
-        .send().await?
+        .send_logged().await?
         .text().await?;
+        self.dump(MEASURE_GLUCOSE_PATH, &body);
 
-        // Parse the response body into a session ID string
-        if let Ok(mut response) = serde_json::from_str::<Vec<GlucoseMeasurement>>(&body) {
-            if response.is_empty() {
-                Ok(None)
-            } else {
-                Ok(Some(response.remove(0)))
+        // Parse the response body into a list of glucose measurements
+        if let Ok(response) = serde_json::from_str::<Vec<GlucoseMeasurement>>(&body) {
+            let unknown = unknown_fields(&body);
+            if !unknown.is_empty() {
+                debug!("The glucose measurements have fields we don't know about: {unknown:?}");
             }
+            Ok(response)
         }
         // Parse the response body into an error
         else if let Ok(e) = serde_json::from_str::<ErrorResponse>(&body) {
 
             // If the session ID just expired, try to renew it for the next request
             if let Error::SessionInvalid = e.code {
-                self.cache.session_id = self.get_session_id().await?;
+                let session_id = self.get_session_id(&self.cache.account_id).await?;
+                self.cache.set_session_id(session_id);
                 // Save the cache
                 self.cache.save();
             }
@@ -167,77 +381,193 @@ impl Api {
 
 /// Cachable information regarding the API. These are saved and fetched from the cache file.
 /// 
-/// - NOTE: This caches the username so we can hopefully detect if the targeted user has changed (thus requiring a cache refresh)
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// - NOTE: The cache file holds the IDs of every account that has been used, so switching accounts doesn't require re-authenticating
+/// - NOTE: The cache is only written if it changed since it was loaded or last saved
+#[derive(Debug)]
 struct ApiCache {
+    /// The path to the cache file
+    path: PathBuf,
     /// The username of the account
     username: String,
     /// The ID of the account
     account_id: String,
     /// The ID of the session
-    session_id: String
+    session_id: String,
+    /// When the IDs were last fetched by authenticating (`None` if that was before the time was cached)
+    authenticated_at: Option<DateTime<Utc>>,
+    /// Whether any of the IDs changed since the cache was loaded or last saved
+    dirty: bool
 }
 impl ApiCache {
-    fn try_load_cache(username: &str) -> Option<Self> {
-        debug!("Trying to load the API cache...");
+    /// Creates a new, empty cache that will be saved to `path`
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            username: String::new(),
+            account_id: String::new(),
+            session_id: String::new(),
+            authenticated_at: None,
+            dirty: false
+        }
+    }
 
-        // Get the path to the cache file
-        let path = {
-            current_exe()
-            .unwrap()
-            .parent()
-            .unwrap()
-            .to_path_buf()
-            .join("api_cache.json")
-        };
-        // Open the cache file
-        let file = File::open(path);
+    /// Updates the username, marking the cache as changed if it's different
+    fn set_username(&mut self, username: &str) {
+        if self.username != username {
+            self.username = username.to_string();
+            self.dirty = true;
+        }
+    }
 
-        // If the file doesn't exist or we can't open it, return None (i.e. create a new cache)
-        if let Err(e) = file {
-            warn!("Failed to open the API cache file: {e:?}");
-            return None;
+    /// Updates the account ID, marking the cache as changed if it's different
+    fn set_account_id(&mut self, account_id: String) {
+        if self.account_id != account_id {
+            self.account_id = account_id;
+            self.dirty = true;
         }
-        let file = file.unwrap();
+    }
 
-        // Read the cache file
-        let cached_s: Self = serde_json::from_reader(file)
-        .context("The API cache is invalid (perhaps try deleting it)")
-        .unwrap();
+    /// Updates the session ID, marking the cache as changed if it's different
+    fn set_session_id(&mut self, session_id: String) {
+        if self.session_id != session_id {
+            self.session_id = session_id;
+            self.dirty = true;
+        }
+    }
+
+    /// Updates when we last authenticated, marking the cache as changed
+    fn set_authenticated_at(&mut self, authenticated_at: DateTime<Utc>) {
+        self.authenticated_at = Some(authenticated_at);
+        self.dirty = true;
+    }
+
+    /// Returns true if we authenticated longer than `max_age` ago (or at an unknown time), and false if there's no max age
+    fn is_older_than(&self, max_age: Option<Duration>) -> bool {
+        let Some(max_age) = max_age else {
+            return false;
+        };
+        self.authenticated_at.is_none_or(|at| Utc::now() - at > TimeDelta::from_std(max_age).unwrap_or(TimeDelta::MAX))
+    }
+
+    /// Returns the cached IDs of the account, or `None` if the account isn't in the cache
+    fn try_load_cache(path: &Path, username: &str) -> Option<Self> {
+        debug!("Trying to load the API cache...");
+
+        let ids = CacheFile::load(path).accounts.remove(username)?;
+        debug!("API cache is still valid");
+
+        Some(Self {
+            path: path.to_path_buf(),
+            username: username.to_string(),
+            account_id: ids.account_id,
+            session_id: ids.session_id,
+            authenticated_at: ids.authenticated_at,
+            dirty: false
+        })
+    }
 
-        // The username changed. The cache should be refreshed
-        if cached_s.username != username {
-            None
+    /// Saves the IDs of the account to the cache file, keeping the IDs of the other accounts
+    /// 
+    /// - NOTE: This does nothing if the cache didn't change
+    /// - NOTE: A failed write is only logged (this also runs on drop), and it's tried again on the next save
+    fn save(&mut self) {
+        // There's nothing worth saving until something changed and we've authenticated
+        if !self.dirty || self.username.is_empty() || self.account_id.is_empty() || self.session_id.is_empty() {
+            return;
         }
-        // The cache is still valid, so return it
-        else {
-            debug!("API cache is still valid");
-            Some(cached_s)
+
+        let mut file = CacheFile::load(&self.path);
+        file.accounts.insert(self.username.clone(), CachedIds {
+            account_id: self.account_id.clone(),
+            session_id: self.session_id.clone(),
+            authenticated_at: self.authenticated_at
+        });
+        if let Err(e) = file.save(&self.path) {
+            warn!("{e:?}. The dexcom session will be renewed after a restart");
+            return;
         }
+        self.dirty = false;
+    }
+}
+impl Drop for ApiCache {
+    fn drop(&mut self) {
+        self.save();
     }
+}
+
+/// Returns the contents of the cache file as JSON, with the session IDs redacted
+pub fn show_cache(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+    .with_context(|| format!("Failed to read the API cache file at {path:?}"))?;
+    let mut file: CacheFile = serde_json::from_str(&contents)
+    .context("The API cache file is invalid")?;
+
+    for ids in file.accounts.values_mut() {
+        ids.session_id = "<redacted>".to_string();
+    }
+    Ok(serde_json::to_string_pretty(&file)?)
+}
 
-    fn save(&self) {
-        // Get the path to the cache file
-        let path = {
-            current_exe()
-            .unwrap()
-            .parent()
-            .unwrap()
-            .to_path_buf()
-            .join("api_cache.json")
+/// Deletes the cache file, so the next start authenticates from scratch
+/// 
+/// - NOTE: This returns false if there was no cache file
+pub fn clear_cache(path: &Path) -> Result<bool> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to delete the API cache file at {path:?}"))
+    }
+}
+
+/// The contents of the cache file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    /// The cached IDs, keyed by username
+    accounts: BTreeMap<String, CachedIds>
+}
+impl CacheFile {
+    /// Reads the cache file
+    /// 
+    /// - NOTE: If the file doesn't exist or is invalid (e.g. it's from an older version), this returns an empty cache
+    fn load(path: &Path) -> Self {
+        // Open the cache file
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Failed to open the API cache file: {e:?}");
+                return Self::default();
+            }
         };
+
+        // Read the cache file
+        serde_json::from_reader(file).unwrap_or_else(|e| {
+            warn!("The API cache is invalid, so it will be replaced: {e:?}");
+            Self::default()
+        })
+    }
+
+    /// Writes the cache file
+    fn save(&self, path: &Path) -> Result<()> {
         // Create the cache file
-        let file = File::create(path).unwrap();
+        let file = File::create(path)
+        .with_context(|| format!("Failed to create the API cache file at {path:?}"))?;
         // Write self to the cache file
         serde_json::to_writer_pretty(file, &self)
-        .context("Failed to write to the API cache file")
-        .unwrap();
+        .context("Failed to write to the API cache file")?;
+        Ok(())
     }
 }
-impl Drop for ApiCache {
-    fn drop(&mut self) {
-        self.save();
-    }
+
+/// The cached IDs of a single account
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedIds {
+    /// The ID of the account
+    account_id: String,
+    /// The ID of the session
+    session_id: String,
+    /// When the IDs were fetched (missing from caches written by older versions)
+    #[serde(default)]
+    authenticated_at: Option<DateTime<Utc>>
 }
 
 // API REQUESTS
@@ -281,15 +611,19 @@ struct MeasureGlucoseRequest<'a> {
 }
 
 /// A single glucose measurement in the glucose readings response body
-#[derive(Debug, Deserialize)]
+/// 
+/// - NOTE: The publisher endpoint only returns these fields. The transmitter battery, sensor session start, and sensor expiry
+///   are only available to the Dexcom app itself, so a sensor that's about to expire can't be detected from here.
+///   Any other field that shows up is logged (see `unknown_fields`), so we notice if that ever changes.
+#[derive(Debug, Clone, Deserialize)]
 pub struct GlucoseMeasurement {
-    /// The date and time of the measurement
+    /// The date and time of the measurement (wall time)
     #[serde(rename = "WT")]
     pub wt: String,
-    /// The date and time of the measurement
+    /// The date and time of the measurement (system time, according to the receiver's clock)
     #[serde(rename = "ST")]
     pub st: String,
-    /// The date and time of the measurement
+    /// The date and time of the measurement (display time, including the receiver's timezone offset)
     #[serde(rename = "DT")]
     pub dt: String,
     /// The glucose value
@@ -297,7 +631,164 @@ pub struct GlucoseMeasurement {
     pub value: u32,
     /// The trend of the glucose value
     #[serde(rename = "Trend")]
-    pub trend: String
+    pub trend: String,
+    /// The value reported by the sensor, if a calibration offset was applied to `value` (see [`GlucoseMeasurement::calibrated`])
+    #[serde(skip)]
+    pub raw_value: Option<u32>
+}
+impl GlucoseMeasurement {
+    /// Returns the measurement with the offset (in mg/dL) added to its value, keeping the reported value in `raw_value`
+    /// 
+    /// - NOTE: An offset of 0 leaves the measurement untouched, and the value never goes below 1 mg/dL
+    pub fn calibrated(self, offset: i32) -> Self {
+        if offset == 0 {
+            return self;
+        }
+        let value = self.value.saturating_add_signed(offset).max(1);
+        Self { value, raw_value: Some(self.raw_value.unwrap_or(self.value)), ..self }
+    }
+
+    /// Returns the date and time of the measurement, parsed from the `WT` field
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.timestamp_from(TimestampSource::Wt)
+    }
+
+    /// Returns the date and time of the measurement, parsed from the provided field
+    pub fn timestamp_from(&self, source: TimestampSource) -> Option<DateTime<Utc>> {
+        match source {
+            TimestampSource::Wt => parse_date(&self.wt),
+            TimestampSource::St => parse_date(&self.st),
+            TimestampSource::Dt => parse_date(&self.dt)
+        }
+    }
+
+    /// Returns how long ago the measurement was taken, according to the provided field
+    /// 
+    /// - NOTE: The age is clamped to zero if the measurement is in the future (i.e. the system clock is behind)
+    pub fn age(&self, source: TimestampSource) -> Option<TimeDelta> {
+        self.age_at(Utc::now(), source)
+    }
+
+    /// Returns how long before `now` the measurement was taken, clamped to zero
+    pub fn age_at(&self, now: DateTime<Utc>, source: TimestampSource) -> Option<TimeDelta> {
+        Some((now - self.timestamp_from(source)?).max(TimeDelta::zero()))
+    }
+
+    /// Returns true if the other measurement is the same reading, i.e. their timestamps are at most `tolerance` apart
+    /// 
+    /// - NOTE: Measurements without a valid timestamp are only the same if their `WT` fields match exactly
+    pub fn is_same_reading(&self, other: &Self, tolerance: TimeDelta) -> bool {
+        match (self.timestamp(), other.timestamp()) {
+            (Some(a), Some(b)) => same_time(a, b, tolerance),
+            _ => self.wt == other.wt
+        }
+    }
+
+    /// Returns the trend of the glucose value
+    pub fn trend(&self) -> Trend {
+        Trend::from_name(&self.trend)
+    }
+
+    /// Returns the glucose value in the provided unit
+    pub fn value_in(&self, unit: GlucoseUnit) -> f64 {
+        unit.convert(self.value)
+    }
+
+    /// Returns the glucose value in the provided unit, rounded to the number of decimals
+    pub fn rounded_in(&self, unit: GlucoseUnit, decimals: usize) -> f64 {
+        let scale = 10f64.powi(decimals as i32);
+        (self.value_in(unit) * scale).round() / scale
+    }
+}
+
+/// The fields of a measurement that can be used as its timestamp
+/// 
+/// - NOTE: These can differ if the receiver's clock has drifted, or its timezone is wrong
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampSource {
+    /// The wall time (`WT`)
+    #[default]
+    Wt,
+    /// The system time of the receiver (`ST`)
+    St,
+    /// The display time of the receiver (`DT`)
+    Dt
+}
+
+/// The units glucose values can be shown in
+/// 
+/// - NOTE: The APIs always return mg/dL, and every threshold in the config is in mg/dL
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GlucoseUnit {
+    /// Milligrams per deciliter (used in the US)
+    #[default]
+    #[serde(rename = "mg/dL")]
+    MgDl,
+    /// Millimoles per liter (used in most other countries)
+    #[serde(rename = "mmol/L")]
+    MmolL
+}
+impl GlucoseUnit {
+    /// How many mg/dL are in a mmol/L of glucose
+    const MGDL_PER_MMOLL: f64 = 18.0182;
+
+    /// Converts a value in mg/dL to this unit
+    pub fn convert(self, mgdl: u32) -> f64 {
+        match self {
+            Self::MgDl => mgdl as f64,
+            Self::MmolL => mgdl as f64 / Self::MGDL_PER_MMOLL
+        }
+    }
+
+    /// Formats a value in mg/dL in this unit with the number of decimals (e.g. `112` or `6.2`)
+    pub fn format(self, mgdl: u32, decimals: usize) -> String {
+        format!("{:.*}", decimals, self.convert(mgdl))
+    }
+
+    /// Returns the configured number of decimals, or the usual number for this unit (0 for mg/dL and 1 for mmol/L)
+    pub fn decimals(self, configured: Option<u8>) -> usize {
+        match (configured, self) {
+            (Some(decimals), _) => decimals as usize,
+            (None, Self::MgDl) => 0,
+            (None, Self::MmolL) => 1
+        }
+    }
+
+    /// Returns the label of the unit (e.g. `mg/dL`)
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::MgDl => "mg/dL",
+            Self::MmolL => "mmol/L"
+        }
+    }
+}
+
+/// Returns the fields of the glucose measurements in a response body that aren't parsed (e.g. sensor status fields)
+fn unknown_fields(body: &str) -> BTreeSet<String> {
+    serde_json::from_str::<Vec<serde_json::Map<String, serde_json::Value>>>(body)
+    .unwrap_or_default()
+    .into_iter()
+    .flat_map(|m| m.into_iter().map(|(k, _)| k))
+    .filter(|k| !KNOWN_FIELDS.contains(&k.as_str()))
+    .collect()
+}
+
+/// Parses a date from the API (e.g. `Date(1691423454000)` or `Date(1691423454000-0400)`)
+/// 
+/// - NOTE: The number is always milliseconds since the unix epoch. The optional offset is only informational.
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    let inner = date
+    .trim_matches('/')
+    .strip_prefix("Date(")?
+    .strip_suffix(')')?;
+
+    // Strip the timezone offset if there is one
+    let millis = inner
+    .find(['+', '-'])
+    .map_or(inner, |i| &inner[..i]);
+
+    DateTime::from_timestamp_millis(millis.parse().ok()?)
 }
 
 /// An error response from the API
@@ -335,3 +826,75 @@ pub enum Error {
     #[error("Encountered an unknown error: {0}")]
     Unknown(String)
 }
+
+/// Returns true if the timestamps are at most `tolerance` apart, since dexcom's timestamps can wobble by a few seconds for the same reading
+pub fn same_time(a: DateTime<Utc>, b: DateTime<Utc>, tolerance: TimeDelta) -> bool {
+    (a - b).abs() <= tolerance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(wt: &str) -> GlucoseMeasurement {
+        GlucoseMeasurement { wt: wt.to_string(), st: wt.to_string(), dt: wt.to_string(), value: 112, trend: "Flat".to_string(), raw_value: None }
+    }
+
+    #[test]
+    fn readings_within_the_tolerance_are_the_same() {
+        let reading = measurement("Date(1723472400000)");
+        let tolerance = TimeDelta::seconds(5);
+        assert!(reading.is_same_reading(&measurement("Date(1723472403000)"), tolerance));
+        assert!(reading.is_same_reading(&measurement("Date(1723472395000)"), tolerance));
+        assert!(!reading.is_same_reading(&measurement("Date(1723472406000)"), tolerance));
+        assert!(!reading.is_same_reading(&measurement("Date(1723472403000)"), TimeDelta::zero()));
+        assert!(measurement("garbage").is_same_reading(&measurement("garbage"), tolerance));
+    }
+
+    #[test]
+    fn parses_dates_with_and_without_offsets() {
+        assert_eq!(parse_date("Date(1723472400000)"), DateTime::from_timestamp_millis(1723472400000));
+        assert_eq!(parse_date("/Date(1723472400000-0400)/"), DateTime::from_timestamp_millis(1723472400000));
+    }
+
+    #[test]
+    fn finds_unknown_measurement_fields() {
+        let body = r#"[{ "WT": "Date(1723472400000)", "Value": 112, "Trend": "Flat", "TransmitterBattery": "Ok" }]"#;
+        assert_eq!(unknown_fields(body), BTreeSet::from(["TransmitterBattery".to_string()]));
+        assert!(unknown_fields(crate::tests::fixtures::GLUCOSE).is_empty());
+    }
+
+    #[test]
+    fn shown_cache_is_redacted() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("api_cache.json");
+        let mut file = CacheFile::default();
+        file.accounts.insert("username".to_string(), CachedIds { account_id: "account".to_string(), session_id: "secret".to_string(), authenticated_at: None });
+        file.save(&path).unwrap();
+
+        let shown = show_cache(&path).unwrap();
+        assert!(shown.contains("account") && !shown.contains("secret"));
+        assert!(clear_cache(&path).unwrap());
+        assert!(!clear_cache(&path).unwrap());
+    }
+
+    #[test]
+    fn failed_cache_writes_are_returned() {
+        let dir = tempfile::TempDir::new().unwrap();
+        assert!(CacheFile::default().save(&dir.path().join("missing").join("api_cache.json")).is_err());
+    }
+
+    #[test]
+    fn future_measurements_have_no_age() {
+        let now = DateTime::from_timestamp_millis(1723472400000 - 60_000).unwrap();
+        assert_eq!(measurement("Date(1723472400000)").age_at(now, TimestampSource::Wt), Some(TimeDelta::zero()));
+    }
+
+    #[test]
+    fn age_uses_the_selected_timestamp() {
+        let now = DateTime::from_timestamp_millis(1723472400000).unwrap();
+        let measurement = GlucoseMeasurement { st: "Date(1723472100000)".to_string(), ..measurement("Date(1723472400000)") };
+        assert_eq!(measurement.age_at(now, TimestampSource::Wt), Some(TimeDelta::zero()));
+        assert_eq!(measurement.age_at(now, TimestampSource::St), Some(TimeDelta::minutes(5)));
+    }
+}
@@ -0,0 +1,278 @@
+//
+// An interface to the official Dexcom developer API, which uses OAuth and is supported (unlike the share API)
+// The readings are delayed by the API (about 3 hours in the US and 1 hour elsewhere), so the status lags behind
+//
+
+use std::{path::PathBuf, time::{Duration, Instant}};
+use chrono::{NaiveDateTime, Utc};
+use serde::Deserialize;
+use anyhow::Result;
+use tracing::{debug, error, info, warn};
+use crate::{dexcom::GlucoseMeasurement, http::RequestExt};
+
+/// The default base URL of the API
+pub const DEFAULT_BASE_URL: &str = "https://api.dexcom.com";
+/// The path to exchange the refresh token for an access token
+pub(crate) const TOKEN_PATH: &str = "/v2/oauth2/token";
+/// The path to fetch the estimated glucose values
+pub(crate) const EGVS_PATH: &str = "/v3/users/self/egvs";
+/// How far back the readings are requested, which has to cover the delay of the API
+const LOOKBACK_HOURS: i64 = 24;
+/// The format of the timestamps in the requests and responses (e.g. `2024-08-12T14:20:00`)
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Options for connecting to the API
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The base URL of the API (e.g. `https://api.dexcom.com`)
+    pub base_url: String,
+    /// The redirect URI the app was registered with, which the token requests have to repeat
+    pub redirect_uri: String,
+    /// The file the latest refresh token is saved to, since every refresh replaces it. Nothing is saved if this is unset.
+    pub token_path: Option<PathBuf>,
+    /// The proxy the requests are sent through
+    pub proxy: Option<reqwest::Proxy>
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            redirect_uri: String::new(),
+            token_path: None,
+            proxy: None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Api {
+    /// The HTTP client
+    client: reqwest::Client,
+    /// The base URL of the API
+    base_url: String,
+    /// The ID of the developer app
+    client_id: String,
+    /// The secret of the developer app
+    client_secret: String,
+    /// The redirect URI of the developer app
+    redirect_uri: String,
+    /// The latest refresh token
+    refresh_token: String,
+    /// The file the latest refresh token is saved to
+    token_path: Option<PathBuf>,
+    /// The current access token and when it expires. This is `None` until we refresh, or after the token expires.
+    access: Option<(String, Instant)>
+}
+impl Api {
+    /// Create a new API instance
+    ///
+    /// - NOTE: If a refresh token was saved by a previous run, it's used instead of the one in the config (which was used up)
+    /// - NOTE: This doesn't refresh the access token until the first measurement is requested
+    pub fn new(client_id: &str, client_secret: &str, refresh_token: &str, options: Options) -> Result<Self> {
+
+        // Ensure the credentials are not empty
+        if client_id.is_empty() { Err(Error::ArgClientId)? };
+        if client_secret.is_empty() { Err(Error::ArgClientSecret)? };
+
+        let saved = options.token_path.as_ref()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty());
+        let refresh_token = saved.unwrap_or_else(|| refresh_token.to_string());
+        if refresh_token.is_empty() { Err(Error::ArgRefreshToken)? };
+
+        // Create the HTTP client
+        let mut builder = reqwest::ClientBuilder::default();
+        if let Some(proxy) = options.proxy {
+            builder = builder.proxy(proxy);
+        }
+
+        info!("Using the official Dexcom API, which delays the readings by about 3 hours (1 hour outside of the US)");
+
+        Ok(Self {
+            client: builder.build()?,
+            base_url: options.base_url.trim_end_matches('/').to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            redirect_uri: options.redirect_uri,
+            refresh_token,
+            token_path: options.token_path,
+            access: None
+        })
+    }
+
+    /// Returns the full URL of an API endpoint
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Exchanges the refresh token for a new access token, saving the new refresh token
+    async fn refresh(&mut self) -> Result<String> {
+        debug!("Refreshing the Dexcom OAuth access token...");
+
+        let response = self.client.post(self.url(TOKEN_PATH))
+        .form(&[
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+            ("refresh_token", self.refresh_token.as_str()),
+            ("grant_type", "refresh_token"),
+            ("redirect_uri", self.redirect_uri.as_str())
+        ])
+        .send_logged().await?;
+        let status = response.status();
+        let body = response.text().await?;
+
+        if status == reqwest::StatusCode::BAD_REQUEST || status == reqwest::StatusCode::UNAUTHORIZED {
+            error!("Failed to refresh the Dexcom OAuth access token: {body}");
+            Err(Error::InvalidGrant)?
+        }
+        let token: TokenResponse = serde_json::from_str(&body)
+        .map_err(|_| Error::Unknown(body))?;
+
+        // The old refresh token stops working, so the new one has to survive a restart
+        crate::redact::register(&token.access_token);
+        crate::redact::register(&token.refresh_token);
+        self.refresh_token = token.refresh_token;
+        if let Some(path) = &self.token_path {
+            if let Err(e) = std::fs::write(path, &self.refresh_token) {
+                warn!("Failed to save the Dexcom OAuth refresh token to {path:?}: {e:?}. The config's refresh token won't work after a restart");
+            }
+        }
+
+        // Refresh a minute early, so the token doesn't expire mid-request
+        let expires_at = Instant::now() + Duration::from_secs(token.expires_in.saturating_sub(60));
+        self.access = Some((token.access_token.clone(), expires_at));
+        Ok(token.access_token)
+    }
+
+    /// Queries the API for the latest glucose measurement
+    pub async fn get_latest_glucose(&mut self) -> Result<Option<GlucoseMeasurement>> {
+
+        // Refresh the access token if we don't have one that's still valid
+        let access_token = match &self.access {
+            Some((token, expires_at)) if Instant::now() < *expires_at => token.clone(),
+            _ => self.refresh().await?
+        };
+
+        let end = Utc::now().naive_utc();
+        let start = end - chrono::Duration::hours(LOOKBACK_HOURS);
+        let response = self.client.get(self.url(EGVS_PATH))
+        .query(&[("startDate", start.format(TIMESTAMP_FORMAT).to_string()), ("endDate", end.format(TIMESTAMP_FORMAT).to_string())])
+        .bearer_auth(access_token)
+        .send_logged().await?;
+
+        // The access token was revoked, so refresh it on the next request
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.access = None;
+            Err(Error::Unauthorized)?
+        }
+
+        let body = response.text().await?;
+        let egvs: EgvsResponse = serde_json::from_str(&body)
+        .map_err(|_| Error::Unknown(body))?;
+
+        // Use the newest record that has a value (the order of the records isn't documented)
+        let latest = egvs.records.into_iter()
+        .filter(|r| r.value.is_some())
+        .max_by(|a, b| a.system_time.cmp(&b.system_time));
+
+        match latest {
+            Some(record) => Ok(Some(record.into_glucose_measurement()?)),
+            None => Ok(None)
+        }
+    }
+}
+
+// API RESPONSES
+/// The token response
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    /// The token used to authorize the requests
+    access_token: String,
+    /// The token used to get the next access token (the one that was sent stops working)
+    refresh_token: String,
+    /// How long the access token is valid for (in seconds)
+    expires_in: u64
+}
+
+/// The estimated glucose values response
+#[derive(Debug, Deserialize)]
+struct EgvsResponse {
+    /// The readings in the requested time range
+    #[serde(default)]
+    records: Vec<Record>
+}
+
+/// A single reading in the estimated glucose values response
+#[derive(Debug, Deserialize)]
+struct Record {
+    /// The date and time of the reading in UTC
+    #[serde(rename = "systemTime")]
+    system_time: String,
+    /// The date and time of the reading shown on the receiver
+    #[serde(rename = "displayTime")]
+    display_time: String,
+    /// The glucose value (`None` if the sensor couldn't calculate one)
+    value: Option<u32>,
+    /// The trend of the glucose value (e.g. `fortyFiveUp`)
+    #[serde(default)]
+    trend: Option<String>
+}
+impl Record {
+    /// Converts the reading into the format used by the rest of the program
+    fn into_glucose_measurement(self) -> Result<GlucoseMeasurement> {
+        let date = |time: &str| -> Result<String> {
+            let millis = NaiveDateTime::parse_from_str(time, TIMESTAMP_FORMAT)?.and_utc().timestamp_millis();
+            Ok(format!("Date({millis})"))
+        };
+        let system_time = date(&self.system_time)?;
+
+        // The trends are the same as the share API's, but in camel case (e.g. `fortyFiveUp` is `FortyFiveUp`)
+        let trend = self.trend.as_deref().unwrap_or("notComputable");
+        let mut chars = trend.chars();
+        let trend = chars.next().map(|c| c.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default();
+
+        Ok(GlucoseMeasurement {
+            wt: system_time.clone(),
+            st: system_time,
+            dt: date(&self.display_time)?,
+            value: self.value.unwrap_or_default(),
+            trend,
+            raw_value: None
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("The refresh token was rejected (it may have expired or already been used)")]
+    InvalidGrant,
+    #[error("The access token was rejected")]
+    Unauthorized,
+    #[error("The client ID must not be empty")]
+    ArgClientId,
+    #[error("The client secret must not be empty")]
+    ArgClientSecret,
+    #[error("The refresh token must not be empty")]
+    ArgRefreshToken,
+    #[error("Encountered an unknown error: {0}")]
+    Unknown(String)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_use_the_share_api_format() {
+        let record = Record {
+            system_time: "2024-08-12T14:20:00".to_string(),
+            display_time: "2024-08-12T07:20:00".to_string(),
+            value: Some(112),
+            trend: Some("fortyFiveUp".to_string())
+        };
+        let measurement = record.into_glucose_measurement().unwrap();
+        assert_eq!(measurement.wt, "Date(1723472400000)");
+        assert_eq!(measurement.trend, "FortyFiveUp");
+    }
+}
@@ -8,50 +8,185 @@ use base64::engine::general_purpose::STANDARD;
 use anyhow::{Context, Result};
 use base64::Engine;
 use prost::Message;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tracing::{debug, error, trace};
-use crate::{preloaded_user_settings::{CustomStatus, StatusSettings}, PreloadedUserSettings};
+use crate::{http::{self, RequestExt}, preloaded_user_settings::{CustomStatus, StatusSettings}, PreloadedUserSettings};
 
-const PROTO_SETTINGS_URL: &str = "https://discord.com/api/v9/users/@me/settings-proto/1";
+/// The default base URL of the API
+pub const DEFAULT_BASE_URL: &str = "https://discord.com";
+/// The path to the protobuf user settings (of the default version)
+pub(crate) const PROTO_SETTINGS_PATH: &str = "/api/v9/users/@me/settings-proto/1";
+/// The version of the protobuf user settings that `PreloadedUserSettings` encodes
+pub const PROTO_SETTINGS_VERSION: u8 = 1;
+/// The path to the profile of the account
+pub(crate) const PROFILE_PATH: &str = "/api/v9/users/@me/profile";
+/// The longest custom status discord accepts (in characters)
+pub const MAX_STATUS_LENGTH: usize = 128;
+/// The default user agent, which is spoofed to reduce our chances of being detected by discord
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:129.0) Gecko/20100101 Firefox/129.0";
+
+/// Returns the path to a version of the protobuf user settings
+pub fn proto_settings_path(version: u8) -> String {
+    format!("/api/v9/users/@me/settings-proto/{version}")
+}
+
+/// Options for connecting to the API
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The base URL of the API (e.g. `https://discord.com`)
+    pub base_url: String,
+    /// The proxy the requests are sent through
+    pub proxy: Option<reqwest::Proxy>,
+    /// The user agent of the requests. If this is unset, no user agent is sent.
+    pub user_agent: Option<String>,
+    /// The version of the protobuf user settings endpoint (`settings-proto/{version}`)
+    pub settings_proto_version: u8,
+    /// Whether the requests are only sent over IPv4 (see [`http::ipv4_only`])
+    pub force_ipv4: bool
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            proxy: None,
+            user_agent: Some(DEFAULT_USER_AGENT.to_string()),
+            settings_proto_version: PROTO_SETTINGS_VERSION,
+            force_ipv4: false
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Api {
     /// The HTTP client
     client: reqwest::Client,
+    /// The base URL of the API
+    base_url: String,
     /// The token of the account
-    token: String
+    token: String,
+    /// The path to the protobuf user settings
+    settings_path: String
 }
 impl Api {
     /// Create a new API instance
-    pub async fn new(token: &str) -> Self {
+    pub async fn new(token: &str, options: Options) -> Self {
 
         // Create the HTTP client
-        // We spoof the user agent here to reduce our chances of being detected by discord
-        let client = reqwest::ClientBuilder::default()
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:129.0) Gecko/20100101 Firefox/129.0")
+        // The user agent is spoofed by default to reduce our chances of being detected by discord
+        let mut builder = http::ipv4_only(reqwest::ClientBuilder::default(), options.force_ipv4);
+        if let Some(user_agent) = options.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        if let Some(proxy) = options.proxy {
+            builder = builder.proxy(proxy);
+        }
+        let client = builder
         .build()
         .context("Failed to create HTTP client for the discord API").unwrap();
 
         Self {
             client,
-            token: token.to_string()
+            base_url: options.base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            settings_path: proto_settings_path(options.settings_proto_version)
+        }
+    }
+
+    /// Returns the full URL of an API endpoint
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Updates the status of the account with the provided string and optional emoji
+    pub async fn set_status(&self, text: &str, emoji: Option<&Emoji>) -> Result<()> {
+
+        // Get the ID and name of the emoji (an ID of 0 means it's a unicode emoji)
+        let (emoji_id, emoji_name) = match emoji {
+            Some(Emoji::Unicode(name)) => (0, name.clone()),
+            Some(Emoji::Custom { id, name }) => (*id, name.clone()),
+            None => (0, String::new())
+        };
+
+        let custom_status = CustomStatus {
+            text: text.to_string(),
+            emoji_id,
+            emoji_name,
+            expires_at_ms: 0, // This implies the status is permanent
+            created_at_ms: get_epoch_ms(), // It works without this, but hopefully this will help trick the API into thinking we are mere mortals
+        };
+
+        self.patch_custom_status(custom_status).await?;
+        trace!("Updated status to '{}' successfully", text);
+        Ok(())
+    }
+
+    /// Clears the custom status of the account
+    pub async fn clear_status(&self) -> Result<()> {
+        // An empty custom status replaces the current one
+        self.patch_custom_status(CustomStatus::default()).await?;
+        trace!("Cleared status successfully");
+        Ok(())
+    }
+
+    /// Updates the "About Me" bio of the account
+    pub async fn set_bio(&self, bio: &str) -> Result<()> {
+
+        // Send the request to the API
+        let response = self.client.patch(self.url(PROFILE_PATH))
+        .header("Authorization", &self.token)
+        .json(&json!({ "bio": bio }))
+        .send_logged().await?;
+
+        // The bio change was successful
+        if response.status().is_success() {
+            trace!("Updated bio to '{}' successfully", bio);
+            Ok(())
+        }
+        // The bio change failed
+        else {
+            let status = response.status();
+            let body = response.text().await?;
+            error!("Failed to update bio: {}", body);
+            Err(Error::from_response(status, body))?
         }
     }
 
-    /// Updates the status of the account with the provided string
-    pub async fn set_status(&self, text: &str) -> Result<()> {
+    /// Returns the current custom status text of the account (empty if there is no custom status)
+    pub async fn get_status(&self) -> Result<String> {
+
+        // Send the request to the API
+        let response = self.client.get(self.url(&self.settings_path))
+        .header("Authorization", &self.token)
+        .send_logged().await?;
+
+        // The request failed
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await?;
+            error!("Failed to get status: {}", body);
+            return Err(Error::from_response(status, body).into());
+        }
+
+        // Decode the base64 protobuf settings packet
+        let body: SettingsResponse = response.json().await?;
+        let packet = STANDARD.decode(body.settings)?;
+        let settings = PreloadedUserSettings::decode(packet.as_slice())?;
+
+        Ok(settings.status
+            .and_then(|s| s.custom_status)
+            .map(|c| c.text)
+            .unwrap_or_default())
+    }
+
+    /// Replaces the custom status of the account
+    async fn patch_custom_status(&self, custom_status: CustomStatus) -> Result<()> {
 
         // Create the status change packet
         let packet = PreloadedUserSettings {
             status: Some(StatusSettings {
                 status: None,
-                custom_status: Some(CustomStatus {
-                    text: text.to_string(),
-                    emoji_id: 0,
-                    emoji_name: String::new(),
-                    expires_at_ms: 0, // This implies the status is permanent
-                    created_at_ms: get_epoch_ms(), // It works without this, but hopefully this will help trick the API into thinking we are mere mortals
-                }),
+                custom_status: Some(custom_status),
                 show_current_game: None,
                 status_expires_at_ms: 0,
             }),
@@ -62,35 +197,106 @@ impl Api {
         let packet_b64 = STANDARD.encode(packet.encode_to_vec());
 
         // Send the request to the API
-        let response = self.client.patch(PROTO_SETTINGS_URL)
+        let response = self.client.patch(self.url(&self.settings_path))
         .header("Authorization", &self.token)
         .json(&json!({ "settings": packet_b64 }))
-        .send().await?;
+        .send_logged().await?;
 
         // The status change was successful
         if response.status().is_success() {
-            trace!("Updated status to '{}' successfully", text);
             Ok(())
         }
         // The status change failed
         else {
+            let status = response.status();
             let body = response.text().await?;
-            error!("Failed to update status to '{}': {}", text, body);
-            Err(Error::Unknown(body))?
+            error!("Failed to update status: {}", body);
+            Err(Error::from_response(status, body))?
         }
     }
 }
 
+/// The response of the protobuf user settings endpoint
+#[derive(Debug, Deserialize)]
+struct SettingsResponse {
+    /// The base64 encoded protobuf settings packet
+    settings: String
+}
+
+/// The kinds of discord accounts the status can be set on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// A user account, updated through the user settings API
+    User,
+    /// A bot account, updated through the gateway
+    Bot
+}
+
+/// Where on the profile the status is shown
+/// 
+/// - NOTE: This only applies to the bot backend. User accounts always use the custom status, since activities can only be set
+///   over a gateway connection (the user settings have no activity field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusTarget {
+    /// The custom status line
+    CustomStatus,
+    /// An activity (e.g. "Playing ..."), of the kind in `activity_kind`
+    Activity
+}
+
+/// An emoji shown next to the custom status
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Emoji {
+    /// A unicode emoji (e.g. `"🟢"`)
+    Unicode(String),
+    /// A custom emoji from a server
+    Custom {
+        /// The ID of the emoji
+        id: u64,
+        /// The name of the emoji
+        name: String
+    }
+}
+
+/// The body of a captcha challenge response
+#[derive(Debug, Deserialize)]
+struct CaptchaResponse {
+    /// The reasons the captcha is required (e.g. `["captcha-required"]`)
+    captcha_key: Vec<String>
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("Discord wants the account to be verified with a captcha")]
+    VerificationRequired,
+    #[error("Discord is rate limiting the requests")]
+    RateLimited,
     #[error("Received an unknown error: {0}")]
     Unknown(String)
 }
+impl Error {
+    /// Converts an unsuccessful response into an error
+    fn from_response(status: reqwest::StatusCode, body: String) -> Self {
+        // Discord sometimes challenges automated requests with a captcha instead of a normal error
+        if status == reqwest::StatusCode::FORBIDDEN && serde_json::from_str::<CaptchaResponse>(&body).is_ok() {
+            return Self::VerificationRequired;
+        }
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Self::RateLimited;
+        }
+        Self::Unknown(body)
+    }
+}
 
 /// Returns the current unix epoch in milliseconds
+/// 
+/// - NOTE: This returns 0 if the system clock is before 1970 (e.g. on devices without an RTC that haven't synced yet)
 fn get_epoch_ms() -> u64 {
     SystemTime::now()
     .duration_since(UNIX_EPOCH)
-    .unwrap()
-    .as_millis() as u64
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or(0)
 }
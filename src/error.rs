@@ -0,0 +1,230 @@
+//
+// The errors of the whole program, so decisions about them (retry, renew, or stop) are made in one place
+//
+
+use std::{fmt, io};
+use crate::{dexcom, dexcom_oauth, discord, librelinkup};
+
+/// An error from any part of the program
+///
+/// - NOTE: Converting from `anyhow::Error` finds the underlying error, so the modules can keep returning `anyhow::Result`
+#[derive(thiserror::Error)]
+pub enum AppError {
+    #[error(transparent)]
+    Dexcom(#[from] dexcom::Error),
+    #[error(transparent)]
+    LibreLinkUp(#[from] librelinkup::Error),
+    #[error(transparent)]
+    DexcomOauth(#[from] dexcom_oauth::Error),
+    #[error(transparent)]
+    Discord(#[from] discord::Error),
+    /// The config file couldn't be loaded, or it isn't usable
+    #[error("{0}")]
+    Config(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Anything else (e.g. network errors)
+    #[error(transparent)]
+    Other(anyhow::Error)
+}
+impl From<anyhow::Error> for AppError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<AppError>() {
+            Ok(e) => return e,
+            Err(e) => e
+        };
+        let e = match e.downcast::<dexcom::Error>() {
+            Ok(e) => return Self::Dexcom(e),
+            Err(e) => e
+        };
+        let e = match e.downcast::<librelinkup::Error>() {
+            Ok(e) => return Self::LibreLinkUp(e),
+            Err(e) => e
+        };
+        let e = match e.downcast::<dexcom_oauth::Error>() {
+            Ok(e) => return Self::DexcomOauth(e),
+            Err(e) => e
+        };
+        let e = match e.downcast::<discord::Error>() {
+            Ok(e) => return Self::Discord(e),
+            Err(e) => e
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => Self::Io(e),
+            Err(e) => Self::Other(e)
+        }
+    }
+}
+// Show the underlying error, so logs look the same as before it was wrapped
+impl fmt::Debug for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dexcom(e) => e.fmt(f),
+            Self::LibreLinkUp(e) => e.fmt(f),
+            Self::DexcomOauth(e) => e.fmt(f),
+            Self::Discord(e) => e.fmt(f),
+            Self::Config(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+            Self::Other(e) => e.fmt(f)
+        }
+    }
+}
+
+/// The broad kind of failure behind an error, which the cgm error status can show (see [`crate::config::Config::error_status`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The credentials or session were rejected
+    Auth,
+    /// The source couldn't be reached (e.g. no network, or a timeout)
+    Offline,
+    /// The source responded, but not with any readings we could use
+    NoData,
+    /// Anything else
+    Other
+}
+impl ErrorKind {
+    /// Returns the short label of the kind (e.g. `"auth error"`)
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Auth => "auth error",
+            Self::Offline => "offline",
+            Self::NoData => "no data",
+            Self::Other => "error"
+        }
+    }
+}
+
+/// What the loop should do about an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Renew the dexcom session and retry right away
+    RenewSession,
+    /// Try again on the next iteration
+    Retry,
+    /// Stop, since retrying won't help until someone fixes something by hand
+    Halt
+}
+
+impl AppError {
+    /// Returns what the loop should do about the error
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Self::Dexcom(dexcom::Error::SessionInvalid) => Recovery::RenewSession,
+            Self::Discord(discord::Error::VerificationRequired) | Self::Config(_) => Recovery::Halt,
+            _ => Recovery::Retry
+        }
+    }
+
+    /// Returns true if discord rejected the request because of its rate limits
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self, Self::Discord(discord::Error::RateLimited))
+    }
+
+    /// Returns the broad kind of failure behind the error
+    /// 
+    /// - NOTE: Unexpected responses and missing credentials are `Other`, since they don't mean the login or session was rejected
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Dexcom(dexcom::Error::InvalidPassword | dexcom::Error::MaxAuthenticationAttemptsReached | dexcom::Error::SessionNotFound | dexcom::Error::SessionInvalid)
+            | Self::LibreLinkUp(librelinkup::Error::InvalidCredentials | librelinkup::Error::TermsNotAccepted | librelinkup::Error::Unauthorized)
+            | Self::DexcomOauth(dexcom_oauth::Error::InvalidGrant | dexcom_oauth::Error::Unauthorized) => ErrorKind::Auth,
+            Self::Other(e) => match e.downcast_ref::<reqwest::Error>() {
+                Some(e) if e.is_connect() || e.is_timeout() || e.is_request() => ErrorKind::Offline,
+                _ => ErrorKind::Other
+            },
+            _ => ErrorKind::Other
+        }
+    }
+
+    /// Returns what the user can do about the error, if it's one we know how to fix
+    ///
+    /// - NOTE: The error messages say what went wrong, while this says how to fix it
+    pub fn guidance(&self) -> Option<&'static str> {
+        match self {
+            Self::Dexcom(e) => match e {
+                dexcom::Error::InvalidPassword => Some(
+                    "Check your dexcom username and password in the config. Use the credentials of the account wearing the sensor (not a follower), \
+                    and note that Share must be enabled in the dexcom app with at least one follower added, or the login is rejected."
+                ),
+                dexcom::Error::MaxAuthenticationAttemptsReached => Some(
+                    "Dexcom locked the account after too many failed logins. Wait about 10 minutes, double check the password, and try again."
+                ),
+                dexcom::Error::ArgUsername | dexcom::Error::ArgPassword => Some(
+                    "Set dexcom_username and dexcom_password in the config (or run with --setup)."
+                ),
+                dexcom::Error::SessionNotFound | dexcom::Error::SessionInvalid | dexcom::Error::MaxRetriesReached => Some(
+                    "Dexcom keeps rejecting the session. Make sure Share is enabled in the dexcom app with at least one follower, \
+                    and run `dexcord cache clear` if it keeps happening."
+                ),
+                dexcom::Error::Unknown(_) => Some(
+                    "Dexcom returned an unexpected response. If your account was created outside of the US, set dexcom_base_url to https://shareous1.dexcom.com."
+                )
+            },
+            Self::LibreLinkUp(e) => match e {
+                librelinkup::Error::InvalidCredentials => Some("Check your LibreLinkUp email and password in the config."),
+                librelinkup::Error::TermsNotAccepted => Some("Log in to the LibreLinkUp app and accept the terms of use, then restart the program."),
+                librelinkup::Error::ArgEmail | librelinkup::Error::ArgPassword => Some("Set librelinkup_email and librelinkup_password in the config."),
+                _ => None
+            },
+            Self::DexcomOauth(e) => match e {
+                dexcom_oauth::Error::InvalidGrant => Some(
+                    "Authorize the developer app again to get a new refresh token, set dexcom_oauth_refresh_token to it, \
+                    and delete the saved dexcom_oauth_token file next to the API cache."
+                ),
+                dexcom_oauth::Error::ArgClientId | dexcom_oauth::Error::ArgClientSecret | dexcom_oauth::Error::ArgRefreshToken => Some(
+                    "Set dexcom_oauth_client_id, dexcom_oauth_client_secret, and dexcom_oauth_refresh_token in the config."
+                ),
+                _ => None
+            },
+            Self::Discord(discord::Error::VerificationRequired) => Some(
+                "Log in to discord in a browser, solve the captcha, and then restart the program."
+            ),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_underlying_error() {
+        let e = AppError::from(anyhow::Error::new(dexcom::Error::SessionInvalid).context("Failed to fetch"));
+        assert!(matches!(e, AppError::Dexcom(dexcom::Error::SessionInvalid)));
+        assert_eq!(e.recovery(), Recovery::RenewSession);
+
+        let e = AppError::from(anyhow::Error::new(AppError::from(discord::Error::VerificationRequired)));
+        assert_eq!(e.recovery(), Recovery::Halt);
+
+        let e = AppError::from(anyhow::anyhow!("connection refused"));
+        assert!(matches!(e, AppError::Other(_)));
+        assert_eq!(e.recovery(), Recovery::Retry);
+        assert_eq!(e.kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn only_rejected_logins_are_auth_errors() {
+        assert_eq!(AppError::from(dexcom::Error::InvalidPassword).kind(), ErrorKind::Auth);
+        assert_eq!(AppError::from(librelinkup::Error::Unauthorized).kind(), ErrorKind::Auth);
+        assert_eq!(AppError::from(dexcom_oauth::Error::InvalidGrant).kind(), ErrorKind::Auth);
+
+        assert_eq!(AppError::from(dexcom::Error::MaxRetriesReached).kind(), ErrorKind::Other);
+        assert_eq!(AppError::from(dexcom::Error::ArgPassword).kind(), ErrorKind::Other);
+        assert_eq!(AppError::from(librelinkup::Error::ArgEmail).kind(), ErrorKind::Other);
+        assert_eq!(AppError::from(dexcom::Error::Unknown("<html>".to_string())).kind(), ErrorKind::Other);
+        assert_eq!(AppError::from(dexcom_oauth::Error::Unknown("{}".to_string())).kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn finds_rate_limits_behind_other_errors() {
+        let e = AppError::from(anyhow::Error::new(AppError::from(discord::Error::RateLimited)));
+        assert!(e.is_rate_limited());
+        assert_eq!(e.recovery(), Recovery::Retry);
+
+        let e = AppError::from(anyhow::Error::new(discord::Error::RateLimited).context("Failed to update the status"));
+        assert!(e.is_rate_limited());
+
+        assert!(!AppError::from(discord::Error::Unknown("{}".to_string())).is_rate_limited());
+    }
+}
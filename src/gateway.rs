@@ -0,0 +1,407 @@
+//
+// A minimal client for the Discord bot gateway, used to set the presence of bot accounts
+// Bots can't use the user settings API, so their statuses have to be sent over the gateway websocket
+// It also answers the `/glucose` slash command, since interactions arrive over the gateway too
+//
+
+use std::{sync::Arc, time::Duration};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, trace, warn};
+use crate::{discord, http::{self, RequestExt}};
+
+/// The default URL of the gateway
+pub const DEFAULT_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+/// How long to wait before reconnecting after the connection drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// GATEWAY OPCODES
+const OP_DISPATCH: u8 = 0;
+const OP_HEARTBEAT: u8 = 1;
+const OP_IDENTIFY: u8 = 2;
+const OP_PRESENCE_UPDATE: u8 = 3;
+const OP_RECONNECT: u8 = 7;
+const OP_INVALID_SESSION: u8 = 9;
+const OP_HELLO: u8 = 10;
+const OP_HEARTBEAT_ACK: u8 = 11;
+
+/// The name of the slash command that replies with the current glucose
+const GLUCOSE_COMMAND: &str = "glucose";
+/// The interaction type of slash commands
+const INTERACTION_APPLICATION_COMMAND: u64 = 2;
+/// The interaction callback type of a plain message reply
+const CALLBACK_CHANNEL_MESSAGE: u8 = 4;
+
+/// A handle to the gateway connection, which runs in the background and reconnects automatically
+#[derive(Debug)]
+pub struct Gateway {
+    /// The channel used to send presence updates to the connection task (`None` clears the activity)
+    presences: mpsc::UnboundedSender<Option<Activity>>
+}
+impl Gateway {
+    /// Connects to the gateway in the background, answering the slash commands if they're provided
+    pub fn connect(token: &str, url: &str, commands: Option<Commands>) -> Self {
+        let (presences, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(token.to_string(), url.to_string(), rx, commands));
+
+        Self { presences }
+    }
+
+    /// Updates the presence of the bot with the provided activity
+    ///
+    /// - NOTE: If the gateway is currently disconnected, the activity is sent once it reconnects
+    pub fn set_activity(&self, activity: Activity) -> Result<()> {
+        self.presences.send(Some(activity)).map_err(|_| Error::Closed(None))?;
+        Ok(())
+    }
+
+    /// Clears the activity of the bot
+    pub fn clear_activity(&self) -> Result<()> {
+        self.presences.send(None).map_err(|_| Error::Closed(None))?;
+        Ok(())
+    }
+}
+
+/// Answers the `/glucose` slash command with the latest reading
+#[derive(Clone)]
+pub struct Commands {
+    /// The HTTP client used to register the command and reply to it
+    client: reqwest::Client,
+    /// The base URL of the API (e.g. `https://discord.com`)
+    base_url: String,
+    /// The token of the bot
+    token: String,
+    /// Returns the text of the reply
+    reply: Arc<dyn Fn() -> String + Send + Sync>
+}
+impl Commands {
+    /// Creates the commands, sending their requests through the proxy of the options
+    /// 
+    /// - NOTE: The user agent of the options is ignored, since it's only spoofed for user accounts
+    pub fn new(token: &str, options: discord::Options, reply: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        let mut builder = http::ipv4_only(reqwest::ClientBuilder::default(), options.force_ipv4);
+        if let Some(proxy) = options.proxy {
+            builder = builder.proxy(proxy);
+        }
+        let client = builder
+        .build()
+        .context("Failed to create HTTP client for the discord commands").unwrap();
+
+        Self {
+            client,
+            base_url: options.base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            reply: Arc::new(reply)
+        }
+    }
+
+    /// Handles a gateway event, registering the command once we're ready and replying to it when it's used
+    ///
+    /// - NOTE: The requests are sent in the background, so they don't hold up the heartbeats
+    fn handle(&self, event: &str, data: &Value) {
+        match event {
+            "READY" => if let Some(application_id) = data["application"]["id"].as_str() {
+                let commands = self.clone();
+                let application_id = application_id.to_string();
+                tokio::spawn(async move {
+                    match commands.register(&application_id).await {
+                        Ok(()) => info!("Registered the /{GLUCOSE_COMMAND} slash command"),
+                        Err(e) => warn!("Failed to register the /{GLUCOSE_COMMAND} slash command: {e:?}")
+                    }
+                });
+            },
+            "INTERACTION_CREATE" if is_glucose_command(data) => {
+                let (Some(id), Some(token)) = (data["id"].as_str(), data["token"].as_str()) else {
+                    return;
+                };
+                let commands = self.clone();
+                let (id, token) = (id.to_string(), token.to_string());
+                tokio::spawn(async move {
+                    if let Err(e) = commands.respond(&id, &token).await {
+                        warn!("Failed to reply to the /{GLUCOSE_COMMAND} slash command: {e:?}");
+                    }
+                });
+            },
+            _ => {}
+        }
+    }
+
+    /// Registers the slash command for the application
+    ///
+    /// - NOTE: Registering a command that already exists just updates it
+    async fn register(&self, application_id: &str) -> Result<()> {
+        self.client.post(format!("{}/api/v10/applications/{application_id}/commands", self.base_url))
+        .header("Authorization", format!("Bot {}", self.token))
+        .json(&json!({
+            "name": GLUCOSE_COMMAND,
+            "description": "Shows the current glucose reading",
+            "type": 1
+        }))
+        .send_logged().await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Replies to an interaction with the latest reading
+    async fn respond(&self, id: &str, token: &str) -> Result<()> {
+        self.client.post(format!("{}/api/v10/interactions/{id}/{token}/callback", self.base_url))
+        .json(&json!({
+            "type": CALLBACK_CHANNEL_MESSAGE,
+            "data": { "content": (self.reply)() }
+        }))
+        .send_logged().await?
+        .error_for_status()?;
+        Ok(())
+    }
+}
+impl std::fmt::Debug for Commands {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Commands").field("base_url", &self.base_url).finish_non_exhaustive()
+    }
+}
+
+/// Returns true if the interaction is a use of the `/glucose` slash command
+fn is_glucose_command(interaction: &Value) -> bool {
+    interaction["type"].as_u64() == Some(INTERACTION_APPLICATION_COMMAND) && interaction["data"]["name"] == GLUCOSE_COMMAND
+}
+
+/// The kinds of activities a bot can show
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityType {
+    /// "Playing {name}"
+    Playing,
+    /// "Listening to {name}"
+    Listening,
+    /// "Watching {name}"
+    Watching,
+    /// "Competing in {name}"
+    Competing,
+    /// A custom status, showing just the text
+    Custom
+}
+
+/// An activity shown on the profile of the bot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Activity {
+    /// The kind of activity
+    pub kind: ActivityType,
+    /// The text of the activity
+    pub text: String
+}
+impl Activity {
+    /// Returns the activity object of the presence update payload
+    fn to_json(&self) -> Value {
+        match self.kind {
+            ActivityType::Playing => json!({ "name": self.text, "type": 0 }),
+            ActivityType::Listening => json!({ "name": self.text, "type": 2 }),
+            ActivityType::Watching => json!({ "name": self.text, "type": 3 }),
+            ActivityType::Competing => json!({ "name": self.text, "type": 5 }),
+            // Custom statuses need a name, but only the state is shown
+            ActivityType::Custom => json!({ "name": "Custom Status", "type": 4, "state": self.text })
+        }
+    }
+}
+
+/// Returns the presence object sent in the identify and presence update payloads
+fn presence_json(activity: Option<&Activity>) -> Value {
+    json!({
+        "since": null,
+        "activities": activity.map(|a| vec![a.to_json()]).unwrap_or_default(),
+        "status": "online",
+        "afk": false
+    })
+}
+
+/// A message received from the gateway
+#[derive(Debug, Deserialize)]
+struct Payload {
+    /// The opcode of the message
+    op: u8,
+    /// The data of the message
+    #[serde(default)]
+    d: Value,
+    /// The sequence number (only for dispatches)
+    s: Option<u64>,
+    /// The event name (only for dispatches)
+    t: Option<String>
+}
+
+/// Keeps a gateway connection alive, reconnecting whenever it drops
+async fn run(token: String, url: String, mut presences: mpsc::UnboundedReceiver<Option<Activity>>, commands: Option<Commands>) {
+    // The latest activity, which is restored whenever we reconnect
+    let mut activity = None;
+
+    loop {
+        match session(&token, &url, &mut presences, &mut activity, commands.as_ref()).await {
+            Ok(true) => debug!("The discord gateway asked us to reconnect"),
+            // The handle was dropped, so there's nothing left to do
+            Ok(false) => return,
+            Err(e) => warn!("The discord gateway connection failed: {e:?}")
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Runs a single gateway session
+///
+/// - NOTE: This returns `Ok(true)` if we should reconnect, and `Ok(false)` if the handle was dropped
+async fn session(
+    token: &str,
+    url: &str,
+    presences: &mut mpsc::UnboundedReceiver<Option<Activity>>,
+    activity: &mut Option<Activity>,
+    commands: Option<&Commands>
+) -> Result<bool> {
+    debug!("Connecting to the discord gateway...");
+    let (ws, _) = tokio_tungstenite::connect_async(url).await?;
+    let (mut write, mut read) = ws.split();
+
+    // The first message is always the hello, which tells us how often to heartbeat
+    let hello = read_payload(&mut read).await?;
+    if hello.op != OP_HELLO {
+        Err(Error::UnexpectedPayload(hello.op))?
+    }
+    let interval = hello.d["heartbeat_interval"].as_u64().unwrap_or(41250);
+    let mut heartbeat = tokio::time::interval(Duration::from_millis(interval));
+
+    // Identify ourselves, restoring the latest activity
+    write.send(Message::text(json!({
+        "op": OP_IDENTIFY,
+        "d": {
+            "token": token,
+            "intents": 0,
+            "properties": { "os": std::env::consts::OS, "browser": "dexcord", "device": "dexcord" },
+            "presence": presence_json(activity.as_ref())
+        }
+    }).to_string())).await?;
+
+    // The last sequence number, which is sent with heartbeats
+    let mut sequence: Option<u64> = None;
+
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                trace!("Sending gateway heartbeat");
+                write.send(Message::text(json!({ "op": OP_HEARTBEAT, "d": sequence }).to_string())).await?;
+            },
+            new_activity = presences.recv() => {
+                let Some(new_activity) = new_activity else {
+                    return Ok(false);
+                };
+                write.send(Message::text(json!({
+                    "op": OP_PRESENCE_UPDATE,
+                    "d": presence_json(new_activity.as_ref())
+                }).to_string())).await?;
+                trace!("Updated bot activity to {new_activity:?}");
+                *activity = new_activity;
+            },
+            payload = read_payload(&mut read) => {
+                let payload = payload?;
+                match payload.op {
+                    OP_DISPATCH => {
+                        sequence = payload.s.or(sequence);
+                        if let Some(event) = payload.t {
+                            trace!("Received gateway event {event}");
+                            if let Some(commands) = commands {
+                                commands.handle(&event, &payload.d);
+                            }
+                        }
+                    },
+                    OP_HEARTBEAT => {
+                        write.send(Message::text(json!({ "op": OP_HEARTBEAT, "d": sequence }).to_string())).await?;
+                    },
+                    OP_RECONNECT => return Ok(true),
+                    OP_INVALID_SESSION => Err(Error::InvalidSession)?,
+                    OP_HEARTBEAT_ACK => {},
+                    op => trace!("Ignoring gateway payload with opcode {op}")
+                }
+            }
+        }
+    }
+}
+
+/// Reads the next JSON payload from the gateway, skipping non-text messages
+async fn read_payload<S>(read: &mut S) -> Result<Payload>
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin
+{
+    loop {
+        match read.next().await {
+            Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text)?),
+            Some(Ok(Message::Close(frame))) => Err(Error::Closed(frame.map(|f| f.reason.to_string())))?,
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => Err(e)?,
+            None => Err(Error::Closed(None))?
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("The gateway connection was closed: {0:?}")]
+    Closed(Option<String>),
+    #[error("The gateway invalidated the session (is the bot token correct?)")]
+    InvalidSession,
+    #[error("Received an unexpected payload with opcode {0}")]
+    UnexpectedPayload(u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_activity_uses_state() {
+        let activity = Activity { kind: ActivityType::Custom, text: "We chillin (112 mg/dL)".to_string() };
+        assert_eq!(presence_json(Some(&activity))["activities"], json!([
+            { "name": "Custom Status", "type": 4, "state": "We chillin (112 mg/dL)" }
+        ]));
+    }
+
+    #[test]
+    fn playing_activity_uses_name() {
+        let activity = Activity { kind: ActivityType::Playing, text: "We chillin (112 mg/dL)".to_string() };
+        assert_eq!(presence_json(Some(&activity))["activities"], json!([
+            { "name": "We chillin (112 mg/dL)", "type": 0 }
+        ]));
+    }
+
+    #[test]
+    fn listening_activity_uses_its_type() {
+        let activity = Activity { kind: ActivityType::Listening, text: "We chillin (112 mg/dL)".to_string() };
+        assert_eq!(presence_json(Some(&activity))["activities"], json!([
+            { "name": "We chillin (112 mg/dL)", "type": 2 }
+        ]));
+    }
+
+    #[test]
+    fn only_the_glucose_command_is_answered() {
+        assert!(is_glucose_command(&json!({ "type": 2, "data": { "name": "glucose" } })));
+        assert!(!is_glucose_command(&json!({ "type": 2, "data": { "name": "other" } })));
+        // Autocomplete and component interactions have other types
+        assert!(!is_glucose_command(&json!({ "type": 3, "data": { "name": "glucose" } })));
+    }
+
+    #[tokio::test]
+    async fn command_is_answered_with_the_reply() {
+        use wiremock::{matchers::{body_json, method, path}, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+        .and(path("/api/v10/interactions/123/abc/callback"))
+        .and(body_json(json!({ "type": 4, "data": { "content": "112 mg/dL →" } })))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server).await;
+
+        let options = discord::Options { base_url: server.uri(), ..Default::default() };
+        let commands = Commands::new("token", options, || "112 mg/dL →".to_string());
+        commands.respond("123", "abc").await.unwrap();
+    }
+}
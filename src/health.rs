@@ -0,0 +1,89 @@
+//
+// Tracks whether each part of the program is working, so the health endpoint reflects every output and not just the fetches
+//
+
+use std::collections::BTreeMap;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// The parts of the program whose health is tracked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    /// Fetching glucose from the sources
+    Sources,
+    /// Updating the discord status
+    Discord,
+    /// Uploading readings to nightscout
+    Nightscout,
+    /// Publishing readings to MQTT
+    Mqtt,
+    /// Sending alerts to the webhook
+    Webhook
+}
+
+/// The health of a single subsystem
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SubsystemHealth {
+    /// When the subsystem last succeeded
+    pub last_success: Option<DateTime<Utc>>,
+    /// When the subsystem last failed
+    pub last_error_at: Option<DateTime<Utc>>,
+    /// The last error of the subsystem
+    pub last_error: Option<String>
+}
+impl SubsystemHealth {
+    /// Returns true unless the subsystem failed more recently than it succeeded
+    pub fn is_healthy(&self) -> bool {
+        match (self.last_success, self.last_error_at) {
+            (_, None) => true,
+            (None, Some(_)) => false,
+            (Some(success), Some(error)) => success >= error
+        }
+    }
+}
+
+/// The health of every subsystem that has been used
+/// 
+/// - NOTE: Subsystems that haven't been used yet (e.g. because they aren't configured) don't show up
+#[derive(Debug, Clone, Default)]
+pub struct HealthState {
+    /// The health of each subsystem
+    pub subsystems: BTreeMap<Subsystem, SubsystemHealth>
+}
+impl HealthState {
+    /// Records the result of using a subsystem
+    pub fn record<T, E: std::fmt::Display>(&mut self, subsystem: Subsystem, result: &Result<T, E>) {
+        let health = self.subsystems.entry(subsystem).or_default();
+        let now = Utc::now();
+
+        match result {
+            Ok(_) => health.last_success = Some(now),
+            Err(e) => {
+                health.last_error_at = Some(now);
+                health.last_error = Some(format!("{e:#}"));
+            }
+        }
+    }
+
+    /// Returns true if every subsystem is healthy
+    pub fn is_healthy(&self) -> bool {
+        self.subsystems.values().all(SubsystemHealth::is_healthy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_after_a_success() {
+        let mut health = HealthState::default();
+        health.record::<(), _>(Subsystem::Discord, &Err("The token is invalid"));
+        assert!(!health.is_healthy());
+        assert_eq!(health.subsystems[&Subsystem::Discord].last_error.as_deref(), Some("The token is invalid"));
+
+        health.record::<_, String>(Subsystem::Discord, &Ok(()));
+        assert!(health.is_healthy());
+    }
+}
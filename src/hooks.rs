@@ -0,0 +1,57 @@
+//
+// Runs user configured shell commands when something happens (e.g. a new reading arrives)
+// These run arbitrary commands from the config, so they are opt-in
+//
+
+use tokio::{process::Command, task::JoinHandle};
+use tracing::{trace, warn};
+
+/// Runs a shell command in the background with the provided environment variables
+/// 
+/// - NOTE: Failures are only logged, since a broken command shouldn't affect the loop
+pub fn run(command: &str, vars: Vec<(&'static str, String)>) -> JoinHandle<()> {
+    let command = command.to_string();
+
+    tokio::spawn(async move {
+        let mut process = shell(&command);
+        process.envs(vars).kill_on_drop(true);
+
+        match process.output().await {
+            Ok(output) if output.status.success() => trace!("The command '{command}' succeeded"),
+            Ok(output) => warn!(
+                "The command '{command}' failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => warn!("Failed to run the command '{command}': {e:?}")
+        }
+    })
+}
+
+/// Returns a command that runs the provided string in the system shell
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.args(["/C", command]);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.args(["-c", command]);
+        c
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn passes_variables_to_the_command() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("out");
+
+        run(&format!("echo \"$DEXCORD_VALUE\" > {}", out.display()), vec![("DEXCORD_VALUE", "112".to_string())]).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(out).unwrap().trim(), "112");
+    }
+}
@@ -0,0 +1,104 @@
+//
+// Helpers shared by the HTTP clients
+//
+
+use std::{error::Error, io, net::{IpAddr, Ipv4Addr}, time::{Duration, Instant}};
+use rand::Rng;
+use reqwest::{ClientBuilder, RequestBuilder, Response};
+use tracing::{debug, warn};
+
+/// Extensions for sending requests
+pub trait RequestExt {
+    /// Sends the request, logging its method, path, status code, and latency at debug level
+    /// 
+    /// - NOTE: Only the path is logged, since the query and headers can contain secrets
+    /// - NOTE: If the connection was reset, the request is retried once on a new connection
+    async fn send_logged(self) -> reqwest::Result<Response>;
+}
+impl RequestExt for RequestBuilder {
+    async fn send_logged(self) -> reqwest::Result<Response> {
+        let (client, request) = self.build_split();
+        let request = request?;
+        let method = request.method().clone();
+        let path = request.url().path().to_string();
+        // Keep a copy in case it has to be retried (streamed bodies can't be copied, so those aren't retried)
+        let retry = request.try_clone();
+
+        let start = Instant::now();
+        let mut result = client.execute(request).await;
+
+        // The pool drops connections that were reset, so the retry gets a new one
+        if let (Err(e), Some(retry)) = (&result, retry) {
+            if is_connection_reset(e) {
+                warn!("The connection was reset during {method} {path}. Retrying on a new connection...");
+                result = client.execute(retry).await;
+            }
+        }
+        let elapsed = start.elapsed();
+
+        match &result {
+            Ok(response) => debug!("{method} {path} -> {} in {elapsed:?}", response.status()),
+            Err(e) => debug!("{method} {path} failed after {elapsed:?}: {e}")
+        }
+
+        result
+    }
+}
+
+/// Binds the client to the IPv4 wildcard address if enabled, so it only connects over IPv4
+/// 
+/// - NOTE: This works around networks where IPv6 is advertised but broken, which otherwise makes requests hang
+pub fn ipv4_only(builder: ClientBuilder, enabled: bool) -> ClientBuilder {
+    match enabled {
+        true => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        false => builder
+    }
+}
+
+/// Returns a random delay between zero and the provided delay (i.e. "full jitter"), so clients that fail together don't retry together
+/// 
+/// - NOTE: The delay is returned as is if jitter is disabled
+pub fn jitter(delay: Duration, enabled: bool) -> Duration {
+    match enabled {
+        true => rand::thread_rng().gen_range(Duration::ZERO..=delay),
+        false => delay
+    }
+}
+
+/// Returns true if the error was caused by the other end resetting or closing the connection
+fn is_connection_reset(e: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<io::Error>() {
+            if matches!(io.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe) {
+                return true;
+            }
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An error wrapping another one, like reqwest and hyper do
+    #[derive(Debug, thiserror::Error)]
+    #[error("error sending request")]
+    struct Wrapper(#[source] io::Error);
+
+    #[test]
+    fn finds_resets_in_the_source_chain() {
+        assert!(is_connection_reset(&Wrapper(io::Error::from(io::ErrorKind::ConnectionReset))));
+        assert!(is_connection_reset(&Wrapper(io::Error::from(io::ErrorKind::BrokenPipe))));
+        assert!(!is_connection_reset(&Wrapper(io::Error::from(io::ErrorKind::TimedOut))));
+    }
+
+    #[test]
+    fn jitter_stays_within_the_delay() {
+        let delay = Duration::from_secs(10);
+        assert!((0..100).all(|_| jitter(delay, true) <= delay));
+        assert_eq!(jitter(delay, false), delay);
+    }
+}
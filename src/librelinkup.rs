@@ -0,0 +1,346 @@
+//
+// An interface to the (undocumented) LibreLinkUp API, used by Abbott's FreeStyle Libre sensors
+//
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use anyhow::Result;
+use tracing::{debug, error};
+use crate::{dexcom::GlucoseMeasurement, http::RequestExt};
+
+/// The default base URL of the API
+pub const DEFAULT_BASE_URL: &str = "https://api.libreview.io";
+/// The path to log in
+pub(crate) const LOGIN_PATH: &str = "/llu/auth/login";
+/// The path to fetch the connections (i.e. the followed patients and their latest measurement)
+pub(crate) const CONNECTIONS_PATH: &str = "/llu/connections";
+/// The product that is reported to the API
+const PRODUCT: &str = "llu.android";
+/// The app version that is reported to the API (older versions are rejected)
+const VERSION: &str = "4.12.0";
+/// How many region redirects a login follows, so a misbehaving API can't bounce the credentials around forever
+const MAX_REDIRECTS: u32 = 2;
+/// The format of the timestamps in the API responses (e.g. `8/12/2024 2:20:00 PM`)
+const TIMESTAMP_FORMAT: &str = "%m/%d/%Y %I:%M:%S %p";
+
+/// Options for connecting to the API
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The base URL of the API (e.g. `https://api.libreview.io`)
+    pub base_url: String,
+    /// The proxy the requests are sent through
+    pub proxy: Option<reqwest::Proxy>
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            proxy: None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Api {
+    /// The HTTP client
+    client: reqwest::Client,
+    /// The base URL of the API (this changes if the API redirects us to another region)
+    base_url: String,
+    /// The email of the account
+    email: String,
+    /// The password of the account
+    password: String,
+    /// The current login. This is `None` until we log in, or after the login expires.
+    auth: Option<Auth>
+}
+impl Api {
+    /// Create a new API instance
+    ///
+    /// - NOTE: This doesn't log in until the first measurement is requested
+    pub fn new(email: &str, password: &str, options: Options) -> Result<Self> {
+
+        // Ensure the email and password are not empty
+        if email.is_empty() { Err(Error::ArgEmail)? };
+        if password.is_empty() { Err(Error::ArgPassword)? };
+
+        // Create the HTTP client
+        let mut builder = reqwest::ClientBuilder::default();
+        if let Some(proxy) = options.proxy {
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            base_url: options.base_url.trim_end_matches('/').to_string(),
+            email: email.to_string(),
+            password: password.to_string(),
+            auth: None
+        })
+    }
+
+    /// Returns the full URL of an API endpoint
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Logs in to the API, following up to [`MAX_REDIRECTS`] region redirects
+    async fn login(&mut self) -> Result<Auth> {
+        debug!("Logging in to LibreLinkUp...");
+
+        let mut redirects = 0;
+        loop {
+            // Send the request to the API and get the response body
+            let body = self.client.post(self.url(LOGIN_PATH))
+            .header("product", PRODUCT)
+            .header("version", VERSION)
+            .json(&LoginRequest {
+                email: &self.email,
+                password: &self.password
+            })
+            .send_logged().await?
+            .text().await?;
+
+            let data: Option<LoginData> = parse_response(&body).inspect_err(|e| {
+                error!("Failed to log in to LibreLinkUp: {e:?}");
+            })?;
+
+            match data {
+                // The account lives in another region, so retry there
+                Some(LoginData::Redirect { redirect: true, region }) => {
+                    if redirects >= MAX_REDIRECTS {
+                        Err(Error::Unknown(format!("LibreLinkUp redirected the login more than {MAX_REDIRECTS} times (last to '{region}')")))?
+                    }
+                    // The password is sent to the new URL, so only plain region names are trusted
+                    let Some(base_url) = region_base_url(&region) else {
+                        Err(Error::Unknown(format!("LibreLinkUp redirected the login to an invalid region ('{region}')")))?
+                    };
+                    debug!("LibreLinkUp redirected us to the '{region}' region");
+                    self.base_url = base_url;
+                    redirects += 1;
+                },
+                Some(LoginData::Redirect { redirect: false, .. }) => Err(Error::Unknown("The login response didn't contain a user".to_string()))?,
+                Some(LoginData::Success { user, auth_ticket }) => {
+                    crate::redact::register(&auth_ticket.token);
+                    crate::redact::register(&user.id);
+                    return Ok(Auth {
+                        token: auth_ticket.token,
+                        account_id: format!("{:x}", Sha256::digest(user.id.as_bytes()))
+                    });
+                },
+                None => Err(Error::Unknown("The login response didn't contain any data".to_string()))?
+            }
+        }
+    }
+
+    /// Queries the API for the latest glucose measurement of the first followed patient
+    pub async fn get_latest_glucose(&mut self) -> Result<Option<GlucoseMeasurement>> {
+
+        // Log in if we haven't already
+        if self.auth.is_none() {
+            self.auth = Some(self.login().await?);
+        }
+        let auth = self.auth.as_ref().unwrap();
+
+        // Send the request to the API and get the response body
+        let response = self.client.get(self.url(CONNECTIONS_PATH))
+        .header("product", PRODUCT)
+        .header("version", VERSION)
+        .header("Authorization", format!("Bearer {}", auth.token))
+        .header("Account-Id", &auth.account_id)
+        .send_logged().await?;
+
+        // The login expired, so log in again on the next request
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.auth = None;
+            Err(Error::Unauthorized)?
+        }
+
+        let body = response.text().await?;
+        let data: Option<Vec<Connection>> = parse_response(&body).inspect_err(|e| {
+            error!("Failed to get LibreLinkUp connections: {e:?}");
+        })?;
+
+        // Use the measurement of the first connection
+        let measurement = data
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|c| c.glucose_measurement);
+
+        match measurement {
+            Some(m) => Ok(Some(m.into_glucose_measurement()?)),
+            None => Ok(None)
+        }
+    }
+}
+
+/// Parses the data out of a response body, or the error if the response status isn't successful
+fn parse_response<T: for<'de> Deserialize<'de>>(body: &str) -> Result<Option<T>, Error> {
+
+    // Check the status first, since error responses have a different shape
+    let status = serde_json::from_str::<Response<serde_json::Value>>(body)
+    .map_err(|_| Error::Unknown(body.to_string()))?
+    .status;
+    if status != 0 {
+        return Err(Error::from_status(status));
+    }
+
+    // Parse the response body into the data
+    serde_json::from_str::<Response<T>>(body)
+    .map(|r| r.data)
+    .map_err(|_| Error::Unknown(body.to_string()))
+}
+
+/// The current login
+#[derive(Debug)]
+struct Auth {
+    /// The bearer token
+    token: String,
+    /// The SHA-256 hash of the user ID, which the API requires alongside the token
+    account_id: String
+}
+
+// API REQUESTS
+/// The body for the login request
+#[derive(Debug, Serialize)]
+struct LoginRequest<'a> {
+    /// The email of the account
+    email: &'a str,
+    /// The password of the account
+    password: &'a str
+}
+
+// API RESPONSES
+/// The envelope around every API response
+#[derive(Debug, Deserialize)]
+struct Response<T> {
+    /// The status of the response (0 means success)
+    status: u32,
+    /// The data of the response
+    data: Option<T>
+}
+
+/// Returns the base URL of the API in a region (e.g. `eu`), or `None` if the region isn't a plain `[a-z0-9]+` name
+fn region_base_url(region: &str) -> Option<String> {
+    let valid = !region.is_empty() && region.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit());
+    valid.then(|| format!("https://api-{region}.libreview.io"))
+}
+
+/// The data of the login response
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LoginData {
+    /// The account lives in another region
+    Redirect {
+        redirect: bool,
+        region: String
+    },
+    /// The login was successful
+    Success {
+        user: User,
+        #[serde(rename = "authTicket")]
+        auth_ticket: AuthTicket
+    }
+}
+
+/// The user in the login response
+#[derive(Debug, Deserialize)]
+struct User {
+    /// The ID of the user
+    id: String
+}
+
+/// The auth ticket in the login response
+#[derive(Debug, Deserialize)]
+struct AuthTicket {
+    /// The bearer token
+    token: String
+}
+
+/// A followed patient in the connections response
+#[derive(Debug, Deserialize)]
+struct Connection {
+    /// The latest glucose measurement of the patient
+    #[serde(rename = "glucoseMeasurement")]
+    glucose_measurement: Option<Measurement>
+}
+
+/// A glucose measurement in the connections response
+#[derive(Debug, Deserialize)]
+struct Measurement {
+    /// The date and time of the measurement in UTC
+    #[serde(rename = "FactoryTimestamp")]
+    factory_timestamp: String,
+    /// The glucose value
+    #[serde(rename = "ValueInMgPerDl")]
+    value: u32,
+    /// The trend of the glucose value (1 = falling quickly, 3 = flat, 5 = rising quickly)
+    #[serde(rename = "TrendArrow")]
+    trend_arrow: u8
+}
+impl Measurement {
+    /// Converts the measurement into the format used by the rest of the program
+    fn into_glucose_measurement(self) -> Result<GlucoseMeasurement> {
+        let millis = NaiveDateTime::parse_from_str(&self.factory_timestamp, TIMESTAMP_FORMAT)?
+        .and_utc()
+        .timestamp_millis();
+        let date = format!("Date({millis})");
+
+        let trend = match self.trend_arrow {
+            1 => "SingleDown",
+            2 => "FortyFiveDown",
+            3 => "Flat",
+            4 => "FortyFiveUp",
+            5 => "SingleUp",
+            _ => "NotComputable"
+        };
+
+        Ok(GlucoseMeasurement {
+            wt: date.clone(),
+            st: date.clone(),
+            dt: date,
+            value: self.value,
+            trend: trend.to_string(),
+            raw_value: None
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid email or password")]
+    InvalidCredentials,
+    #[error("The LibreLinkUp terms of use or privacy policy must be accepted in the app")]
+    TermsNotAccepted,
+    #[error("The login expired")]
+    Unauthorized,
+    #[error("The email must not be empty")]
+    ArgEmail,
+    #[error("The password must not be empty")]
+    ArgPassword,
+    #[error("Encountered an unknown error: {0}")]
+    Unknown(String)
+}
+impl Error {
+    /// Converts a non-zero response status into an error
+    fn from_status(status: u32) -> Self {
+        match status {
+            2 => Self::InvalidCredentials,
+            4 => Self::TermsNotAccepted,
+            _ => Self::Unknown(format!("The API returned status {status}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_plain_regions_are_followed() {
+        assert_eq!(region_base_url("eu2").as_deref(), Some("https://api-eu2.libreview.io"));
+        assert_eq!(region_base_url(""), None);
+        assert_eq!(region_base_url("evil.example.com/"), None);
+        assert_eq!(region_base_url("EU"), None);
+    }
+}
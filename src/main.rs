@@ -4,157 +4,185 @@ pub mod discord_protocols {
     pub mod users {
         include!(concat!(env!("OUT_DIR"), "/discord_protocols.users.rs"));
     }
+
+    /// The encoded descriptors of the messages above (see [`crate::proto`])
+    pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/discord_protocols.bin"));
 }
+mod alert;
+mod app;
+mod check;
+mod cli;
+mod config;
+mod console;
+mod control;
 mod dexcom;
+mod dexcom_oauth;
+mod error;
 mod discord;
+mod gateway;
+mod health;
+mod hooks;
+mod http;
+mod librelinkup;
+mod mqtt;
+mod nightscout;
+#[cfg(feature = "otel")]
+mod otel;
+mod proto;
+mod redact;
+mod script;
+mod setup;
+mod source;
+mod sqlite;
+mod status;
+mod systemd;
+mod trend;
+#[cfg(test)]
+mod tests;
 
 use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
-use std::{env::current_exe, fs::File, time::Duration};
-use base64::Engine;
+use std::{io::IsTerminal, time::{Duration, Instant}};
+use app::{App, OnMaxRetries, Schedule, TickOutcome};
+use cli::{CacheAction, Cli, Command};
+use config::Config;
+use error::AppError;
 use discord_protocols::users::*;
-use preloaded_user_settings::{CustomStatus, StatusSettings};
-use prost::Message;
 use tracing::{debug, error, info, trace, warn, Level};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt, reload, util::SubscriberInitExt, Layer, Registry};
+
+/// The logger layer the OpenTelemetry exporter is added to, once the config says where to export to
+pub type OtelHandle = reload::Handle<Option<Box<dyn Layer<Registry> + Send + Sync>>, Registry>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Parse the command line arguments
+    let cli = Cli::parse_with_build_info();
+
     // Initialize logger
     let filter = tracing_subscriber::filter::Targets::new()
         .with_target(module_path!(), Level::TRACE); // Log only this module at TRACE level
+    // Keep stdout clean for the emitted readings
+    let writer = match cli.emit {
+        Some(_) => BoxMakeWriter::new(std::io::stderr),
+        None => BoxMakeWriter::new(std::io::stdout)
+    };
+    let (otel_layer, otel_handle) = reload::Layer::new(None);
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(otel_layer)
+        .with(tracing_subscriber::fmt::layer().with_writer(redact::RedactingWriter::new(writer)))
         .with(filter)
         .init();
 
-    // Load the config
-    let config = Config::new();
+    // Log the error with its context, since converting it only keeps the error that caused it
+    if let Err(e) = run(cli, otel_handle).await {
+        error!("{e:?}");
+        if let Some(guidance) = AppError::from(e).guidance() {
+            error!("{guidance}");
+        }
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Loads the config and runs the selected command
+async fn run(cli: Cli, otel_handle: OtelHandle) -> Result<()> {
+    // The proto command only describes the build, so it doesn't need a config either
+    if cli.command() == Command::Proto {
+        print!("{}", proto::describe(proto::SETTINGS_MESSAGES)?);
+        return Ok(());
+    }
+
+    // The cache commands are for fixing a broken setup, so they don't need a valid config
+    if let Command::Cache { action } = cli.command() {
+        let path = Config::load_unvalidated().unwrap_or_default().cache_path();
+        match action {
+            CacheAction::Show => println!("{}", dexcom::show_cache(&path)?),
+            CacheAction::Clear => match dexcom::clear_cache(&path)? {
+                true => println!("Deleted the API cache at {path:?}"),
+                false => println!("There is no API cache at {path:?}")
+            }
+        }
+        return Ok(());
+    }
+
+    // Load the config, asking for the credentials on the first run if there's someone to ask
+    let first_run = !Config::exists() && std::io::stdin().is_terminal();
+    let config = match cli.command() == Command::Setup || first_run {
+        true => setup::run().await?,
+        false => Config::new()?
+    };
+    redact::configure(&config);
+
+    // Test the credentials and exit, if requested
+    if cli.command() == Command::Check {
+        let passed = check::run(&config).await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    // Spread out the instances that start at the same time (e.g. at boot)
+    let delay = config.startup_delay();
+    if !delay.is_zero() {
+        info!("Waiting {delay:?} before the first fetch...");
+        tokio::time::sleep(delay).await;
+    }
+
+    // Start exporting the traces before anything worth tracing happens
+    #[cfg(feature = "otel")]
+    let telemetry = match &config.otlp_endpoint {
+        Some(endpoint) => Some(otel::Telemetry::start(endpoint, &otel_handle)?),
+        None => None
+    };
 
     // Create the API instances
-    let discord_api = discord::Api::new(&config.discord_token).await;
-    let mut dexcom_api = dexcom::Api::new(&config.dexcom_username, &config.dexcom_password).await.unwrap();
+    let mut app = App::from_config(config).await?;
+    app.emit = cli.emit;
+    #[cfg(feature = "otel")]
+    if let Some(telemetry) = &telemetry {
+        telemetry.observe(&app.shared);
+    }
 
     // A flag used to update the status immediately on the first loop iteration
     let mut loop_has_started = false;
+    // How long the last loop iteration took, which is taken out of the sleep
+    let mut tick_time = Duration::ZERO;
+    // How long the next wait is, which is jittered, so it's rolled once per iteration and the logs match the actual wait
+    let mut poll_interval = Duration::ZERO;
+    let mut schedule = Schedule::new(app.config.loop_timing, app.config.missed_ticks);
 
     loop {
         
-        // Sleep for 5 minutes. This doesn't apply to the first loop iteration since that's the first one
+        // Wait for the rest of the 5 minutes (or longer if we're backing off). This doesn't apply to the first loop iteration since that's the first one
         if loop_has_started {
-            tokio::time::sleep(Duration::from_secs(300)).await;
+            schedule.wait(&app, tick_time, poll_interval).await;
         }
         // Update the loop flag since we just started
         loop_has_started = true;
 
-        // Get a blood sugar measurement
-        let status_string = match dexcom_api.get_latest_glucose().await {
-            Ok(measurement) => {
-                // If the API returned an empty response, log a warning and continue
-                if measurement.is_none() {
-                    warn!("The API didn't return a glucose measurement");
-                    continue;
-                }
-                // Shadow the measurement variable
-                let measurement = measurement.unwrap();
-                trace!("Successfully got glucose measurement: {}", measurement.value);
-                // Return the status string
-                format_status(measurement.value)
-            },
-            Err(e) => {
-
-                // If the session expired, just continue
-                if let Some(&dexcom::Error::SessionInvalid) = e.downcast_ref::<dexcom::Error>() {
-                    debug!("The dexcom session ID expired. Retrying with a new session ID...");
-                    // Reset the loop flag so we instantly retry
-                    loop_has_started = false;
-                    continue;
-                } else {
-                    error!("Failed to get latest glucose measurement: {e:?}");
-                    "Tell me to change my cgm".to_string()
-                }
-            }
-        };
-
-        // Log a warning if the status update failed
-        if let Err(e) = discord_api.set_status(&status_string).await {
-            warn!("Failed to update discord account status: {e:?}");
-            continue;
+        let started = Instant::now();
+        let outcome = app.tick_guarded().await;
+        tick_time = started.elapsed();
+        poll_interval = app.poll_interval();
+        #[cfg(feature = "otel")]
+        if let Some(telemetry) = &telemetry {
+            telemetry.record_tick(&outcome, tick_time);
         }
-    }
-
-}
-
-fn format_status(value: u32) -> String {
-    match value {
-        ..40 => format!("I'm currently dying, send help ({value} mg/dL)"),
-        40..60 => format!("I'm in sugar withdrawls, send help ({value} mg/dL)"),
-        60..80 => format!("Tell me to eat something, I'm a little low ({value} mg/dL)"),
-        80..200 => format!("We chillin ({value} mg/dL)"),
-        200..300 => format!("I'm a little high, tell me to do some pushups ({value} mg/dL)"),
-        300.. => format!("I'm currently ODing on sugar, send help ({value} mg/dL)")
-    }
-}
-
-/// The application configuration
-#[derive(Debug, Default, Serialize, Deserialize)]
-struct Config {
-    dexcom_username: String,
-    dexcom_password: String,
-    discord_token: String
-}
-impl Config {
-    /// Returns the existsing config file.
-    /// 
-    /// - NOTE: If there is no config file, it will create a new one and panic.
-    fn new() -> Self {
-        debug!("Trying to load the config file...");
-
-        // Get the path to the config file
-        let path = {
-            current_exe()
-            .unwrap()
-            .parent()
-            .unwrap()
-            .to_path_buf()
-            .join("config.json")
-        };
-        // Open the config file
-        let file = File::open(path);
-
-        // If the file doesn't exist or we can't open it, return None (i.e. create a new config)
-        if let Err(e) = file {
-            warn!("Failed to open the config file: {e:?}");
-            info!("Created a new config file. Please edit it and restart the program.");
-            // Save the default config and panic
-            Self::default().save();
-            panic!("Read the above message");
+        app.heartbeat(&outcome);
+
+        match outcome {
+            // Reset the loop flag if the tick wants to be retried instantly
+            TickOutcome::RetryNow => loop_has_started = false,
+            // Stop instead of hammering an account that needs manual intervention
+            TickOutcome::Halt => {
+                error!("Discord is asking for the account to be verified. Log in to discord in a browser, solve the captcha, and then restart the program.");
+                anyhow::bail!("The discord account needs to be verified");
+            },
+            // Either let a supervisor restart us, or keep trying less often
+            TickOutcome::MaxRetriesReached => match app.config.on_max_retries {
+                OnMaxRetries::Exit => anyhow::bail!(dexcom::Error::MaxRetriesReached),
+                OnMaxRetries::Backoff => warn!("Backing off for {poll_interval:?} before trying dexcom again")
+            },
+            TickOutcome::Updated | TickOutcome::Skipped => {}
         }
-        let file = file.unwrap();
-
-        // Read the config file
-        let cached_c: Self = serde_json::from_reader(file)
-        .context("The config file is invalid (perhaps try deleting it)")
-        .unwrap();
-
-        cached_c
     }
 
-    fn save(&self) {
-        // Get the path to the config file
-        let path = {
-            current_exe()
-            .unwrap()
-            .parent()
-            .unwrap()
-            .to_path_buf()
-            .join("config.json")
-        };
-        // Create the config file
-        let file = File::create(path).unwrap();
-        // Write self to the config file
-        serde_json::to_writer_pretty(file, &self)
-        .context("Failed to write to the config file")
-        .unwrap();
-    }
 }
@@ -0,0 +1,139 @@
+//
+// Publishes the glucose readings to an MQTT broker, so they show up as a Home Assistant sensor
+//
+
+use std::time::Duration;
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use serde_json::{json, Value};
+use anyhow::Result;
+use tracing::{debug, trace, warn};
+use crate::{config::Config, dexcom::{GlucoseMeasurement, GlucoseUnit}};
+
+/// The client ID used to connect to the broker
+const CLIENT_ID: &str = "dexcord";
+/// How long to wait before reconnecting after the connection drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A connection to the MQTT broker, which runs in the background and reconnects automatically
+#[derive(Debug)]
+pub struct Publisher {
+    /// The MQTT client
+    client: AsyncClient,
+    /// The topic the readings are published to
+    topic: String,
+    /// The unit the readings are published in
+    unit: GlucoseUnit,
+    /// How many decimals the readings are rounded to
+    decimals: usize
+}
+impl Publisher {
+    /// Connects to the broker in the config, or returns `None` if MQTT isn't configured
+    /// 
+    /// - NOTE: The Home Assistant discovery message is published as soon as we connect
+    pub fn connect(config: &Config) -> Option<Self> {
+        let host = config.mqtt_host.as_deref().filter(|h| !h.trim().is_empty())?;
+
+        let mut options = MqttOptions::new(CLIENT_ID, host, config.mqtt_port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &config.mqtt_username {
+            options.set_credentials(username, config.mqtt_password.as_deref().unwrap_or_default());
+        }
+
+        let (client, event_loop) = AsyncClient::new(options, 10);
+        tokio::spawn(run(event_loop));
+
+        let (unit, decimals) = config.channel_unit(config.mqtt_unit);
+        let s = Self {
+            client,
+            topic: config.mqtt_topic.clone(),
+            unit,
+            decimals
+        };
+
+        // Let Home Assistant know about the sensor
+        let discovery_topic = format!("{}/sensor/{CLIENT_ID}/glucose/config", config.mqtt_discovery_prefix);
+        if let Err(e) = s.client.try_publish(discovery_topic, QoS::AtLeastOnce, true, discovery_json(&s.topic, s.unit).to_string()) {
+            warn!("Failed to publish the Home Assistant discovery message: {e:?}");
+        }
+
+        Some(s)
+    }
+
+    /// Publishes a reading to the topic
+    /// 
+    /// - NOTE: This never waits for the broker, so a broken connection can't hold up the loop
+    pub fn publish(&self, measurement: &GlucoseMeasurement) -> Result<()> {
+        self.client.try_publish(&self.topic, QoS::AtLeastOnce, true, state_json(measurement, self.unit, self.decimals).to_string())?;
+        trace!("Published {} mg/dL to MQTT", measurement.value);
+        Ok(())
+    }
+}
+
+/// Drives the MQTT connection, reconnecting whenever it drops
+async fn run(mut event_loop: EventLoop) {
+    loop {
+        match event_loop.poll().await {
+            Ok(event) => trace!("Received MQTT event {event:?}"),
+            Err(e) => {
+                warn!("The MQTT connection failed: {e:?}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                debug!("Reconnecting to the MQTT broker...");
+            }
+        }
+    }
+}
+
+/// Returns the payload published for a reading
+/// 
+/// - NOTE: `value` is in the configured unit, while `value_mgdl` is always in mg/dL
+/// - NOTE: The local `/history` endpoint uses the same shape
+/// - NOTE: If a calibration offset was applied, the reported value is included as `raw_value_mgdl`
+pub(crate) fn state_json(measurement: &GlucoseMeasurement, unit: GlucoseUnit, decimals: usize) -> Value {
+    let mut state = json!({
+        "value": measurement.rounded_in(unit, decimals),
+        "value_mgdl": measurement.value,
+        "unit": unit,
+        "trend": measurement.trend,
+        "timestamp": measurement.timestamp().map(|t| t.to_rfc3339())
+    });
+    if let Some(raw_value) = measurement.raw_value {
+        state["raw_value_mgdl"] = json!(raw_value);
+    }
+    state
+}
+
+/// Returns the Home Assistant MQTT discovery config of the sensor
+fn discovery_json(state_topic: &str, unit: GlucoseUnit) -> Value {
+    json!({
+        "name": "Glucose",
+        "unique_id": "dexcord_glucose",
+        "state_topic": state_topic,
+        "unit_of_measurement": unit.label(),
+        "value_template": "{{ value_json.value }}",
+        "json_attributes_topic": state_topic,
+        "icon": "mdi:diabetes",
+        "state_class": "measurement",
+        "device": {
+            "identifiers": ["dexcord"],
+            "name": "dexcord"
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_includes_value_trend_and_timestamp() {
+        let date = "Date(1723472400000)".to_string();
+        let measurement = GlucoseMeasurement { wt: date.clone(), st: date.clone(), dt: date, value: 112, trend: "Flat".to_string(), raw_value: None };
+        assert_eq!(state_json(&measurement, GlucoseUnit::MgDl, 0), json!({
+            "value": 112.0,
+            "value_mgdl": 112,
+            "unit": "mg/dL",
+            "trend": "Flat",
+            "timestamp": "2024-08-12T14:20:00+00:00"
+        }));
+    }
+}
@@ -0,0 +1,188 @@
+//
+// Uploads the glucose readings to a Nightscout site, so they show up on its chart
+//
+
+use std::collections::{HashSet, VecDeque};
+use chrono::SecondsFormat;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use anyhow::{Context, Result};
+use tracing::{debug, error, trace, warn};
+use crate::{dexcom::GlucoseMeasurement, http::RequestExt};
+
+/// The path to upload and query entries
+pub(crate) const ENTRIES_PATH: &str = "/api/v1/entries";
+/// The path to query entries as JSON
+pub(crate) const ENTRIES_JSON_PATH: &str = "/api/v1/entries.json";
+/// The device name the entries are uploaded with
+const DEVICE: &str = "dexcord";
+/// The most entries uploaded in a single request (Nightscout sites behind proxies often limit the body size)
+const MAX_BATCH_SIZE: usize = 100;
+
+/// A Nightscout site the readings are uploaded to
+#[derive(Debug)]
+pub struct Uploader {
+    /// The HTTP client
+    client: reqwest::Client,
+    /// The base URL of the site (e.g. `https://example.herokuapp.com`)
+    base_url: String,
+    /// The SHA-1 hash of the API secret, which Nightscout expects instead of the secret itself
+    api_secret: Option<String>
+}
+impl Uploader {
+    /// Creates an uploader for the site
+    pub fn new(url: &str, api_secret: Option<&str>, proxy: Option<reqwest::Proxy>) -> Result<Self> {
+        let mut builder = reqwest::ClientBuilder::default();
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(Self {
+            client: builder.build().context("Failed to create HTTP client for nightscout")?,
+            base_url: url.trim_end_matches('/').to_string(),
+            api_secret: api_secret.map(|s| format!("{:x}", Sha1::digest(s.as_bytes())))
+        })
+    }
+
+    /// Returns the full URL of an API endpoint
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Uploads a single reading
+    pub async fn upload(&self, measurement: &GlucoseMeasurement) -> Result<()> {
+        let entry = Entry::from_measurement(measurement).context("The glucose measurement doesn't have a timestamp")?;
+        self.post(&[entry]).await?;
+        trace!("Uploaded {} mg/dL to nightscout", measurement.value);
+        Ok(())
+    }
+
+    /// Uploads the readings the site doesn't already have, returning how many were uploaded
+    /// 
+    /// - NOTE: The readings are uploaded in batches, which are split further if the site says they're too large
+    pub async fn backfill(&self, measurements: &[GlucoseMeasurement]) -> Result<usize> {
+        let mut entries: Vec<Entry> = measurements.iter().filter_map(Entry::from_measurement).collect();
+        let Some(oldest) = entries.iter().map(|e| e.date).min() else {
+            return Ok(0);
+        };
+
+        // Skip the readings that were already uploaded (by us, or by another uploader)
+        match self.existing_dates(oldest, entries.len()).await {
+            Ok(existing) => entries.retain(|e| !existing.contains(&e.date)),
+            Err(e) => warn!("Failed to get the existing nightscout entries, so every reading will be uploaded: {e:?}")
+        }
+
+        let mut batches: VecDeque<&[Entry]> = entries.chunks(MAX_BATCH_SIZE).collect();
+        while let Some(batch) = batches.pop_front() {
+            match self.post(batch).await {
+                Ok(()) => {},
+                // Split the batch in half and try again
+                Err(e) if batch.len() > 1 && matches!(e.downcast_ref::<Error>(), Some(Error::TooLarge)) => {
+                    debug!("Nightscout rejected a batch of {} entries as too large. Splitting it...", batch.len());
+                    let (first, second) = batch.split_at(batch.len() / 2);
+                    batches.push_front(second);
+                    batches.push_front(first);
+                },
+                Err(e) => Err(e)?
+            }
+        }
+
+        Ok(entries.len())
+    }
+
+    /// Returns the dates (in milliseconds) of the entries at or after `since`
+    async fn existing_dates(&self, since: i64, count: usize) -> Result<HashSet<i64>> {
+        let response = self.authorize(self.client.get(self.url(ENTRIES_JSON_PATH)))
+        .query(&[("find[date][$gte]", since.to_string()), ("count", count.to_string())])
+        .send_logged().await?;
+
+        if !response.status().is_success() {
+            return Err(Error::Rejected(response.text().await?).into());
+        }
+
+        let entries: Vec<ExistingEntry> = response.json().await?;
+        Ok(entries.into_iter().map(|e| e.date).collect())
+    }
+
+    /// Uploads entries in a single request
+    async fn post(&self, entries: &[Entry]) -> Result<()> {
+        let response = self.authorize(self.client.post(self.url(ENTRIES_PATH)))
+        .json(entries)
+        .send_logged().await?;
+
+        if response.status() == reqwest::StatusCode::PAYLOAD_TOO_LARGE {
+            Err(Error::TooLarge)?
+        }
+        if !response.status().is_success() {
+            let body = response.text().await?;
+            error!("Failed to upload to nightscout: {body}");
+            Err(Error::Rejected(body))?
+        }
+
+        Ok(())
+    }
+
+    /// Adds the API secret to a request, if there is one
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.api_secret {
+            Some(secret) => request.header("api-secret", secret),
+            None => request
+        }
+    }
+}
+
+/// A glucose entry in the format Nightscout expects
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    /// The kind of entry (always `sgv`, for sensor glucose values)
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// The glucose value (in mg/dL)
+    sgv: u32,
+    /// The time of the reading in milliseconds since the epoch
+    date: i64,
+    /// The time of the reading as an ISO 8601 string
+    date_string: String,
+    /// The trend of the reading (e.g. `FortyFiveUp`)
+    direction: String,
+    /// The device that uploaded the entry
+    device: &'static str
+}
+impl Entry {
+    /// Converts a measurement into an entry, or returns `None` if it doesn't have a timestamp
+    fn from_measurement(measurement: &GlucoseMeasurement) -> Option<Self> {
+        let timestamp = measurement.timestamp()?;
+
+        // Nightscout uses the dexcom trend names, except for these two
+        let direction = match measurement.trend.as_str() {
+            "NotComputable" | "None" => "NOT COMPUTABLE",
+            "RateOutOfRange" => "RATE OUT OF RANGE",
+            trend => trend
+        };
+
+        Some(Self {
+            kind: "sgv",
+            sgv: measurement.value,
+            date: timestamp.timestamp_millis(),
+            date_string: timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            direction: direction.to_string(),
+            device: DEVICE
+        })
+    }
+}
+
+/// An entry returned by the site
+#[derive(Debug, Deserialize)]
+struct ExistingEntry {
+    /// The time of the reading in milliseconds since the epoch
+    date: i64
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Nightscout rejected the request as too large")]
+    TooLarge,
+    #[error("Nightscout rejected the request: {0}")]
+    Rejected(String)
+}
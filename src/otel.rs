@@ -0,0 +1,126 @@
+//
+// Exports traces and metrics to an OpenTelemetry collector over OTLP/HTTP (only built with the `otel` feature)
+//
+
+use std::time::Duration;
+use opentelemetry::{metrics::{Counter, Histogram, MeterProvider as _}, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{metrics::{PeriodicReader, SdkMeterProvider}, runtime, trace::TracerProvider, Resource};
+use anyhow::{Context, Result};
+use tracing::info;
+use crate::{app::TickOutcome, control::Shared, OtelHandle};
+
+/// The name the traces and metrics are exported under
+const SERVICE_NAME: &str = "dexcord";
+
+/// The exporters, and the loop metrics recorded after every iteration
+pub struct Telemetry {
+    /// Exports the spans of the fetches, authentications, and status updates
+    tracer_provider: TracerProvider,
+    /// Exports the metrics periodically
+    meter_provider: SdkMeterProvider,
+    /// How many loop iterations there were, by outcome
+    ticks: Counter<u64>,
+    /// How long the loop iterations took (in seconds), by outcome
+    tick_durations: Histogram<f64>
+}
+impl Telemetry {
+    /// Starts exporting to the collector at the endpoint (e.g. `http://localhost:4318`), and adds the span exporter to the logger
+    pub fn start(endpoint: &str, handle: &OtelHandle) -> Result<Self> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = Resource::new([KeyValue::new("service.name", SERVICE_NAME)]);
+
+        let span_exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/traces"))
+        .build()
+        .context("Failed to create the OTLP span exporter")?;
+        let tracer_provider = TracerProvider::builder()
+        .with_batch_exporter(span_exporter, runtime::Tokio)
+        .with_resource(resource.clone())
+        .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(SERVICE_NAME));
+        handle.reload(Some(Box::new(layer) as _))
+        .context("Failed to add the OpenTelemetry layer to the logger")?;
+
+        let metric_exporter = opentelemetry_otlp::MetricExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/metrics"))
+        .build()
+        .context("Failed to create the OTLP metric exporter")?;
+        let meter_provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(metric_exporter, runtime::Tokio).build())
+        .with_resource(resource)
+        .build();
+        let meter = meter_provider.meter(SERVICE_NAME);
+
+        info!("Exporting traces and metrics to the OpenTelemetry collector at {endpoint}");
+        Ok(Self {
+            ticks: meter.u64_counter("loop_ticks")
+            .with_description("How many loop iterations there were")
+            .build(),
+            tick_durations: meter.f64_histogram("loop_tick_duration_seconds")
+            .with_description("How long the loop iterations took")
+            .with_unit("s")
+            .build(),
+            tracer_provider,
+            meter_provider
+        })
+    }
+
+    /// Exports the latest reading as gauges, like the `/metrics` endpoint
+    pub fn observe(&self, shared: &Shared) {
+        let meter = self.meter_provider.meter(SERVICE_NAME);
+
+        let values = shared.clone();
+        meter.u64_observable_gauge("glucose_value_mgdl")
+        .with_description("The latest glucose value in mg/dL")
+        .with_callback(move |gauge| {
+            if let Some(reading) = values.readings.lock().unwrap().back() {
+                gauge.observe(reading.value.into(), &[]);
+            }
+        })
+        .build();
+
+        let ages = shared.clone();
+        meter.i64_observable_gauge("glucose_age_seconds")
+        .with_description("How long ago the latest glucose reading was taken")
+        .with_unit("s")
+        .with_callback(move |gauge| {
+            if let Some(age) = ages.readings.lock().unwrap().back().and_then(|r| r.age(ages.timestamp_source)) {
+                gauge.observe(age.num_seconds(), &[]);
+            }
+        })
+        .build();
+    }
+
+    /// Records the outcome and duration of a loop iteration
+    pub fn record_tick(&self, outcome: &TickOutcome, elapsed: Duration) {
+        let attributes = [KeyValue::new("outcome", format!("{outcome:?}"))];
+        self.ticks.add(1, &attributes);
+        self.tick_durations.record(elapsed.as_secs_f64(), &attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::reload;
+    use wiremock::{matchers::{method, path}, Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_the_loop_metrics() {
+        let collector = MockServer::start().await;
+        Mock::given(method("POST"))
+        .and(path("/v1/metrics"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1..)
+        .mount(&collector).await;
+
+        // The layer has to outlive the handle, like it does in the logger
+        let (_layer, handle) = reload::Layer::new(None);
+        let telemetry = Telemetry::start(&collector.uri(), &handle).unwrap();
+        telemetry.record_tick(&TickOutcome::Updated, Duration::from_millis(250));
+        telemetry.meter_provider.force_flush().unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+//
+// Introspection of the discord settings protobuf the binary was compiled with, for debugging changes to the API
+//
+
+use anyhow::{Context, Result};
+use prost::Message;
+use prost_types::{field_descriptor_proto::{Label, Type}, DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+use crate::{discord, discord_protocols::FILE_DESCRIPTOR_SET};
+
+/// The package the settings messages are in
+const PACKAGE: &str = "discord_protocols.users";
+/// The messages the status updates are encoded with, from the outermost to the innermost
+pub const SETTINGS_MESSAGES: &[&str] = &["PreloadedUserSettings", "PreloadedUserSettings.StatusSettings", "PreloadedUserSettings.CustomStatus"];
+
+/// Returns the fields (number, name, and type) of each message, by their names relative to the package
+/// 
+/// ```text
+/// PreloadedUserSettings.CustomStatus
+///     1 text: string
+///     2 emoji_id: fixed64
+/// ```
+pub fn describe(names: &[&str]) -> Result<String> {
+    let set = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET)
+    .context("The compiled protobuf descriptors are invalid")?;
+    let messages: Vec<&DescriptorProto> = set.file.iter()
+    .filter(|f| f.package() == PACKAGE)
+    .flat_map(|f| &f.message_type)
+    .collect();
+
+    let mut description = format!("Encoded as settings version {}\n", discord::PROTO_SETTINGS_VERSION);
+    for name in names {
+        let message = find_message(&messages, name)
+        .with_context(|| format!("This build has no {name} message"))?;
+        description.push_str(&format!("\n{name}\n"));
+        for field in &message.field {
+            description.push_str(&format!("{:>5} {}: {}\n", field.number(), field.name(), field_type(field)));
+        }
+    }
+    Ok(description)
+}
+
+/// Finds a (possibly nested) message by its name relative to the package (e.g. `PreloadedUserSettings.CustomStatus`)
+fn find_message<'a>(messages: &[&'a DescriptorProto], name: &str) -> Option<&'a DescriptorProto> {
+    let mut parts = name.split('.');
+    let first = parts.next()?;
+    let mut message = *messages.iter().find(|m| m.name() == first)?;
+    for part in parts {
+        message = message.nested_type.iter().find(|m| m.name() == part)?;
+    }
+    Some(message)
+}
+
+/// Returns the type of a field as it's written in the `.proto` file (e.g. `optional CustomStatus`)
+fn field_type(field: &FieldDescriptorProto) -> String {
+    let name = match field.r#type() {
+        // Show the referenced types without the package and their parents (e.g. `CustomStatus`)
+        Type::Message | Type::Enum => field.type_name().rsplit('.').next().unwrap_or_default().to_string(),
+        scalar => scalar.as_str_name().trim_start_matches("TYPE_").to_lowercase()
+    };
+    match (field.label(), field.proto3_optional()) {
+        (Label::Repeated, _) => format!("repeated {name}"),
+        (_, true) => format!("optional {name}"),
+        _ => name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_custom_status() {
+        let description = describe(&["PreloadedUserSettings.StatusSettings", "PreloadedUserSettings.CustomStatus"]).unwrap();
+        assert!(description.contains("\nPreloadedUserSettings.CustomStatus\n    1 text: string\n    2 emoji_id: fixed64\n"), "{description}");
+        assert!(description.contains("    2 custom_status: optional CustomStatus\n"), "{description}");
+        assert!(describe(&["PreloadedUserSettings.Missing"]).is_err());
+    }
+}
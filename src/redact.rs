@@ -0,0 +1,176 @@
+//
+// Scrubbing of personal information (usernames, IDs, tokens) from the logs and debug dumps, so they're safe to share
+//
+
+use std::{borrow::Cow, io, sync::RwLock};
+use tracing_subscriber::fmt::MakeWriter;
+use crate::config::Config;
+
+/// What redacted text is replaced with
+pub const REDACTED: &str = "[REDACTED]";
+
+/// The redactor used by the log writer and debug dumps (`None` if redaction is disabled)
+///
+/// - NOTE: This starts enabled, so the logs before the config is loaded are redacted too
+static REDACTOR: RwLock<Option<Redactor>> = RwLock::new(Some(Redactor::new()));
+
+/// Replaces known secrets and anything shaped like an ID with [`REDACTED`]
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    /// The exact strings to redact (e.g. usernames and tokens)
+    secrets: Vec<String>
+}
+impl Redactor {
+    pub const fn new() -> Self {
+        Self { secrets: Vec::new() }
+    }
+
+    /// Adds a string to redact, ignoring empty ones
+    pub fn add(&mut self, secret: &str) {
+        let secret = secret.trim();
+        if !secret.is_empty() && !self.secrets.iter().any(|s| s == secret) {
+            self.secrets.push(secret.to_string());
+            // Longer secrets go first, so a secret containing another one is still fully redacted
+            self.secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        }
+    }
+
+    /// Returns the text with the secrets and UUIDs (dexcom and LibreLinkUp IDs) redacted
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for secret in &self.secrets {
+            if text.contains(secret.as_str()) {
+                text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+            }
+        }
+        match find_uuid(&text) {
+            Some(_) => Cow::Owned(redact_uuids(&text)),
+            None => text
+        }
+    }
+}
+
+/// Turns redaction on or off
+pub fn set_enabled(enabled: bool) {
+    let mut redactor = REDACTOR.write().unwrap();
+    match (enabled, redactor.is_some()) {
+        (true, false) => *redactor = Some(Redactor::new()),
+        (false, true) => *redactor = None,
+        _ => {}
+    }
+}
+
+/// Adds a string to redact (e.g. a session ID we just got)
+pub fn register(secret: &str) {
+    if let Some(redactor) = REDACTOR.write().unwrap().as_mut() {
+        redactor.add(secret);
+    }
+}
+
+/// Applies the redaction settings of the config, and adds the credentials in it
+pub fn configure(config: &Config) {
+    set_enabled(config.redact_pii);
+    let optional = [&config.proxy_username, &config.proxy_password, &config.mqtt_username, &config.mqtt_password, &config.nightscout_api_secret];
+    let secrets = [&config.dexcom_username, &config.dexcom_password, &config.discord_token, &config.librelinkup_email, &config.librelinkup_password].into_iter()
+    .chain([&config.dexcom_oauth_client_secret, &config.dexcom_oauth_refresh_token])
+    .chain(optional.into_iter().flatten())
+    .chain(&config.extra_discord_tokens)
+    .chain(config.followed_accounts.iter().flat_map(|a| [&a.dexcom_username, &a.dexcom_password]));
+    for secret in secrets {
+        register(secret);
+    }
+}
+
+/// Returns the text redacted with the global redactor, or as is if redaction is disabled
+pub fn redact(text: &str) -> Cow<'_, str> {
+    match REDACTOR.read().unwrap().as_ref() {
+        Some(redactor) => Cow::Owned(redactor.redact(text).into_owned()),
+        None => Cow::Borrowed(text)
+    }
+}
+
+/// Returns the byte offset of the first UUID (e.g. `01234567-89ab-cdef-0123-456789abcdef`) in the text
+fn find_uuid(text: &str) -> Option<usize> {
+    const GROUPS: [usize; 5] = [8, 4, 4, 4, 12];
+    let bytes = text.as_bytes();
+    (0..bytes.len().saturating_sub(35)).find(|&start| {
+        let mut i = start;
+        GROUPS.iter().enumerate().all(|(n, &length)| {
+            if n > 0 {
+                if bytes[i] != b'-' {
+                    return false;
+                }
+                i += 1;
+            }
+            let hex = bytes[i..i + length].iter().all(u8::is_ascii_hexdigit);
+            i += length;
+            hex
+        })
+    })
+}
+
+/// Returns the text with every UUID redacted
+fn redact_uuids(text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = find_uuid(rest) {
+        redacted.push_str(&rest[..start]);
+        redacted.push_str(REDACTED);
+        rest = &rest[start + 36..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// A log writer that redacts everything written through it
+pub struct RedactingWriter<M> {
+    /// The writer the redacted logs are written to
+    inner: M
+}
+impl<M> RedactingWriter<M> {
+    pub fn new(inner: M) -> Self {
+        Self { inner }
+    }
+}
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingWriter<M> {
+    type Writer = Redacting<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Redacting(self.inner.make_writer())
+    }
+}
+
+/// A writer that redacts each write before passing it on
+///
+/// - NOTE: The formatter writes each log line all at once, so secrets aren't split across writes
+pub struct Redacting<W>(W);
+impl<W: io::Write> io::Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_and_ids_are_redacted() {
+        let mut redactor = Redactor::new();
+        redactor.add("someone@example.com");
+        redactor.add("");
+
+        let line = "Logged in as someone@example.com with session 01234567-89AB-cdef-0123-456789abcdef.";
+        assert_eq!(redactor.redact(line), "Logged in as [REDACTED] with session [REDACTED].");
+
+        // Things that only look a bit like IDs are left alone
+        let line = "GET /ShareWebServices/Services/General/LoginPublisherAccountById -> 200 OK in 12ms";
+        assert!(matches!(redactor.redact(line), Cow::Borrowed(_)));
+    }
+}
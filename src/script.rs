@@ -0,0 +1,146 @@
+//
+// A user-provided Rhai script that turns a measurement into the status text, for more control than templates allow
+//
+
+use std::{path::Path, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use rhai::{Dynamic, Engine, Scope, AST};
+use anyhow::{Context, Result};
+use tracing::trace;
+
+/// How long a script can run before it's stopped
+const TIMEOUT: Duration = Duration::from_millis(100);
+/// The most operations a script can run (a backstop in case the clock isn't checked often enough)
+const MAX_OPERATIONS: u64 = 1_000_000;
+/// The longest string a script can build (in bytes)
+const MAX_STRING_SIZE: usize = 4096;
+
+/// The values a status script can use
+///
+/// - NOTE: Values that aren't known are `()` in the script
+#[derive(Debug, Clone, Default)]
+pub struct Inputs {
+    /// The glucose value in mg/dL (`value`)
+    pub value: u32,
+    /// The glucose value in the configured unit (`value_in_unit`)
+    pub value_in_unit: f64,
+    /// The label of the configured unit (`unit`, e.g. "mg/dL")
+    pub unit: String,
+    /// The name of the trend (`trend`, e.g. "FortyFiveUp")
+    pub trend: String,
+    /// How fast the glucose is changing in mg/dL per minute (`rate`)
+    pub rate: Option<f64>,
+    /// How old the reading is in minutes (`age`)
+    pub age: Option<i64>,
+    /// The time of the reading in the configured timezone and format (`time`)
+    pub time: Option<String>,
+    /// The hour of the reading in the configured timezone (`hour`, 0-23)
+    pub hour: Option<u32>
+}
+
+/// A compiled status script
+///
+/// - NOTE: Scripts can't touch the filesystem or network, and they're stopped if they run for too long
+pub struct StatusScript {
+    /// The engine the script runs in, with the limits applied
+    engine: Engine,
+    /// The compiled script
+    ast: AST,
+    /// When the current run started, which the engine checks while the script runs
+    started: Arc<Mutex<Instant>>
+}
+impl StatusScript {
+    /// Compiles the script at the provided path
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the status script ({})", path.display()))?;
+        Self::compile(&source)
+        .with_context(|| format!("Failed to compile the status script ({})", path.display()))
+    }
+
+    /// Compiles a script
+    pub fn compile(source: &str) -> Result<Self> {
+        let started = Arc::new(Mutex::new(Instant::now()));
+
+        // The raw engine doesn't have any way to reach outside of the script
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(1024);
+        engine.set_max_map_size(1024);
+        engine.set_max_call_levels(32);
+        engine.set_max_expr_depths(64, 32);
+        engine.disable_symbol("eval");
+        // The default handlers write to stdout, which would skip the redaction and break the output of `--emit`
+        engine.on_print(|text| trace!("The status script printed: {text}"));
+        engine.on_debug(|text, _, position| trace!("The status script printed (at {position}): {text}"));
+        let deadline = started.clone();
+        engine.on_progress(move |_| {
+            match deadline.lock().unwrap().elapsed() > TIMEOUT {
+                true => Some("the script took too long".into()),
+                false => None
+            }
+        });
+
+        let ast = engine.compile(source)?;
+        Ok(Self { engine, ast, started })
+    }
+
+    /// Runs the script, returning the status text it produced
+    pub fn run(&self, inputs: &Inputs) -> Result<String> {
+        let mut scope = Scope::new();
+        scope.push("value", inputs.value as i64);
+        scope.push("value_in_unit", inputs.value_in_unit);
+        scope.push("unit", inputs.unit.clone());
+        scope.push("trend", inputs.trend.clone());
+        scope.push("rate", inputs.rate.map(Dynamic::from).unwrap_or(Dynamic::UNIT));
+        scope.push("age", inputs.age.map(Dynamic::from).unwrap_or(Dynamic::UNIT));
+        scope.push("time", inputs.time.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT));
+        scope.push("hour", inputs.hour.map(|h| Dynamic::from(h as i64)).unwrap_or(Dynamic::UNIT));
+
+        *self.started.lock().unwrap() = Instant::now();
+        let result: Dynamic = self.engine.eval_ast_with_scope(&mut scope, &self.ast)
+        .map_err(|e| anyhow::anyhow!("The status script failed: {e}"))?;
+
+        // Only strings are statuses, so anything else is probably a mistake in the script
+        result.into_string()
+        .map_err(|t| anyhow::anyhow!("The status script returned a {t} instead of a string"))
+    }
+}
+impl std::fmt::Debug for StatusScript {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatusScript").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_can_use_the_inputs() {
+        let script = StatusScript::compile(r#"
+            if hour >= 22 { "Sleeping" }
+            else if rate != () && rate < -2.0 { `Dropping fast (${value})` }
+            else { `${value} ${unit}` }
+        "#).unwrap();
+        let inputs = Inputs { value: 112, unit: "mg/dL".to_string(), rate: Some(-3.0), hour: Some(14), ..Default::default() };
+        assert_eq!(script.run(&inputs).unwrap(), "Dropping fast (112)");
+        assert_eq!(script.run(&Inputs { rate: None, ..inputs.clone() }).unwrap(), "112 mg/dL");
+        assert_eq!(script.run(&Inputs { hour: Some(23), ..inputs }).unwrap(), "Sleeping");
+    }
+
+    #[test]
+    fn printing_does_not_stop_the_script() {
+        let script = StatusScript::compile(r#"print(`value is ${value}`); debug(value); `${value}`"#).unwrap();
+        assert_eq!(script.run(&Inputs { value: 112, ..Default::default() }).unwrap(), "112");
+    }
+
+    #[test]
+    fn runaway_scripts_are_stopped() {
+        let script = StatusScript::compile("loop {}").unwrap();
+        assert!(script.run(&Inputs::default()).is_err());
+
+        let script = StatusScript::compile("42").unwrap();
+        assert!(script.run(&Inputs::default()).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//
+// An interactive first-run setup, which asks for the credentials instead of making people edit the config file by hand
+//
+
+use std::io::{self, BufRead, Write};
+use anyhow::{Context, Result};
+use tracing::info;
+use crate::{check, config::{Config, ConfigFormat}, source::SourceKind};
+
+/// Asks for the credentials until they pass the checks, then saves them to the config file
+/// 
+/// - NOTE: Any existing config file is kept, other than the credentials
+/// - NOTE: Only the credentials of the configured sources are asked for, since those are the ones that are checked
+pub async fn run() -> Result<Config> {
+    let format = ConfigFormat::find().unwrap_or_else(ConfigFormat::from_env);
+    // Keep the other settings of an existing config, even if its credentials are missing
+    let mut config = Config::load_unvalidated().unwrap_or_default();
+
+    println!("Let's set up dexcord. The credentials are saved to {:?}.", format.path());
+    loop {
+        for kind in config.sources.clone() {
+            prompt_source(&mut config, kind)?;
+        }
+        config.discord_token = rpassword::prompt_password("Discord token: ")
+        .context("Failed to read the discord token")?;
+
+        // Make sure the credentials actually work before saving them
+        println!("Checking the credentials...");
+        if check::run(&config).await {
+            break;
+        }
+        // Give up without saving, rather than asking forever
+        if prompt("Some of the checks failed. Try again? [Y/n]")?.eq_ignore_ascii_case("n") {
+            anyhow::bail!("The setup was aborted, so the config file wasn't saved");
+        }
+    }
+
+    config.save(format);
+    info!("Saved the config file to {:?}", format.path());
+
+    Ok(config)
+}
+
+/// Asks for the credentials of a glucose source
+fn prompt_source(config: &mut Config, kind: SourceKind) -> Result<()> {
+    match kind {
+        SourceKind::Dexcom => {
+            config.dexcom_username = prompt("Dexcom username (email, phone number, or username)")?;
+            config.dexcom_password = rpassword::prompt_password("Dexcom password: ")
+            .context("Failed to read the password")?;
+        },
+        SourceKind::LibreLinkUp => {
+            config.librelinkup_email = prompt("LibreLinkUp email")?;
+            config.librelinkup_password = rpassword::prompt_password("LibreLinkUp password: ")
+            .context("Failed to read the password")?;
+        },
+        SourceKind::DexcomOauth => {
+            config.dexcom_oauth_client_id = prompt("Dexcom developer app client ID")?;
+            config.dexcom_oauth_client_secret = rpassword::prompt_password("Dexcom developer app client secret: ")
+            .context("Failed to read the client secret")?;
+            config.dexcom_oauth_refresh_token = rpassword::prompt_password("Dexcom OAuth refresh token: ")
+            .context("Failed to read the refresh token")?;
+        }
+    }
+    Ok(())
+}
+
+/// Asks for a line of input, without the trailing newline
+fn prompt(question: &str) -> Result<String> {
+    print!("{question}: ");
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line).context("Failed to read from stdin")?;
+    Ok(line.trim().to_string())
+}
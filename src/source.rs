@@ -0,0 +1,163 @@
+//
+// A common interface for the services that provide glucose measurements
+//
+
+use std::time::Duration;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use anyhow::Result;
+use tracing::warn;
+use crate::{config::Config, dexcom::{self, GlucoseMeasurement}, dexcom_oauth, librelinkup};
+
+/// A service that provides glucose measurements
+#[async_trait]
+pub trait GlucoseSource: Send {
+    /// The name of the source, used in logs
+    fn name(&self) -> &'static str;
+
+    /// Queries the source for the latest glucose measurement
+    async fn get_latest_glucose(&mut self) -> Result<Option<GlucoseMeasurement>>;
+
+    /// Queries the source for the glucose measurements over the last `hours` hours, sorted from oldest to newest
+    /// 
+    /// - NOTE: Sources without a history return no measurements
+    async fn get_history(&mut self, _hours: u8) -> Result<Vec<GlucoseMeasurement>> {
+        Ok(Vec::new())
+    }
+}
+
+/// The kinds of glucose sources that can be selected in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// The Dexcom share API
+    Dexcom,
+    /// The LibreLinkUp API
+    LibreLinkUp,
+    /// The official Dexcom developer API (OAuth), whose readings are delayed by about 3 hours
+    #[serde(rename = "dexcom_oauth")]
+    DexcomOauth
+}
+
+#[async_trait]
+impl GlucoseSource for dexcom::Api {
+    fn name(&self) -> &'static str {
+        "dexcom"
+    }
+
+    async fn get_latest_glucose(&mut self) -> Result<Option<GlucoseMeasurement>> {
+        dexcom::Api::get_latest_glucose(self).await
+    }
+
+    async fn get_history(&mut self, hours: u8) -> Result<Vec<GlucoseMeasurement>> {
+        dexcom::Api::get_history(self, hours).await
+    }
+}
+
+#[async_trait]
+impl GlucoseSource for librelinkup::Api {
+    fn name(&self) -> &'static str {
+        "librelinkup"
+    }
+
+    async fn get_latest_glucose(&mut self) -> Result<Option<GlucoseMeasurement>> {
+        librelinkup::Api::get_latest_glucose(self).await
+    }
+}
+
+#[async_trait]
+impl GlucoseSource for dexcom_oauth::Api {
+    fn name(&self) -> &'static str {
+        "dexcom_oauth"
+    }
+
+    async fn get_latest_glucose(&mut self) -> Result<Option<GlucoseMeasurement>> {
+        dexcom_oauth::Api::get_latest_glucose(self).await
+    }
+}
+
+/// Another dexcom account that is followed alongside the main one (e.g. someone else in the household)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FollowedAccount {
+    /// The name the account is shown under in the control endpoint (e.g. `"kid"`)
+    pub label: String,
+    /// The username of the dexcom account
+    pub dexcom_username: String,
+    /// The password of the dexcom account
+    pub dexcom_password: String
+}
+
+/// Creates the glucose sources selected in the config, in order of priority
+pub async fn from_config(config: &Config) -> Result<Vec<Box<dyn GlucoseSource>>> {
+    let mut sources: Vec<Box<dyn GlucoseSource>> = Vec::new();
+
+    for kind in &config.sources {
+        sources.push(create(config, *kind).await?);
+    }
+
+    Ok(sources)
+}
+
+/// Creates a single glucose source using the credentials in the config
+pub async fn create(config: &Config, kind: SourceKind) -> Result<Box<dyn GlucoseSource>> {
+    Ok(match kind {
+        SourceKind::Dexcom => {
+            let api = dexcom::Api::new(&config.dexcom_username, &config.dexcom_password, dexcom_options(config)?).await?;
+            Box::new(api)
+        },
+        SourceKind::LibreLinkUp => {
+            let api = librelinkup::Api::new(&config.librelinkup_email, &config.librelinkup_password, librelinkup::Options {
+                base_url: config.librelinkup_base_url.clone(),
+                proxy: config.proxy()?
+            })?;
+            Box::new(api)
+        },
+        SourceKind::DexcomOauth => {
+            let api = dexcom_oauth::Api::new(
+                &config.dexcom_oauth_client_id,
+                &config.dexcom_oauth_client_secret,
+                &config.dexcom_oauth_refresh_token,
+                dexcom_oauth::Options {
+                    base_url: config.dexcom_oauth_base_url.clone(),
+                    redirect_uri: config.dexcom_oauth_redirect_uri.clone(),
+                    token_path: Some(config.cache_path().with_file_name("dexcom_oauth_token")),
+                    proxy: config.proxy()?
+                }
+            )?;
+            Box::new(api)
+        }
+    })
+}
+
+/// Creates the dexcom sources of the followed accounts, labelled by their names
+/// 
+/// - NOTE: Accounts that fail to authenticate are skipped, so they can't stop the main account from working
+pub async fn followed_from_config(config: &Config) -> Result<Vec<(String, Box<dyn GlucoseSource>)>> {
+    let mut followed: Vec<(String, Box<dyn GlucoseSource>)> = Vec::new();
+
+    for account in &config.followed_accounts {
+        match dexcom::Api::new(&account.dexcom_username, &account.dexcom_password, dexcom_options(config)?).await {
+            Ok(api) => followed.push((account.label.clone(), Box::new(api))),
+            Err(e) => warn!("Failed to connect to the followed dexcom account '{}': {e:?}", account.label)
+        }
+    }
+
+    Ok(followed)
+}
+
+/// Returns the options for connecting to the dexcom API
+fn dexcom_options(config: &Config) -> Result<dexcom::Options> {
+    Ok(dexcom::Options {
+        base_url: config.dexcom_base_url.clone(),
+        proxy: config.proxy()?,
+        dump_dir: config.debug_dump.then(dexcom::default_dump_dir),
+        auth_retries: config.startup_retries,
+        auth_retry_delay: Duration::from_secs(config.startup_retry_delay_secs),
+        auth_timeout: Duration::from_secs(config.auth_timeout_secs),
+        retry_jitter: config.retry_jitter,
+        force_ipv4: config.force_ipv4,
+        max_session_age: (config.max_session_age_secs > 0).then(|| Duration::from_secs(config.max_session_age_secs)),
+        cache_path: config.cache_path()
+    })
+}
@@ -0,0 +1,93 @@
+//
+// Keeps a local history of the readings in a SQLite database, for long-term analytics without nightscout
+//
+
+use std::{path::Path, sync::{Arc, Mutex}};
+use rusqlite::{params, Connection};
+use anyhow::{Context, Result};
+use tokio::task::JoinHandle;
+use tracing::{trace, warn};
+use crate::dexcom::GlucoseMeasurement;
+
+/// The schema of the database, which is created on the first run
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS readings (
+    timestamp TEXT PRIMARY KEY,
+    value_mgdl INTEGER NOT NULL,
+    trend TEXT NOT NULL,
+    source TEXT NOT NULL
+)";
+
+/// A SQLite database the readings are written to
+#[derive(Debug, Clone)]
+pub struct Log {
+    /// The connection to the database
+    connection: Arc<Mutex<Connection>>
+}
+impl Log {
+    /// Opens (or creates) the database at the provided path
+    pub fn open(path: &Path) -> Result<Self> {
+        let connection = Connection::open(path)
+        .with_context(|| format!("Failed to open the SQLite database at {path:?}"))?;
+        connection.execute(SCHEMA, [])
+        .context("Failed to create the readings table")?;
+
+        Ok(Self { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    /// Writes a reading to the database in the background, skipping readings that are already in it
+    ///
+    /// - NOTE: Failures are only logged, since the history isn't worth interrupting the loop for
+    pub fn insert(&self, measurement: &GlucoseMeasurement, source: &'static str) -> JoinHandle<()> {
+        let log = self.clone();
+        let measurement = measurement.clone();
+        tokio::task::spawn_blocking(move || {
+            match log.insert_blocking(&measurement, source) {
+                Ok(true) => trace!("Wrote the glucose measurement to the SQLite database"),
+                Ok(false) => trace!("The glucose measurement is already in the SQLite database"),
+                Err(e) => warn!("Failed to write the glucose measurement to the SQLite database: {e:?}")
+            }
+        })
+    }
+
+    /// Writes a reading to the database, returning false if there already was one at the same time
+    fn insert_blocking(&self, measurement: &GlucoseMeasurement, source: &str) -> Result<bool> {
+        let Some(timestamp) = measurement.timestamp() else {
+            anyhow::bail!("The glucose measurement has no valid timestamp ({})", measurement.wt);
+        };
+
+        let inserted = self.connection.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO readings (timestamp, value_mgdl, trend, source) VALUES (?1, ?2, ?3, ?4)",
+            params![timestamp.to_rfc3339(), measurement.value, measurement.trend, source]
+        )?;
+        Ok(inserted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn readings_are_deduped_by_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("readings.db");
+        let date = "Date(1723472400000)".to_string();
+        let measurement = GlucoseMeasurement { wt: date.clone(), st: date.clone(), dt: date, value: 112, trend: "Flat".to_string(), raw_value: None };
+
+        let log = Log::open(&path).unwrap();
+        assert!(log.insert_blocking(&measurement, "dexcom").unwrap());
+        assert!(!log.insert_blocking(&measurement, "dexcom").unwrap());
+        log.insert(&GlucoseMeasurement { wt: "Date(1723472700000)".to_string(), ..measurement }, "librelinkup").await.unwrap();
+
+        // The schema is only created once, so reopening keeps the history
+        let connection = Connection::open(&path).unwrap();
+        Log::open(&path).unwrap();
+        let rows: Vec<(String, u32, String)> = connection.prepare("SELECT timestamp, value_mgdl, source FROM readings ORDER BY timestamp").unwrap()
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+        .collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(rows, vec![
+            ("2024-08-12T14:20:00+00:00".to_string(), 112, "dexcom".to_string()),
+            ("2024-08-12T14:25:00+00:00".to_string(), 112, "librelinkup".to_string())
+        ]);
+    }
+}
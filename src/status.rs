@@ -0,0 +1,608 @@
+//
+// Formatting of glucose measurements into discord statuses
+//
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::{app::LoopState, config::Config, dexcom::{GlucoseMeasurement, GlucoseUnit}, discord::Emoji, trend::{self, Trend, TrendDisplay}};
+
+/// The marker that is prepended to the status when the glucose is critically low
+const SAFETY_MARKER: &str = "⚠️ LOW";
+/// The blocks of the sparkline, from lowest to highest
+const SPARK_BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+/// The template used when no bucket contains the glucose value
+const FALLBACK_TEMPLATE: &str = "{value} {unit}";
+
+/// A discord status
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    /// The text of the status
+    pub text: String,
+    /// The emoji shown next to the status
+    pub emoji: Option<Emoji>,
+    /// The text of the bio, if it differs from the status
+    pub bio: Option<String>
+}
+impl Status {
+    /// Creates a status without an emoji
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            emoji: None,
+            bio: None
+        }
+    }
+
+    /// Returns the text of the bio
+    pub fn bio(&self) -> &str {
+        self.bio.as_deref().unwrap_or(&self.text)
+    }
+}
+
+/// The values that can be used in status templates
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    /// The glucose value in mg/dL (`{value}`, shown in `unit`)
+    pub value: u32,
+    /// The unit the value is shown in (`{unit}`)
+    pub unit: GlucoseUnit,
+    /// How many decimals the value is shown with
+    pub decimals: usize,
+    /// The estimated minutes until dropping below the low threshold (`{eta_low}`, e.g. "~15 min to low")
+    pub eta_low: Option<u32>,
+    /// The time of the reading in the configured timezone and format (`{time}`)
+    pub time: Option<String>,
+    /// The trend of the reading (`{arrow}`)
+    pub trend: Option<Trend>,
+    /// The A1C (in %) estimated from the recent average glucose (`{eag_a1c}`, e.g. "5.8")
+    pub eag_a1c: Option<f64>,
+    /// The index of the bucket that is still shown because its dwell time hasn't passed (see [`held_bucket`])
+    pub held_bucket: Option<usize>,
+    /// Which of the bucket's templates is used (wrapping around the number of templates)
+    pub template_index: usize,
+    /// How old the reading is (in minutes), if it's old enough to be marked stale
+    pub stale_minutes: Option<i64>,
+    /// When to retest after a low, in the configured timezone and format (see [`Config::retest_reminder_minutes`])
+    pub retest_at: Option<String>,
+    /// The text returned by the status script, which replaces the template
+    pub script_text: Option<String>,
+    /// A sparkline of the latest readings (`{spark}`, e.g. "▃▄▅▆")
+    pub spark: String,
+    /// Whether the reading just moved into another bucket, so its entering template is used (see [`Bucket::entering_template`])
+    pub entering: bool,
+    /// Whether the reading is back in range after being out of it, so the comeback template is used (see [`Config::comeback_template`])
+    pub comeback: bool,
+    /// The reversal marker, if the latest change turned against the earlier slope (`{reversal}`, see [`Config::reversal_marker`])
+    pub reversal: Option<String>,
+    /// How long (in minutes) the readings have been flat, if it's long enough to mark (see [`Config::flatline_minutes`])
+    pub flatline_minutes: Option<i64>
+}
+impl Context {
+    /// Creates the template context for a measurement
+    pub fn new(config: &Config, state: &LoopState, measurement: &GlucoseMeasurement) -> Self {
+        let eta_low = trend::rate(&state.readings, config.trend_window, config.timestamp_source)
+        .and_then(|rate| trend::minutes_to_low(measurement.value, rate, config.low_threshold, config.eta_horizon_minutes));
+
+        // Show the time of the reading in the configured timezone
+        let timezone = config.timezone.unwrap_or(chrono_tz::UTC);
+        let time = measurement.timestamp()
+        .map(|t| t.with_timezone(&timezone).format(&config.time_format).to_string());
+        let retest_at = state.low_detected_at
+        .filter(|_| config.retest_reminder_minutes > 0)
+        .map(|t| (t + chrono::Duration::minutes(config.retest_reminder_minutes.into())).with_timezone(&timezone).format(&config.time_format).to_string());
+
+        // Mark old readings as stale, so nobody mistakes them for live ones
+        let stale_minutes = measurement.age(config.timestamp_source)
+        .map(|age| age.num_minutes())
+        .filter(|&minutes| config.stale_after_minutes > 0 && minutes >= config.stale_after_minutes.into());
+
+        // The bucket was just entered if the last status was from another one
+        // - NOTE: Nothing was shown before the first reading, so that one never counts as entering (e.g. after a restart)
+        let held_bucket = held_bucket(&config.buckets, config.bucket_boundary, measurement.value, state.shown_bucket, Utc::now());
+        let bucket = held_bucket.or_else(|| find_bucket_index(&config.buckets, measurement.value, config.bucket_boundary));
+        let level = bucket.and_then(|i| config.buckets.get(i)).map(|b| b.level);
+        let entering = state.shown_bucket.is_some_and(|(shown, _)| Some(shown) != bucket);
+
+        // Celebrate coming back into range, for one update or until the comeback time passes
+        let shown_level = state.shown_bucket.and_then(|(shown, _)| config.buckets.get(shown)).map(|b| b.level);
+        let comeback = level == Some(0) && match state.shown_bucket {
+            Some(_) if shown_level.is_some_and(|l| l != 0) => true,
+            Some((_, since)) => state.previous_shown_level.is_some_and(|l| l != 0)
+            && Utc::now() - since < chrono::Duration::minutes(config.comeback_minutes.into()),
+            None => false
+        };
+
+        Self {
+            value: measurement.value,
+            unit: config.unit,
+            decimals: config.decimals(),
+            eta_low,
+            time,
+            trend: Some(measurement.trend()),
+            eag_a1c: trend::estimated_a1c(&state.a1c_values).filter(|_| state.a1c_values.len() >= config.a1c_min_readings),
+            held_bucket,
+            template_index: match config.template_rotation {
+                TemplateRotation::RoundRobin => state.template_rotation,
+                TemplateRotation::Random => rand::random()
+            },
+            stale_minutes,
+            retest_at,
+            script_text: None,
+            spark: sparkline(state.readings.iter().rev().take(config.spark_length).rev().map(|r| r.value), config.spark_min, config.spark_max),
+            entering,
+            comeback,
+            reversal: config.reversal_marker.clone()
+            .filter(|_| trend::reversed(&state.readings, config.trend_window, config.timestamp_source, config.reversal_min_rate)),
+            flatline_minutes: trend::flatline_minutes(&state.readings, config.flatline_tolerance, config.timestamp_source)
+            .filter(|&minutes| config.flatline_minutes > 0 && minutes >= config.flatline_minutes.into())
+        }
+    }
+}
+
+/// How a template is picked when a bucket has several
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateRotation {
+    /// Each update uses the next template
+    #[default]
+    RoundRobin,
+    /// Each update uses a random template
+    Random
+}
+
+/// Which bucket a glucose value exactly on a boundary belongs to (e.g. 80 with buckets `..80` and `80..200`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BucketBoundary {
+    /// The bucket above it (`min` is inclusive and `max` is exclusive, so 80 is in `80..200`)
+    #[default]
+    Min,
+    /// The bucket below it (`min` is exclusive and `max` is inclusive, so 80 is in `..80`)
+    Max
+}
+
+/// A range of glucose values and the status that is shown for them
+/// 
+/// - NOTE: Whether `min` and `max` are inclusive depends on the configured [`BucketBoundary`]
+/// - NOTE: If buckets overlap, the first one in the list is used
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bucket {
+    /// The lowest glucose value (in mg/dL) in the bucket (inclusive by default). There's no lower bound if this is unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<u32>,
+    /// The highest glucose value (in mg/dL) in the bucket (exclusive by default). There's no upper bound if this is unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<u32>,
+    /// The status text. Placeholders like `{value}` are replaced (see [`Context`]).
+    /// This can also be a list of templates, which are rotated through (see [`TemplateRotation`]).
+    #[serde(rename = "template", deserialize_with = "one_or_many")]
+    pub templates: Vec<String>,
+    /// The status text used for the first reading after moving into the bucket (e.g. a louder message when going low).
+    /// The templates are used for the readings after that, or always if this is unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entering_template: Option<String>,
+    /// The emoji shown next to the status (e.g. `"🟢"` or `{ "id": 123, "name": "custom" }`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<Emoji>,
+    /// How far out of range the bucket is. 0 is in range, negative is low, and positive is high.
+    /// Larger magnitudes are more severe (e.g. -2 is a worse low than -1).
+    /// 
+    /// - NOTE: This is required, since a missing level would silently treat lows and highs as in range (no alerts, and cleared statuses)
+    pub level: i8,
+    /// How many minutes the status is kept once shown, even if the value moves into a less severe bucket
+    #[serde(default)]
+    pub dwell_minutes: u32
+}
+impl Bucket {
+    /// Creates a bucket without an emoji
+    fn new(min: Option<u32>, max: Option<u32>, level: i8, template: &str) -> Self {
+        Self {
+            min,
+            max,
+            templates: vec![template.to_string()],
+            entering_template: None,
+            emoji: None,
+            level,
+            dwell_minutes: 0
+        }
+    }
+
+    /// Returns true if the bucket is in range (i.e. neither low nor high)
+    pub fn is_in_range(&self) -> bool {
+        self.level == 0
+    }
+
+    /// Returns true if the glucose value is within the bucket
+    pub fn contains(&self, value: u32, boundary: BucketBoundary) -> bool {
+        match boundary {
+            BucketBoundary::Min => self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value < max),
+            BucketBoundary::Max => self.min.is_none_or(|min| value > min) && self.max.is_none_or(|max| value <= max)
+        }
+    }
+}
+
+/// Deserializes either a single template or a list of them
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>)
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(template) => vec![template],
+        OneOrMany::Many(templates) => templates
+    })
+}
+
+/// Returns the buckets used when the config doesn't specify any
+pub fn default_buckets() -> Vec<Bucket> {
+    vec![
+        Bucket::new(None, Some(40), -3, "I'm currently dying, send help ({value} {unit})"),
+        Bucket::new(Some(40), Some(60), -2, "I'm in sugar withdrawls, send help ({value} {unit})"),
+        Bucket::new(Some(60), Some(80), -1, "Tell me to eat something, I'm a little low ({value} {unit})"),
+        Bucket::new(Some(80), Some(200), 0, "We chillin ({value} {unit})"),
+        Bucket::new(Some(200), Some(300), 1, "I'm a little high, tell me to do some pushups ({value} {unit})"),
+        Bucket::new(Some(300), None, 2, "I'm currently ODing on sugar, send help ({value} {unit})")
+    ]
+}
+
+/// Returns the first bucket that contains the glucose value
+pub fn find_bucket(buckets: &[Bucket], value: u32, boundary: BucketBoundary) -> Option<&Bucket> {
+    find_bucket_index(buckets, value, boundary).map(|i| &buckets[i])
+}
+
+/// Returns the index of the first bucket that contains the glucose value
+pub fn find_bucket_index(buckets: &[Bucket], value: u32, boundary: BucketBoundary) -> Option<usize> {
+    buckets.iter().position(|b| b.contains(value, boundary))
+}
+
+/// Returns the lowest glucose value that isn't in any bucket, if there's a gap
+/// 
+/// - NOTE: Gaps can only start at 0 or next to a boundary, so only those values are checked
+pub fn first_gap(buckets: &[Bucket], boundary: BucketBoundary) -> Option<u32> {
+    let mut candidates: Vec<u32> = buckets.iter()
+    .flat_map(|b| b.min.into_iter().chain(b.max))
+    .flat_map(|v| [v.saturating_sub(1), v, v.saturating_add(1)])
+    .chain([0, u32::MAX])
+    .collect();
+    candidates.sort_unstable();
+    candidates.into_iter().find(|&v| find_bucket(buckets, v, boundary).is_none())
+}
+
+/// Returns the index of the bucket that should keep being shown, if its dwell time hasn't passed yet
+/// 
+/// - NOTE: Moving into a more severe bucket (or out of range in the other direction) always goes through immediately
+/// - NOTE: The bucket is remembered by its index rather than its level, since several buckets can share a level
+pub fn held_bucket(buckets: &[Bucket], boundary: BucketBoundary, value: u32, shown: Option<(usize, DateTime<Utc>)>, now: DateTime<Utc>) -> Option<usize> {
+    let (shown, since) = shown?;
+    let shown_level = buckets.get(shown)?.level;
+    let level = find_bucket(buckets, value, boundary)?.level;
+
+    let escalates = level.unsigned_abs() > shown_level.unsigned_abs() || (level != 0 && level.signum() != shown_level.signum());
+    let dwelling = now - since < chrono::Duration::minutes(buckets[shown].dwell_minutes.into());
+    (level != shown_level && !escalates && dwelling).then_some(shown)
+}
+
+/// Formats a glucose value into a status using the configured buckets
+/// 
+/// - NOTE: If no bucket contains the value, the status just shows the value and has no emoji
+pub fn format_status(config: &Config, context: &Context) -> Status {
+    let bucket = context.held_bucket
+    .and_then(|i| config.buckets.get(i))
+    .or_else(|| find_bucket(&config.buckets, context.value, config.bucket_boundary));
+    let comeback_template = config.comeback_template.as_deref().filter(|_| context.comeback);
+    let (template, mut emoji) = match bucket {
+        Some(bucket) if comeback_template.is_some() => (comeback_template.unwrap_or_default(), bucket.emoji.clone()),
+        Some(Bucket { entering_template: Some(template), emoji, .. }) if context.entering => (template.as_str(), emoji.clone()),
+        Some(bucket) if !bucket.templates.is_empty() => {
+            let template = &bucket.templates[context.template_index % bucket.templates.len()];
+            (template.as_str(), bucket.emoji.clone())
+        },
+        Some(bucket) => (FALLBACK_TEMPLATE, bucket.emoji.clone()),
+        None => (FALLBACK_TEMPLATE, None)
+    };
+
+    // Show the trend as the emoji, unless it has no direction
+    if config.trend_status_emoji {
+        let arrow = context.trend.map(|t| t.arrow(TrendDisplay::Emoji)).unwrap_or_default();
+        if !arrow.is_empty() {
+            emoji = Some(Emoji::Unicode(arrow.to_string()));
+        }
+    }
+
+    // Replace the whole status in the most severe buckets, so it can't be missed
+    let critical = config.critical_emoji_status.as_deref()
+    .filter(|_| bucket.is_some_and(|b| b.level.unsigned_abs() >= config.critical_emoji_level));
+    let text = match critical {
+        Some(critical) if config.critical_emoji_show_value => format!("{critical} {}", render(FALLBACK_TEMPLATE, config.trend_display, context)),
+        Some(critical) => critical.to_string(),
+        None => decorate(config, context.script_text.as_deref().unwrap_or(&render(template, config.trend_display, context)))
+    };
+    let text = match &context.retest_at {
+        Some(retest_at) => format!("{text} (retest at {retest_at})"),
+        None => text
+    };
+    let text = match context.stale_minutes {
+        Some(minutes) => format!("{text} (stale, {minutes} min)"),
+        None => text
+    };
+    let text = match context.flatline_minutes {
+        Some(_) if !config.flatline_marker.is_empty() => format!("{text} {}", config.flatline_marker),
+        _ => text
+    };
+    let text = apply_safety_override(config, context.value, text);
+    let bio = config.bio_template.as_deref().map(|t| render(t, config.trend_display, context));
+
+    Status { text, emoji, bio }
+}
+
+/// Replaces the placeholders in a template
+/// 
+/// - NOTE: Placeholders without a value are replaced with nothing, and the surrounding whitespace is trimmed
+pub fn render(template: &str, trend_display: TrendDisplay, context: &Context) -> String {
+    let eta_low = context.eta_low
+    .map(|m| format!("~{m} min to low"))
+    .unwrap_or_default();
+
+    template
+    .replace("{value}", &context.unit.format(context.value, context.decimals))
+    .replace("{unit}", context.unit.label())
+    .replace("{eta_low}", &eta_low)
+    .replace("{time}", context.time.as_deref().unwrap_or_default())
+    .replace("{arrow}", context.trend.map(|t| t.arrow(trend_display)).unwrap_or_default())
+    .replace("{eag_a1c}", &context.eag_a1c.map(|a| format!("{a:.1}")).unwrap_or_default())
+    .replace("{spark}", &context.spark)
+    .replace("{reversal}", context.reversal.as_deref().unwrap_or_default())
+    .trim()
+    .to_string()
+}
+
+/// Maps the values to the blocks of a sparkline, clamping them to the range
+fn sparkline(values: impl Iterator<Item = u32>, min: u32, max: u32) -> String {
+    let span = max.saturating_sub(min).max(1) as f64;
+    values
+    .map(|v| {
+        let fraction = (v.clamp(min, max.max(min)) - min) as f64 / span;
+        SPARK_BLOCKS[((fraction * (SPARK_BLOCKS.len() - 1) as f64).round() as usize).min(SPARK_BLOCKS.len() - 1)]
+    })
+    .collect()
+}
+
+/// Returns roughly the longest a template can be once it's rendered and decorated (in characters)
+pub fn longest_output(config: &Config, template: &str) -> usize {
+    let context = Context {
+        value: 999,
+        unit: config.unit,
+        decimals: config.decimals(),
+        eta_low: Some(999),
+        time: Some("00:00:00 AM".to_string()),
+        trend: Some(Trend::DoubleDown),
+        eag_a1c: Some(10.0),
+        held_bucket: None,
+        template_index: 0,
+        stale_minutes: None,
+        retest_at: None,
+        script_text: None,
+        spark: SPARK_BLOCKS[0].to_string().repeat(config.spark_length),
+        entering: false,
+        comeback: false,
+        reversal: config.reversal_marker.clone(),
+        flatline_minutes: None
+    };
+    let text = apply_safety_override(config, 0, decorate(config, &render(template, config.trend_display, &context)));
+
+    text.chars().count()
+}
+
+/// Truncates the text to the maximum number of characters, optionally ending it with an ellipsis
+/// 
+/// - NOTE: This returns `None` if the text didn't need to be truncated
+pub fn truncate(text: &str, max: usize, ellipsis: bool) -> Option<String> {
+    if text.chars().count() <= max {
+        return None;
+    }
+
+    let truncated = if ellipsis && max > 0 {
+        let mut t: String = text.chars().take(max - 1).collect();
+        t.push('…');
+        t
+    } else {
+        text.chars().take(max).collect()
+    };
+
+    Some(truncated)
+}
+
+/// Surrounds the status with the configured prefix and suffix
+/// 
+/// - NOTE: This is also used for the error statuses, so every status is formatted consistently
+pub fn decorate(config: &Config, text: &str) -> String {
+    format!("{}{text}{}", config.status_prefix, config.status_suffix)
+}
+
+/// Prepends the safety marker to the status if the glucose value is below the critical floor.
+/// 
+/// - NOTE: This is applied after formatting, so even a misconfigured status can't hide a dangerous low.
+fn apply_safety_override(config: &Config, value: u32, status: String) -> String {
+    if config.safety_overrides && value < config.critical_low {
+        format!("{SAFETY_MARKER} {status}")
+    } else {
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reading at 2024-08-12 14:20:00 UTC
+    fn measurement() -> GlucoseMeasurement {
+        let date = "Date(1723472400000)".to_string();
+        GlucoseMeasurement { wt: date.clone(), st: date.clone(), dt: date, value: 112, trend: "Flat".to_string(), raw_value: None }
+    }
+
+    #[test]
+    fn time_defaults_to_utc() {
+        let context = Context::new(&Config::default(), &LoopState::default(), &measurement());
+        assert_eq!(render("{value} at {time}", TrendDisplay::TextArrow, &context), "112 at 14:20");
+    }
+
+    #[test]
+    fn time_uses_configured_timezone() {
+        let config = Config {
+            timezone: Some(chrono_tz::America::New_York),
+            time_format: "%-I:%M %p".to_string(),
+            ..Default::default()
+        };
+        let context = Context::new(&config, &LoopState::default(), &measurement());
+        assert_eq!(render("{value} at {time}", TrendDisplay::TextArrow, &context), "112 at 10:20 AM");
+    }
+
+    #[test]
+    fn prefix_and_suffix_surround_the_status() {
+        let config = Config {
+            status_prefix: "🩸 ".to_string(),
+            status_suffix: " · via dexcord".to_string(),
+            ..Default::default()
+        };
+        let context = Context::new(&config, &LoopState::default(), &measurement());
+        assert_eq!(format_status(&config, &context).text, "🩸 We chillin (112 mg/dL) · via dexcord");
+    }
+
+    #[test]
+    fn truncates_at_char_boundaries() {
+        assert_eq!(truncate("🩸🩸🩸", 2, false).as_deref(), Some("🩸🩸"));
+        assert_eq!(truncate("🩸🩸🩸", 2, true).as_deref(), Some("🩸…"));
+        assert_eq!(truncate("🩸🩸", 2, true), None);
+    }
+
+    #[test]
+    fn a1c_waits_for_enough_readings() {
+        let config = Config { a1c_min_readings: 2, ..Default::default() };
+        let mut state = LoopState::default();
+        state.a1c_values.push_back(126);
+        let context = Context::new(&config, &state, &measurement());
+        assert_eq!(render("A1C {eag_a1c}", TrendDisplay::TextArrow, &context), "A1C");
+
+        state.a1c_values.push_back(126);
+        let context = Context::new(&config, &state, &measurement());
+        assert_eq!(render("A1C {eag_a1c}", TrendDisplay::TextArrow, &context), "A1C 6.0");
+    }
+
+    #[test]
+    fn value_is_shown_in_the_configured_unit() {
+        let config = Config { unit: GlucoseUnit::MmolL, ..Default::default() };
+        let context = Context::new(&config, &LoopState::default(), &measurement());
+        assert_eq!(format_status(&config, &context).text, "We chillin (6.2 mmol/L)");
+    }
+
+    #[test]
+    fn value_uses_the_configured_decimals() {
+        let config = Config { unit: GlucoseUnit::MmolL, glucose_decimals: Some(2), ..Default::default() };
+        let context = Context::new(&config, &LoopState::default(), &measurement());
+        assert_eq!(render("{value} {unit}", TrendDisplay::TextArrow, &context), "6.22 mmol/L");
+    }
+
+    #[test]
+    fn critical_emoji_replaces_severe_statuses() {
+        let config = Config { critical_emoji_status: Some("🆘".to_string()), status_prefix: "🩸 ".to_string(), ..Default::default() };
+        let severe = Context { value: 350, ..Default::default() };
+        assert_eq!(format_status(&config, &severe).text, "🆘 350 mg/dL");
+
+        // Milder buckets keep their template
+        let mild = Context { value: 250, ..Default::default() };
+        assert_eq!(format_status(&config, &mild).text, "🩸 I'm a little high, tell me to do some pushups (250 mg/dL)");
+    }
+
+    #[test]
+    fn dwell_keeps_the_bucket_unless_escalating() {
+        let buckets: Vec<Bucket> = default_buckets().into_iter().map(|b| Bucket { dwell_minutes: 10, ..b }).collect();
+        let now = Utc::now();
+        // The default high bucket (200..300) is at index 4, and the in range one at index 3
+        let shown = Some((4, now - chrono::Duration::minutes(5)));
+
+        // Dipping back into range is held, but only until the dwell time passes
+        assert_eq!(held_bucket(&buckets, BucketBoundary::Min, 190, shown, now), Some(4));
+        assert_eq!(held_bucket(&buckets, BucketBoundary::Min, 190, Some((4, now - chrono::Duration::minutes(15))), now), None);
+
+        // Getting worse always goes through
+        assert_eq!(held_bucket(&buckets, BucketBoundary::Min, 310, shown, now), None);
+        assert_eq!(held_bucket(&buckets, BucketBoundary::Min, 70, Some((3, now)), now), None);
+    }
+
+    #[test]
+    fn held_buckets_are_found_by_index_when_levels_repeat() {
+        let buckets: Vec<Bucket> = serde_json::from_value(serde_json::json!([
+            { "max": 70, "level": -1, "template": "Low ({value})" },
+            { "min": 70, "max": 120, "level": 0, "template": "Steady ({value})" },
+            { "min": 120, "max": 180, "level": 0, "template": "Fine ({value})", "dwell_minutes": 30 },
+            { "min": 180, "level": 1, "template": "High ({value})", "dwell_minutes": 30 }
+        ])).unwrap();
+        let now = Utc::now();
+
+        // A held bucket keeps its own template, even if an earlier bucket has the same level
+        assert_eq!(held_bucket(&buckets, BucketBoundary::Min, 100, Some((3, now)), now), Some(3));
+        assert_eq!(held_bucket(&buckets, BucketBoundary::Min, 65, Some((2, now)), now), None);
+        let config = Config { buckets, ..Default::default() };
+        let context = Context { value: 100, held_bucket: Some(2), ..Default::default() };
+        assert_eq!(format_status(&config, &context).text, "Fine (100)");
+    }
+
+    #[test]
+    fn buckets_rotate_through_their_templates() {
+        let buckets: Vec<Bucket> = serde_json::from_str(r#"[
+            { "min": 100, "level": 0, "template": ["First ({value})", "Second ({value})"] },
+            { "level": 0, "template": "Single ({value})" }
+        ]"#).unwrap();
+        assert_eq!(buckets[1].templates, vec!["Single ({value})"]);
+
+        let config = Config { buckets, ..Default::default() };
+        let texts: Vec<String> = (0..3).map(|template_index| {
+            format_status(&config, &Context { value: 112, template_index, ..Default::default() }).text
+        }).collect();
+        assert_eq!(texts, vec!["First (112)", "Second (112)", "First (112)"]);
+    }
+
+    #[test]
+    fn sparkline_maps_values_to_blocks() {
+        assert_eq!(sparkline([40, 170, 300, 500, 0].into_iter(), 40, 300), "▁▅██▁");
+        assert_eq!(sparkline(std::iter::empty(), 40, 300), "");
+    }
+
+    #[test]
+    fn trend_can_be_the_status_emoji() {
+        let config = Config { trend_status_emoji: true, ..Default::default() };
+        let context = Context::new(&config, &LoopState::default(), &measurement());
+        assert_eq!(format_status(&config, &context).emoji, Some(Emoji::Unicode("➡️".to_string())));
+    }
+
+    #[test]
+    fn boundary_values_follow_the_configured_side() {
+        let buckets = default_buckets();
+        let level = |value, boundary| find_bucket(&buckets, value, boundary).map(|b| b.level);
+        assert_eq!(level(79, BucketBoundary::Min), Some(-1));
+        assert_eq!(level(80, BucketBoundary::Min), Some(0));
+        assert_eq!(level(80, BucketBoundary::Max), Some(-1));
+        assert_eq!(level(81, BucketBoundary::Max), Some(0));
+        assert_eq!(level(300, BucketBoundary::Min), Some(2));
+        assert_eq!(level(300, BucketBoundary::Max), Some(1));
+        assert_eq!(level(0, BucketBoundary::Min), Some(-3));
+        assert_eq!(level(u32::MAX, BucketBoundary::Max), Some(2));
+    }
+
+    #[test]
+    fn gaps_between_buckets_are_found() {
+        assert_eq!(first_gap(&default_buckets(), BucketBoundary::Min), None);
+        assert_eq!(first_gap(&default_buckets(), BucketBoundary::Max), None);
+
+        // Whether 79 or 80 is left out depends on which side the boundaries belong to
+        let buckets = vec![Bucket::new(None, Some(79), -1, "Low"), Bucket::new(Some(80), None, 0, "Fine")];
+        assert_eq!(first_gap(&buckets, BucketBoundary::Min), Some(79));
+        assert_eq!(first_gap(&buckets, BucketBoundary::Max), Some(80));
+
+        let buckets = vec![Bucket::new(Some(40), None, 0, "Fine")];
+        assert_eq!(first_gap(&buckets, BucketBoundary::Min), Some(0));
+    }
+}
@@ -0,0 +1,92 @@
+//
+// Lets systemd know the program is alive when it's run as a service with `Type=notify` and `WatchdogSec=`
+// This does nothing unless systemd passed us a notification socket
+//
+
+use tracing::{debug, warn};
+
+/// The environment variable systemd sets to the notification socket
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Sends readiness and watchdog notifications to systemd
+/// 
+/// - NOTE: `WatchdogSec=` must be longer than the poll interval, since the watchdog is only pinged once per loop iteration
+#[derive(Debug, Default)]
+pub struct Notifier {
+    /// Whether `READY=1` was sent
+    ready: bool
+}
+impl Notifier {
+    /// Returns a notifier if the program is running under systemd
+    pub fn from_env() -> Option<Self> {
+        if cfg!(unix) && std::env::var_os(NOTIFY_SOCKET).is_some() {
+            debug!("Running under systemd, so it'll be notified about successful fetches");
+            return Some(Self::default());
+        }
+        None
+    }
+
+    /// Pings the watchdog, telling systemd we're ready first if this is the first ping
+    /// 
+    /// - NOTE: This should only be called while fetches are succeeding, so systemd restarts us once they stop
+    pub fn ping(&mut self) {
+        let result = if self.ready {
+            notify(&[Notification::Watchdog])
+        } else {
+            notify(&[Notification::Ready, Notification::Watchdog])
+        };
+
+        match result {
+            Ok(()) => self.ready = true,
+            Err(e) => warn!("Failed to notify systemd: {e:?}")
+        }
+    }
+
+    /// Pings the watchdog without ever telling systemd we're ready (e.g. while paused on purpose)
+    pub fn keepalive(&mut self) {
+        if let Err(e) = notify(&[Notification::Watchdog]) {
+            warn!("Failed to notify systemd: {e:?}");
+        }
+    }
+}
+
+/// The notifications we send to systemd
+#[derive(Debug, Clone, Copy)]
+enum Notification {
+    /// `READY=1`
+    Ready,
+    /// `WATCHDOG=1`
+    Watchdog
+}
+
+/// Sends notifications to systemd
+#[cfg(unix)]
+fn notify(notifications: &[Notification]) -> std::io::Result<()> {
+    let states: Vec<_> = notifications.iter()
+    .map(|n| match n {
+        Notification::Ready => sd_notify::NotifyState::Ready,
+        Notification::Watchdog => sd_notify::NotifyState::Watchdog
+    })
+    .collect();
+    sd_notify::notify(false, &states)
+}
+
+/// Sends notifications to systemd
+#[cfg(not(unix))]
+fn notify(_notifications: &[Notification]) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keepalives_never_send_ready() {
+        let mut notifier = Notifier::default();
+        notifier.keepalive();
+        assert!(!notifier.ready);
+        notifier.ping();
+        assert!(notifier.ready);
+    }
+}
@@ -0,0 +1,140 @@
+//
+// End-to-end tests of the dexcom authentication and API cache
+//
+
+use std::{fs, time::Duration};
+use wiremock::{matchers::{method, path}, Mock, ResponseTemplate};
+use crate::{config::Config, dexcom};
+use super::{fixtures, Harness};
+
+#[tokio::test]
+async fn reuses_cached_session() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    let config = h.config();
+
+    drop(h.dexcom_api(&config).await);
+    drop(h.dexcom_api(&config).await);
+
+    assert_eq!(h.dexcom_requests(dexcom::SESSION_ID_PATH).await, 1);
+}
+
+#[tokio::test]
+async fn keeps_sessions_of_every_account() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    let first = h.config();
+    let mut second = h.config();
+    second.dexcom_username = "other".to_string();
+
+    // Switching back and forth only authenticates each account once
+    drop(h.dexcom_api(&first).await);
+    drop(h.dexcom_api(&second).await);
+    drop(h.dexcom_api(&first).await);
+    drop(h.dexcom_api(&second).await);
+
+    assert_eq!(h.dexcom_requests(dexcom::SESSION_ID_PATH).await, 2);
+}
+
+#[tokio::test]
+async fn does_not_rewrite_unchanged_cache() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    let config = h.config();
+    let cache_path = h.dexcom_options(&config).cache_path;
+
+    // Compact the cache, which would be pretty printed again if it was rewritten
+    drop(h.dexcom_api(&config).await);
+    let cache: serde_json::Value = serde_json::from_str(&fs::read_to_string(&cache_path).unwrap()).unwrap();
+    fs::write(&cache_path, cache.to_string()).unwrap();
+
+    drop(h.dexcom_api(&config).await);
+
+    assert_eq!(fs::read_to_string(&cache_path).unwrap(), cache.to_string());
+}
+
+#[tokio::test]
+async fn retries_authentication_when_the_network_is_not_ready() {
+    let h = Harness::start().await;
+    h.mount_dexcom_once(dexcom::ACCOUNT_ID_PATH, 502, "Bad Gateway").await;
+    h.mount_dexcom_auth().await;
+
+    drop(h.dexcom_api(&h.config()).await);
+
+    assert_eq!(h.dexcom_requests(dexcom::ACCOUNT_ID_PATH).await, 2);
+}
+
+#[tokio::test]
+async fn does_not_retry_invalid_credentials() {
+    let h = Harness::start().await;
+    h.mount_dexcom(dexcom::ACCOUNT_ID_PATH, 500, fixtures::INVALID_PASSWORD).await;
+
+    let result = dexcom::Api::new("username", "password", h.dexcom_options(&h.config())).await;
+
+    assert!(matches!(result.unwrap_err().downcast_ref(), Some(dexcom::Error::InvalidPassword)));
+    assert_eq!(h.dexcom_requests(dexcom::ACCOUNT_ID_PATH).await, 1);
+}
+
+#[tokio::test]
+async fn retries_authentication_that_times_out() {
+    let h = Harness::start().await;
+    Mock::given(method("POST"))
+    .and(path(dexcom::ACCOUNT_ID_PATH))
+    .respond_with(ResponseTemplate::new(200).set_body_raw(fixtures::ACCOUNT_ID, "application/json").set_delay(Duration::from_secs(5)))
+    .up_to_n_times(1)
+    .mount(&h.dexcom).await;
+    h.mount_dexcom_auth().await;
+    let config = Config { auth_timeout_secs: 1, ..h.config() };
+
+    drop(h.dexcom_api(&config).await);
+
+    assert_eq!(h.dexcom_requests(dexcom::ACCOUNT_ID_PATH).await, 2);
+}
+
+#[tokio::test]
+async fn replaces_sessions_older_than_the_max_age() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    let config = Config { max_session_age_secs: 3600, ..h.config() };
+    let cache_path = h.dexcom_options(&config).cache_path;
+
+    // A fresh session is reused
+    drop(h.dexcom_api(&config).await);
+    let mut api = h.dexcom_api(&config).await;
+    api.get_latest_glucose().await.unwrap();
+    assert_eq!(h.dexcom_requests(dexcom::SESSION_ID_PATH).await, 1);
+    drop(api);
+
+    // But an old one is replaced with new account and session IDs
+    let mut cache: serde_json::Value = serde_json::from_str(&fs::read_to_string(&cache_path).unwrap()).unwrap();
+    cache["accounts"][&config.dexcom_username]["authenticated_at"] = "2024-08-12T14:20:00Z".into();
+    fs::write(&cache_path, cache.to_string()).unwrap();
+    drop(h.dexcom_api(&config).await);
+    assert_eq!(h.dexcom_requests(dexcom::ACCOUNT_ID_PATH).await, 2);
+    assert_eq!(h.dexcom_requests(dexcom::SESSION_ID_PATH).await, 2);
+}
+
+#[tokio::test]
+async fn failed_authentication_leaves_the_cache_alone() {
+    let h = Harness::start().await;
+    h.mount_dexcom(dexcom::ACCOUNT_ID_PATH, 200, fixtures::ACCOUNT_ID).await;
+    h.mount_dexcom(dexcom::SESSION_ID_PATH, 500, fixtures::INVALID_PASSWORD).await;
+    let config = Config { max_session_age_secs: 3600, ..h.config() };
+    let cache_path = h.dexcom_options(&config).cache_path;
+
+    // An old session has to be replaced, but the session request fails after the account ID was fetched
+    let cache = serde_json::json!({ "accounts": { &config.dexcom_username: {
+        "account_id": "old-account-id",
+        "session_id": "old-session-id",
+        "authenticated_at": "2024-08-12T14:20:00Z"
+    } } });
+    fs::write(&cache_path, cache.to_string()).unwrap();
+    assert!(dexcom::Api::new(&config.dexcom_username, &config.dexcom_password, h.dexcom_options(&config)).await.is_err());
+    assert_eq!(fs::read_to_string(&cache_path).unwrap(), cache.to_string());
+
+    // A new account isn't written with an empty session ID either
+    let other = Config { dexcom_username: "other".to_string(), ..h.config() };
+    assert!(dexcom::Api::new(&other.dexcom_username, &other.dexcom_password, h.dexcom_options(&other)).await.is_err());
+    assert_eq!(fs::read_to_string(&cache_path).unwrap(), cache.to_string());
+}
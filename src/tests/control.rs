@@ -0,0 +1,204 @@
+//
+// End-to-end tests of the control endpoint
+//
+
+use crate::{app::TickOutcome, control, dexcom::{self, GlucoseUnit}, trend::TrendDisplay, Config};
+use super::{fixtures, Harness};
+
+#[tokio::test]
+async fn pausing_skips_updates_until_resumed() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+    let addr = control::spawn("127.0.0.1:0", app.shared.clone()).await.unwrap();
+    let client = reqwest::Client::new();
+
+    // Pausing stops the loop from touching dexcom or discord
+    let status: serde_json::Value = client.post(format!("http://{addr}/pause")).send().await.unwrap().json().await.unwrap();
+    assert_eq!(status["paused"], true);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(h.dexcom_requests(dexcom::MEASURE_GLUCOSE_PATH).await, 0);
+
+    // Resuming picks up where it left off
+    client.post(format!("http://{addr}/resume")).send().await.unwrap();
+    let status: serde_json::Value = client.get(format!("http://{addr}/status")).send().await.unwrap().json().await.unwrap();
+    assert_eq!(status["paused"], false);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+}
+
+#[tokio::test]
+async fn health_reflects_every_subsystem() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(401).await;
+
+    let mut app = h.app(h.config()).await;
+    let addr = control::spawn("127.0.0.1:0", app.shared.clone()).await.unwrap();
+
+    // The fetch worked, but the discord update didn't
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    let response = reqwest::get(format!("http://{addr}/healthz")).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    let health: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(health["healthy"], false);
+    assert!(health["subsystems"]["sources"]["last_success"].is_string());
+    assert!(health["subsystems"]["discord"]["last_error"].is_string());
+    assert!(health["subsystems"].get("nightscout").is_none());
+}
+
+#[tokio::test]
+async fn serves_the_recent_readings() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_VERY_LOW).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { unit: GlucoseUnit::MmolL, ..h.config() }).await;
+    let addr = control::spawn("127.0.0.1:0", app.shared.clone()).await.unwrap();
+
+    // Nothing has been fetched yet
+    let response = reqwest::get(format!("http://{addr}/glucose")).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+    app.tick().await;
+    app.tick().await;
+    let latest: serde_json::Value = reqwest::get(format!("http://{addr}/glucose")).await.unwrap().json().await.unwrap();
+    assert_eq!(latest["value_mgdl"], 50);
+    assert_eq!(latest["value"], 2.8);
+    assert_eq!(latest["unit"], "mmol/L");
+    assert_eq!(latest["is_stale"], false);
+    assert!(latest["age_seconds"].as_i64().unwrap() > 0);
+
+    let history: Vec<serde_json::Value> = reqwest::get(format!("http://{addr}/history?hours=1")).await.unwrap().json().await.unwrap();
+    assert_eq!(history.iter().map(|r| r["value_mgdl"].as_u64().unwrap()).collect::<Vec<_>>(), vec![65, 50]);
+}
+
+#[tokio::test]
+async fn reports_stale_readings() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { stale_after_minutes: 15, ..h.config() }).await;
+    let addr = control::spawn("127.0.0.1:0", app.shared.clone()).await.unwrap();
+
+    // There's no reading yet, so nothing is live
+    let metrics = reqwest::get(format!("http://{addr}/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("\nglucose_is_stale 1\n"), "{metrics}");
+    assert!(!metrics.contains("glucose_value_mgdl"));
+
+    // The fixture is years old
+    app.tick().await;
+    let latest: serde_json::Value = reqwest::get(format!("http://{addr}/glucose")).await.unwrap().json().await.unwrap();
+    assert_eq!(latest["is_stale"], true);
+    let metrics = reqwest::get(format!("http://{addr}/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("\nglucose_value_mgdl 112\n"), "{metrics}");
+    assert!(metrics.contains("\nglucose_is_stale 1\n"), "{metrics}");
+}
+
+#[tokio::test]
+async fn serves_the_followed_accounts() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+    let other = Config { dexcom_username: "other".to_string(), ..h.config() };
+    app.followed.push(("kid".to_string(), Box::new(h.dexcom_api(&other).await)));
+    let addr = control::spawn("127.0.0.1:0", app.shared.clone()).await.unwrap();
+
+    app.tick().await;
+    let accounts: serde_json::Value = reqwest::get(format!("http://{addr}/accounts")).await.unwrap().json().await.unwrap();
+    assert_eq!(accounts["kid"]["value_mgdl"], 112);
+
+    let metrics = reqwest::get(format!("http://{addr}/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("\nglucose_value_mgdl 112\n"), "{metrics}");
+    assert!(metrics.contains("\nglucose_value_mgdl{account=\"kid\"} 112\n"), "{metrics}");
+}
+
+#[tokio::test]
+async fn summarizes_the_latest_reading_for_the_slash_command() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+    assert_eq!(app.shared.latest_summary(GlucoseUnit::MgDl, 0, TrendDisplay::TextArrow), "There's no glucose reading yet");
+
+    app.tick().await;
+    let summary = app.shared.latest_summary(GlucoseUnit::MgDl, 0, TrendDisplay::TextArrow);
+    assert!(summary.starts_with("112 mg/dL → ("), "{summary}");
+    assert!(summary.ends_with(" min ago)"), "{summary}");
+}
+
+#[tokio::test]
+async fn calibrated_readings_keep_the_raw_value() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { glucose_offset: 10, ..h.config() }).await;
+    let addr = control::spawn("127.0.0.1:0", app.shared.clone()).await.unwrap();
+    app.tick().await;
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (122 mg/dL)"]);
+
+    let latest: serde_json::Value = reqwest::get(format!("http://{addr}/glucose")).await.unwrap().json().await.unwrap();
+    assert_eq!(latest["value_mgdl"], 122);
+    assert_eq!(latest["raw_value_mgdl"], 112);
+    let metrics = reqwest::get(format!("http://{addr}/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("\nglucose_raw_value_mgdl 112\n"), "{metrics}");
+}
+
+#[tokio::test]
+async fn interpolation_adds_a_clamped_estimate() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_VERY_LOW).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { interpolate: true, ..h.config() }).await;
+    let addr = control::spawn("127.0.0.1:0", app.shared.clone()).await.unwrap();
+
+    // A single reading has no rate to extrapolate
+    app.tick().await;
+    let latest: serde_json::Value = reqwest::get(format!("http://{addr}/glucose")).await.unwrap().json().await.unwrap();
+    assert!(latest.get("estimate").is_none());
+
+    // Dropping 1.5 mg/dL per minute, for at most 5 minutes past the years old reading
+    app.tick().await;
+    let latest: serde_json::Value = reqwest::get(format!("http://{addr}/glucose")).await.unwrap().json().await.unwrap();
+    assert_eq!(latest["value_mgdl"], 50);
+    assert_eq!(latest["estimate"]["value_mgdl"], 43);
+    assert_eq!(latest["estimate"]["minutes_ahead"], 5.0);
+    // The status still shows the reading itself
+    assert!(h.sent_statuses().await.last().unwrap().ends_with("(50 mg/dL)"));
+}
+
+#[tokio::test]
+async fn the_endpoint_can_use_its_own_unit() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { control_unit: Some(GlucoseUnit::MmolL), ..h.config() }).await;
+    let addr = control::spawn("127.0.0.1:0", app.shared.clone()).await.unwrap();
+
+    app.tick().await;
+    let latest: serde_json::Value = reqwest::get(format!("http://{addr}/glucose")).await.unwrap().json().await.unwrap();
+    assert_eq!(latest["value"], 6.2);
+    assert_eq!(latest["unit"], "mmol/L");
+    // Discord keeps the global unit
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)"]);
+    assert!(app.heartbeat(&TickOutcome::Updated).unwrap().starts_with("Heartbeat: 112 mg/dL"));
+}
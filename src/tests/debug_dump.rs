@@ -0,0 +1,34 @@
+//
+// End-to-end tests of the dexcom debug dumps
+//
+
+use std::fs;
+use crate::dexcom;
+use super::{fixtures, Harness};
+
+#[tokio::test]
+async fn dumps_redacted_response_bodies() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+
+    let mut config = h.config();
+    config.debug_dump = true;
+    let mut api = h.dexcom_api(&config).await;
+    api.get_latest_glucose().await.unwrap();
+
+    let dumps: Vec<String> = fs::read_dir(h.dump_dir()).unwrap()
+    .map(|e| fs::read_to_string(e.unwrap().path()).unwrap())
+    .collect();
+
+    // The account ID, session ID, and glucose responses are dumped
+    assert_eq!(dumps.len(), 3);
+    assert!(dumps.iter().any(|d| d.contains("\"Value\": 112")));
+
+    // None of the credentials are
+    let account_id: String = serde_json::from_str(fixtures::ACCOUNT_ID).unwrap();
+    let session_id: String = serde_json::from_str(fixtures::SESSION_ID).unwrap();
+    for dump in &dumps {
+        assert!(!dump.contains(&account_id) && !dump.contains(&session_id) && !dump.contains(&config.dexcom_password));
+    }
+}
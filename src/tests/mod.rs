@@ -0,0 +1,341 @@
+//
+// A test harness that stands in for the Dexcom and Discord APIs using mock HTTP servers
+//
+
+mod cache;
+mod control;
+mod debug_dump;
+mod tick;
+mod uploads;
+
+use std::{path::PathBuf, time::Duration};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use prost::Message;
+use tempfile::TempDir;
+use wiremock::{matchers::{method, path}, Mock, MockServer, ResponseTemplate};
+use crate::{app::App, dexcom, dexcom_oauth, discord, librelinkup, nightscout, preloaded_user_settings::{CustomStatus, StatusSettings}, source::{GlucoseSource, SourceKind}, Config, PreloadedUserSettings};
+
+/// Recorded API responses
+pub mod fixtures {
+    /// A successful account ID response
+    pub const ACCOUNT_ID: &str = include_str!("fixtures/account_id.json");
+    /// A successful session ID response
+    pub const SESSION_ID: &str = include_str!("fixtures/session_id.json");
+    /// A glucose response with a single in-range measurement (112 mg/dL)
+    pub const GLUCOSE: &str = include_str!("fixtures/glucose.json");
+    /// A glucose response with a single low measurement (65 mg/dL)
+    pub const GLUCOSE_LOW: &str = include_str!("fixtures/glucose_low.json");
+    /// A glucose response with a single very low measurement (50 mg/dL), 10 minutes after the others
+    pub const GLUCOSE_VERY_LOW: &str = include_str!("fixtures/glucose_very_low.json");
+    /// The same measurement as `GLUCOSE`, but with timestamps that drifted by a couple of seconds
+    pub const GLUCOSE_WOBBLE: &str = include_str!("fixtures/glucose_wobble.json");
+    /// A glucose response with a single bogus measurement (1023 mg/dL)
+    pub const GLUCOSE_BOGUS: &str = include_str!("fixtures/glucose_bogus.json");
+    /// A glucose response with three measurements, 5 minutes apart (101, 108, then 112 mg/dL)
+    pub const GLUCOSE_HISTORY: &str = include_str!("fixtures/glucose_history.json");
+    /// A glucose response without any measurements
+    pub const GLUCOSE_EMPTY: &str = include_str!("fixtures/glucose_empty.json");
+    /// An error response for an expired session
+    pub const SESSION_INVALID: &str = include_str!("fixtures/session_invalid.json");
+    /// An error response for an invalid password
+    pub const INVALID_PASSWORD: &str = include_str!("fixtures/invalid_password.json");
+    /// A successful LibreLinkUp login response
+    pub const LLU_LOGIN: &str = include_str!("fixtures/llu_login.json");
+    /// A LibreLinkUp connections response with a single in-range measurement (98 mg/dL)
+    pub const LLU_CONNECTIONS: &str = include_str!("fixtures/llu_connections.json");
+    /// A successful Dexcom OAuth token response
+    pub const OAUTH_TOKEN: &str = include_str!("fixtures/oauth_token.json");
+    /// An official Dexcom API response with two readings, the newest being 112 mg/dL
+    pub const OAUTH_EGVS: &str = include_str!("fixtures/oauth_egvs.json");
+    /// A Discord captcha challenge response
+    pub const DISCORD_CAPTCHA: &str = include_str!("fixtures/discord_captcha.json");
+}
+
+/// Mock Dexcom, LibreLinkUp, Discord, alert webhook, and Nightscout servers, plus a temporary directory for the API cache and debug dumps
+pub struct Harness {
+    /// The mock Dexcom share API
+    pub dexcom: MockServer,
+    /// The mock LibreLinkUp API
+    pub librelinkup: MockServer,
+    /// The mock Discord API
+    pub discord: MockServer,
+    /// The mock alert webhook
+    pub webhook: MockServer,
+    /// The mock Nightscout site
+    pub nightscout: MockServer,
+    /// The directory holding the API cache file (deleted when the harness is dropped)
+    cache_dir: TempDir
+}
+impl Harness {
+    /// Starts the mock servers. Nothing is mounted on them yet.
+    pub async fn start() -> Self {
+        Self {
+            dexcom: MockServer::start().await,
+            librelinkup: MockServer::start().await,
+            discord: MockServer::start().await,
+            webhook: MockServer::start().await,
+            nightscout: MockServer::start().await,
+            cache_dir: TempDir::new().unwrap()
+        }
+    }
+
+    /// Mounts successful account ID and session ID responses on the Dexcom server
+    pub async fn mount_dexcom_auth(&self) {
+        self.mount_dexcom(dexcom::ACCOUNT_ID_PATH, 200, fixtures::ACCOUNT_ID).await;
+        self.mount_dexcom(dexcom::SESSION_ID_PATH, 200, fixtures::SESSION_ID).await;
+    }
+
+    /// Mounts a response for an endpoint on the Dexcom server
+    pub async fn mount_dexcom(&self, endpoint: &str, status: u16, body: &str) {
+        Mock::given(method("POST"))
+        .and(path(endpoint))
+        .respond_with(ResponseTemplate::new(status).set_body_raw(body, "application/json"))
+        .mount(&self.dexcom).await;
+    }
+
+    /// Mounts a response for an endpoint on the Dexcom server that is only returned once
+    pub async fn mount_dexcom_once(&self, endpoint: &str, status: u16, body: &str) {
+        Mock::given(method("POST"))
+        .and(path(endpoint))
+        .respond_with(ResponseTemplate::new(status).set_body_raw(body, "application/json"))
+        .up_to_n_times(1)
+        .mount(&self.dexcom).await;
+    }
+
+    /// Mounts successful login and connections responses on the LibreLinkUp server
+    pub async fn mount_librelinkup(&self) {
+        Mock::given(method("POST"))
+        .and(path(librelinkup::LOGIN_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(fixtures::LLU_LOGIN, "application/json"))
+        .mount(&self.librelinkup).await;
+        Mock::given(method("GET"))
+        .and(path(librelinkup::CONNECTIONS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(fixtures::LLU_CONNECTIONS, "application/json"))
+        .mount(&self.librelinkup).await;
+    }
+
+    /// Mounts successful token and readings responses of the official API on the Dexcom server
+    pub async fn mount_dexcom_oauth(&self) {
+        Mock::given(method("POST"))
+        .and(path(dexcom_oauth::TOKEN_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(fixtures::OAUTH_TOKEN, "application/json"))
+        .mount(&self.dexcom).await;
+        Mock::given(method("GET"))
+        .and(path(dexcom_oauth::EGVS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(fixtures::OAUTH_EGVS, "application/json"))
+        .mount(&self.dexcom).await;
+    }
+
+    /// Mounts a response for status updates on the Discord server
+    pub async fn mount_discord_status(&self, status: u16) {
+        self.mount_discord_status_body(status, "{}").await;
+    }
+
+    /// Mounts a response with a body for status updates on the Discord server
+    pub async fn mount_discord_status_body(&self, status: u16, body: &str) {
+        Mock::given(method("PATCH"))
+        .and(path(discord::PROTO_SETTINGS_PATH))
+        .respond_with(ResponseTemplate::new(status).set_body_raw(body, "application/json"))
+        .mount(&self.discord).await;
+    }
+
+    /// Mounts a response for bio updates on the Discord server
+    pub async fn mount_discord_bio(&self, status: u16) {
+        Mock::given(method("PATCH"))
+        .and(path(discord::PROFILE_PATH))
+        .respond_with(ResponseTemplate::new(status).set_body_string("{}"))
+        .mount(&self.discord).await;
+    }
+
+    /// Returns the bios the Discord server received, in order
+    pub async fn sent_bios(&self) -> Vec<String> {
+        self.discord.received_requests().await.unwrap()
+        .iter()
+        .filter(|r| r.url.path() == discord::PROFILE_PATH)
+        .map(|r| {
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            body["bio"].as_str().unwrap().to_string()
+        })
+        .collect()
+    }
+
+    /// Mounts a settings response with the provided custom status text on the Discord server
+    pub async fn mount_discord_settings(&self, text: &str) {
+        let settings = PreloadedUserSettings {
+            status: Some(StatusSettings {
+                custom_status: Some(CustomStatus { text: text.to_string(), ..Default::default() }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let body = serde_json::json!({ "settings": STANDARD.encode(settings.encode_to_vec()) });
+
+        Mock::given(method("GET"))
+        .and(path(discord::PROTO_SETTINGS_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(&self.discord).await;
+    }
+
+    /// Mounts a successful response on the alert webhook server
+    pub async fn mount_webhook(&self) {
+        Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&self.webhook).await;
+    }
+
+    /// Mounts the entries the Nightscout site already has, and successful responses for uploads
+    pub async fn mount_nightscout(&self, existing: serde_json::Value) {
+        Mock::given(method("GET"))
+        .and(path(nightscout::ENTRIES_JSON_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(existing))
+        .mount(&self.nightscout).await;
+        Mock::given(method("POST"))
+        .and(path(nightscout::ENTRIES_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_string("[]"))
+        .mount(&self.nightscout).await;
+    }
+
+    /// Returns the entries the Nightscout site received, in order
+    pub async fn uploaded_entries(&self) -> Vec<serde_json::Value> {
+        self.nightscout.received_requests().await.unwrap()
+        .iter()
+        .filter(|r| r.method == wiremock::http::Method::POST)
+        .flat_map(|r| serde_json::from_slice::<Vec<serde_json::Value>>(&r.body).unwrap())
+        .collect()
+    }
+
+    /// Returns a config pointing at the mock servers
+    pub fn config(&self) -> Config {
+        Config {
+            dexcom_username: "username".to_string(),
+            dexcom_password: "password".to_string(),
+            discord_token: "token".to_string(),
+            librelinkup_email: "email@example.com".to_string(),
+            librelinkup_password: "password".to_string(),
+            dexcom_base_url: self.dexcom.uri(),
+            librelinkup_base_url: self.librelinkup.uri(),
+            discord_base_url: self.discord.uri(),
+            alert_webhook_url: Some(self.webhook.uri()),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a Dexcom API instance connected to the mock server
+    ///
+    /// - NOTE: The auth responses must be mounted first
+    pub async fn dexcom_api(&self, config: &Config) -> dexcom::Api {
+        dexcom::Api::new(&config.dexcom_username, &config.dexcom_password, self.dexcom_options(config)).await.unwrap()
+    }
+
+    /// Returns the options for a Dexcom API instance connected to the mock server
+    ///
+    /// - NOTE: Debug dumps are written to the `debug_dump` directory in the temporary directory
+    pub fn dexcom_options(&self, config: &Config) -> dexcom::Options {
+        dexcom::Options {
+            base_url: config.dexcom_base_url.clone(),
+            cache_path: self.cache_dir.path().join("api_cache.json"),
+            proxy: None,
+            dump_dir: config.debug_dump.then(|| self.dump_dir()),
+            auth_retries: config.startup_retries,
+            auth_retry_delay: Duration::ZERO,
+            auth_timeout: Duration::from_secs(config.auth_timeout_secs),
+            retry_jitter: config.retry_jitter,
+            force_ipv4: config.force_ipv4,
+            max_session_age: (config.max_session_age_secs > 0).then(|| Duration::from_secs(config.max_session_age_secs))
+        }
+    }
+
+    /// Returns the directory debug dumps are written to
+    pub fn dump_dir(&self) -> PathBuf {
+        self.cache_dir.path().join("debug_dump")
+    }
+
+    /// Creates the glucose sources selected in the config, connected to the mock servers
+    ///
+    /// - NOTE: The auth responses must be mounted first
+    pub async fn sources(&self, config: &Config) -> Vec<Box<dyn GlucoseSource>> {
+        let mut sources: Vec<Box<dyn GlucoseSource>> = Vec::new();
+
+        for kind in &config.sources {
+            match kind {
+                SourceKind::Dexcom => sources.push(Box::new(self.dexcom_api(config).await)),
+                SourceKind::LibreLinkUp => sources.push(Box::new(
+                    librelinkup::Api::new(&config.librelinkup_email, &config.librelinkup_password, librelinkup::Options {
+                        base_url: config.librelinkup_base_url.clone(),
+                        proxy: None
+                    }).unwrap()
+                )),
+                SourceKind::DexcomOauth => sources.push(Box::new(
+                    dexcom_oauth::Api::new(
+                        &config.dexcom_oauth_client_id,
+                        &config.dexcom_oauth_client_secret,
+                        &config.dexcom_oauth_refresh_token,
+                        dexcom_oauth::Options {
+                            base_url: self.dexcom.uri(),
+                            redirect_uri: config.dexcom_oauth_redirect_uri.clone(),
+                            token_path: Some(self.cache_dir.path().join("dexcom_oauth_token")),
+                            proxy: None
+                        }
+                    ).unwrap()
+                ))
+            }
+        }
+
+        sources
+    }
+
+    /// Creates an app connected to the mock servers
+    ///
+    /// - NOTE: The auth responses must be mounted first
+    pub async fn app(&self, config: Config) -> App {
+        let sources = self.sources(&config).await;
+        let discord_api = self.discord_api(&config).await;
+
+        App::new(config, sources, discord_api)
+    }
+
+    /// Creates a Discord API instance connected to the mock server
+    pub async fn discord_api(&self, config: &Config) -> discord::Api {
+        discord::Api::new(&config.discord_token, config.discord_options().unwrap()).await
+    }
+
+    /// Returns the number of requests the Dexcom server received for an endpoint
+    pub async fn dexcom_requests(&self, endpoint: &str) -> usize {
+        self.dexcom.received_requests().await.unwrap()
+        .iter()
+        .filter(|r| r.url.path() == endpoint)
+        .count()
+    }
+
+    /// Returns the alerts the webhook server received, in order
+    pub async fn sent_alerts(&self) -> Vec<serde_json::Value> {
+        self.webhook.received_requests().await.unwrap()
+        .iter()
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect()
+    }
+
+    /// Returns the custom status texts the Discord server received, in order
+    pub async fn sent_statuses(&self) -> Vec<String> {
+        self.sent_custom_statuses().await
+        .into_iter()
+        .map(|s| s.text)
+        .collect()
+    }
+
+    /// Returns the custom statuses the Discord server received, in order
+    pub async fn sent_custom_statuses(&self) -> Vec<CustomStatus> {
+        self.discord.received_requests().await.unwrap()
+        .iter()
+        .filter(|r| r.url.path() == discord::PROTO_SETTINGS_PATH && r.method == wiremock::http::Method::PATCH)
+        .map(|r| {
+            // Decode the base64 protobuf settings packet
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            let packet = STANDARD.decode(body["settings"].as_str().unwrap()).unwrap();
+            let settings = PreloadedUserSettings::decode(packet.as_slice()).unwrap();
+
+            settings.status.unwrap().custom_status.unwrap()
+        })
+        .collect()
+    }
+}
@@ -0,0 +1,909 @@
+//
+// End-to-end tests of the loop body
+//
+
+use std::time::Duration;
+use chrono::TimeDelta;
+use wiremock::{matchers::{header, method, path}, Mock, ResponseTemplate};
+use crate::{alert, app::{ExtraAccount, LoopTiming, MissedTicks, Schedule, TickOutcome}, dexcom::{self, GlucoseMeasurement}, discord::{self, Emoji}, librelinkup, source::{GlucoseSource, SourceKind}, Config};
+use super::{fixtures, Harness};
+
+#[tokio::test]
+async fn updates_status_with_latest_glucose() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)"]);
+}
+
+#[tokio::test]
+async fn skips_update_without_measurement() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_EMPTY).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert!(h.sent_statuses().await.is_empty());
+}
+
+#[tokio::test]
+async fn shows_warmup_status_once_without_data() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_EMPTY).await;
+    h.mount_discord_status(200).await;
+
+    let mut config = h.config();
+    config.warmup_after_minutes = 0;
+    let warmup_status = config.warmup_status.clone();
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(h.sent_statuses().await, vec![warmup_status]);
+}
+
+#[tokio::test]
+async fn suggests_enabling_share_without_any_readings() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_EMPTY).await;
+    h.mount_discord_status(200).await;
+
+    let config = Config { share_disabled_after_empty: 2, ..h.config() };
+    let share_disabled_status = config.share_disabled_status.clone();
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(h.sent_statuses().await, vec![share_disabled_status]);
+}
+
+#[tokio::test]
+async fn skips_bogus_readings_until_they_persist() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_BOGUS).await;
+    h.mount_discord_status(200).await;
+
+    let mut config = h.config();
+    config.sanity_error_threshold = 2;
+    let sensor_error_status = config.sensor_error_status.clone();
+    let mut app = h.app(config).await;
+
+    // A single glitch is ignored, but a persistent one is shown
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec![sensor_error_status]);
+}
+
+#[tokio::test]
+async fn renews_expired_session_and_retries() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::SESSION_INVALID).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+
+    // The first tick renews the session and asks to be retried without touching discord
+    assert_eq!(app.tick().await, TickOutcome::RetryNow);
+    assert_eq!(h.dexcom_requests(dexcom::SESSION_ID_PATH).await, 2);
+    assert!(h.sent_statuses().await.is_empty());
+
+    // The retry succeeds with the new session
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)"]);
+}
+
+#[tokio::test]
+async fn shows_cgm_error_status_on_dexcom_error() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::INVALID_PASSWORD).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+
+    // The first failure keeps the last status, in case it's a one-off
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert!(h.sent_statuses().await.is_empty());
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["Tell me to change my cgm"]);
+}
+
+#[tokio::test]
+async fn skips_when_discord_rejects_update() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(401).await;
+
+    let mut app = h.app(h.config()).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(h.sent_statuses().await.len(), 1);
+}
+
+#[tokio::test]
+async fn halts_when_discord_wants_a_captcha() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status_body(403, fixtures::DISCORD_CAPTCHA).await;
+
+    let mut app = h.app(h.config()).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Halt);
+}
+
+#[tokio::test]
+async fn user_agent_spoofing_can_be_disabled() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut config = h.config();
+    config.discord_user_agent = "none".to_string();
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    let requests = h.discord.received_requests().await.unwrap();
+    assert!(!requests[0].headers.get("user-agent").is_some_and(|ua| ua.to_str().unwrap().contains("Firefox")));
+}
+
+#[tokio::test]
+async fn sends_bucket_emoji_with_status() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut config = h.config();
+    for bucket in &mut config.buckets {
+        bucket.emoji = Some(Emoji::Unicode("🟢".to_string()));
+    }
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    let sent = h.sent_custom_statuses().await;
+    assert_eq!(sent[0].emoji_name, "🟢");
+    assert_eq!(sent[0].emoji_id, 0);
+}
+
+#[tokio::test]
+async fn librelinkup_only_follows_real_redirects_to_plain_regions() {
+    for data in [r#"{ "redirect": true, "region": "evil.example.com/" }"#, r#"{ "redirect": false, "region": "eu" }"#] {
+        let h = Harness::start().await;
+        h.mount_dexcom_auth().await;
+        Mock::given(method("POST"))
+        .and(path(librelinkup::LOGIN_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(format!(r#"{{ "status": 0, "data": {data} }}"#), "application/json"))
+        .mount(&h.librelinkup).await;
+
+        // The credentials aren't sent anywhere else, and the login fails instead
+        let mut app = h.app(Config { sources: vec![SourceKind::LibreLinkUp], ..h.config() }).await;
+        assert_eq!(app.tick().await, TickOutcome::Skipped);
+        assert_eq!(h.librelinkup.received_requests().await.unwrap().len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn falls_back_to_next_source_on_failure() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::INVALID_PASSWORD).await;
+    h.mount_librelinkup().await;
+    h.mount_discord_status(200).await;
+
+    let mut config = h.config();
+    config.sources = vec![SourceKind::Dexcom, SourceKind::LibreLinkUp];
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (98 mg/dL)"]);
+}
+
+#[tokio::test]
+async fn falls_back_to_next_source_once_the_session_retries_run_out() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::SESSION_INVALID).await;
+    h.mount_librelinkup().await;
+    h.mount_discord_status(200).await;
+
+    let config = Config { sources: vec![SourceKind::Dexcom, SourceKind::LibreLinkUp], max_session_retries: 1, ..h.config() };
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::RetryNow);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (98 mg/dL)"]);
+    assert_eq!(app.state.session_retries, 0);
+}
+
+#[tokio::test]
+async fn clears_status_once_while_in_range() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut config = h.config();
+    config.only_alert_out_of_range = true;
+    let mut app = h.app(config).await;
+
+    // The first in-range reading clears the status, and the next one leaves it alone
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(h.sent_statuses().await, vec![""]);
+}
+
+#[tokio::test]
+async fn retries_update_that_did_not_take_effect() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    h.mount_discord_settings("Some other status").await;
+
+    let mut config = h.config();
+    config.verify_updates = true;
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)", "We chillin (112 mg/dL)"]);
+}
+
+#[tokio::test]
+async fn does_not_retry_verified_update() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    h.mount_discord_settings("We chillin (112 mg/dL)").await;
+
+    let mut config = h.config();
+    config.verify_updates = true;
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await.len(), 1);
+}
+
+#[tokio::test]
+async fn snoozes_repeated_alerts() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let mut app = h.app(Config { dedupe_readings: false, ..h.config() }).await;
+
+    // The second low is snoozed
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    let alerts = h.sent_alerts().await;
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0]["value"], 65);
+    assert_eq!(alerts[0]["level"], -1);
+}
+
+#[tokio::test]
+async fn alerts_include_change_since_last_alert() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_VERY_LOW).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let mut app = h.app(h.config()).await;
+
+    // Dropping into a more severe bucket isn't snoozed
+    app.tick().await;
+    app.tick().await;
+    let alerts = h.sent_alerts().await;
+    assert_eq!(alerts[0]["previous_value"], serde_json::Value::Null);
+    assert_eq!(alerts[1]["previous_value"], 65);
+    assert_eq!(alerts[1]["delta"], -15);
+    assert_eq!(alerts[1]["elapsed_minutes"], 10);
+    assert_eq!(alerts[1]["rate"], -1.5);
+}
+
+#[tokio::test]
+async fn does_not_alert_while_in_range() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let mut app = h.app(h.config()).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert!(h.sent_alerts().await.is_empty());
+}
+
+#[tokio::test]
+async fn updates_bio_independently_of_status() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    h.mount_discord_bio(500).await;
+
+    let mut config = h.config();
+    config.update_bio = true;
+    config.bio_template = Some("Currently {value} mg/dL".to_string());
+    let mut app = h.app(config).await;
+
+    // The failed bio update doesn't stop the status update
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_bios().await, vec!["Currently 112 mg/dL"]);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)"]);
+}
+
+/// A glucose source that panics whenever it's queried
+struct PanickingSource;
+#[async_trait::async_trait]
+impl GlucoseSource for PanickingSource {
+    fn name(&self) -> &'static str {
+        "panicking"
+    }
+
+    async fn get_latest_glucose(&mut self) -> anyhow::Result<Option<GlucoseMeasurement>> {
+        panic!("unexpected response");
+    }
+}
+
+#[tokio::test]
+async fn resilient_tick_survives_a_panic() {
+    let h = Harness::start().await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { sources: vec![], ..h.config() }).await;
+    app.sources.push(Box::new(PanickingSource));
+
+    assert_eq!(app.tick_guarded().await, TickOutcome::Skipped);
+    assert_eq!(app.state.consecutive_failures, 1);
+    assert!(h.sent_statuses().await.is_empty());
+}
+
+#[tokio::test]
+async fn skips_readings_that_were_already_shown() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::INVALID_PASSWORD).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::INVALID_PASSWORD).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+
+    // The same reading isn't shown twice in a row
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+
+    // But it's shown again once an error status replaced it
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)", "Tell me to change my cgm", "We chillin (112 mg/dL)"]);
+}
+
+#[tokio::test]
+async fn updates_stale_readings_when_the_marker_changes() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { stale_after_minutes: 15, ..h.config() }).await;
+
+    // The fixture is years old, so it's always stale
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    let status = h.sent_statuses().await.pop().unwrap();
+    assert!(status.starts_with("We chillin (112 mg/dL) (stale, "), "{status}");
+
+    // Nothing changed within the same minute
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+
+    // But the same reading is shown again once the marker counts up
+    app.state.last_status = Some("We chillin (112 mg/dL) (stale, 15 min)".to_string());
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await.len(), 2);
+}
+
+#[tokio::test]
+async fn reminds_to_retest_until_back_in_range() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_VERY_LOW).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let mut app = h.app(Config { retest_reminder_minutes: 15, ..h.config() }).await;
+    for _ in 0..3 {
+        assert_eq!(app.tick().await, TickOutcome::Updated);
+    }
+
+    // The reminder counts from when the low started, not from the latest reading
+    assert_eq!(h.sent_statuses().await, vec![
+        "Tell me to eat something, I'm a little low (65 mg/dL) (retest at 14:35)",
+        "⚠️ LOW I'm in sugar withdrawls, send help (50 mg/dL) (retest at 14:35)",
+        "We chillin (112 mg/dL)"
+    ]);
+}
+
+#[tokio::test]
+async fn gives_up_on_sessions_that_keep_expiring() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::SESSION_INVALID).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { max_session_retries: 1, retry_jitter: false, ..h.config() }).await;
+
+    assert_eq!(app.tick().await, TickOutcome::RetryNow);
+    assert_eq!(app.tick().await, TickOutcome::MaxRetriesReached);
+    assert!(app.poll_interval() > std::time::Duration::from_secs(300));
+    assert!(h.sent_statuses().await.is_empty());
+}
+
+#[tokio::test]
+async fn updates_every_discord_account() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+    app.extra_discord_apis.push(ExtraAccount::new(h.discord_api(&Config { discord_token: "alt token".to_string(), ..h.config() }).await));
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)", "We chillin (112 mg/dL)"]);
+    let mut tokens: Vec<_> = h.discord.received_requests().await.unwrap()
+    .iter()
+    .map(|r| r.headers["authorization"].to_str().unwrap().to_string())
+    .collect();
+    tokens.sort();
+    assert_eq!(tokens, vec!["alt token", "token"]);
+}
+
+#[tokio::test]
+async fn a_rate_limited_extra_account_is_paused_on_its_own() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    Mock::given(method("PATCH"))
+    .and(path(discord::PROTO_SETTINGS_PATH))
+    .and(header("authorization", "alt token"))
+    .respond_with(ResponseTemplate::new(429).set_body_raw(r#"{"message": "You are being rate limited.", "retry_after": 5.0}"#, "application/json"))
+    .mount(&h.discord).await;
+    h.mount_discord_status(200).await;
+
+    let config = Config { rate_limit_pause_after: 2, dedupe_readings: false, ..h.config() };
+    let mut app = h.app(config).await;
+    app.extra_discord_apis.push(ExtraAccount::new(h.discord_api(&Config { discord_token: "alt token".to_string(), ..h.config() }).await));
+    for _ in 0..4 {
+        assert_eq!(app.tick().await, TickOutcome::Updated);
+    }
+
+    // The main account keeps being updated while the other one cools down
+    let tokens: Vec<_> = h.discord.received_requests().await.unwrap()
+    .iter()
+    .map(|r| r.headers["authorization"].to_str().unwrap().to_string())
+    .collect();
+    assert_eq!(tokens.iter().filter(|t| *t == "token").count(), 4);
+    assert_eq!(tokens.iter().filter(|t| *t == "alt token").count(), 2);
+    assert!(app.state.discord_paused_until.is_none());
+}
+
+#[tokio::test]
+async fn uses_the_configured_settings_proto_version() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    Mock::given(method("PATCH"))
+    .and(path(discord::proto_settings_path(2)))
+    .respond_with(ResponseTemplate::new(200).set_body_string("{}"))
+    .mount(&h.discord).await;
+
+    let mut app = h.app(Config { discord_settings_proto_version: 2, ..h.config() }).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert!(h.sent_statuses().await.is_empty());
+}
+
+#[tokio::test]
+async fn never_touches_discord_when_disabled() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_webhook().await;
+
+    let mut app = h.app(Config { discord_enabled: false, discord_token: String::new(), ..h.config() }).await;
+
+    // The other outputs still get the reading
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_alerts().await.len(), 1);
+    assert!(h.discord.received_requests().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn leaves_manual_statuses_alone_until_the_bucket_changes() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_discord_status(200).await;
+    h.mount_discord_settings("At the gym").await;
+    h.mount_webhook().await;
+
+    let mut app = h.app(Config { respect_manual_status: true, dedupe_readings: false, ..h.config() }).await;
+
+    // The status doesn't match what we sent, so it was changed by hand
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+
+    // Until the bucket changes
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)", "Tell me to eat something, I'm a little low (65 mg/dL)"]);
+}
+
+#[tokio::test]
+async fn status_script_replaces_the_template() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    let path = h.cache_dir.path().join("status.rhai");
+    std::fs::write(&path, r#"`${value} and ${trend}`"#).unwrap();
+
+    let mut app = h.app(Config { status_script: Some(path), status_prefix: "🩸 ".to_string(), ..h.config() }).await;
+
+    // The script only replaces the template, so the prefix is still added
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["🩸 112 and Flat"]);
+}
+
+#[tokio::test]
+async fn waits_for_a_new_reading_when_not_updating_on_start() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_VERY_LOW).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let mut app = h.app(Config { update_on_start: false, ..h.config() }).await;
+
+    // The reading that was there at startup is only alerted
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(h.sent_alerts().await.len(), 1);
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await.len(), 1);
+}
+
+#[tokio::test]
+async fn entering_a_bucket_uses_its_entering_template() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_VERY_LOW).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let buckets = serde_json::from_value(serde_json::json!([
+        { "max": 80, "level": -1, "template": "Still low ({value})", "entering_template": "Going low! ({value})" },
+        { "min": 80, "level": 0, "template": "Fine ({value})" }
+    ])).unwrap();
+    let mut app = h.app(Config { buckets, ..h.config() }).await;
+    for _ in 0..3 {
+        assert_eq!(app.tick().await, TickOutcome::Updated);
+    }
+
+    // Only the reading that crossed into the bucket uses the louder template
+    assert_eq!(h.sent_statuses().await, vec![
+        "Fine (112)",
+        "⚠️ LOW Going low! (50)",
+        "Still low (65)"
+    ]);
+}
+
+#[tokio::test]
+async fn ipv4_only_clients_still_reach_the_apis() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { force_ipv4: true, ..h.config() }).await;
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)"]);
+}
+
+#[tokio::test]
+async fn instant_session_retries_are_rate_limited() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::SESSION_INVALID).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::SESSION_INVALID).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(h.config()).await;
+
+    // The second expiry comes too soon after the first, so it waits for the usual sleep
+    assert_eq!(app.tick().await, TickOutcome::RetryNow);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+
+    // Without the cap, every expiry is retried right away
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::SESSION_INVALID).await;
+    let mut app = h.app(Config { fast_retry_min_interval_secs: 0, ..h.config() }).await;
+    assert_eq!(app.tick().await, TickOutcome::RetryNow);
+    assert_eq!(app.tick().await, TickOutcome::RetryNow);
+}
+
+#[tokio::test]
+async fn coming_back_into_range_shows_the_comeback_template_once() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_VERY_LOW).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let comeback_template = Some("Back in range ✅ ({value} {unit})".to_string());
+    let mut app = h.app(Config { comeback_template, dedupe_readings: false, ..h.config() }).await;
+    for _ in 0..3 {
+        assert_eq!(app.tick().await, TickOutcome::Updated);
+    }
+
+    // Only the first update back in range is celebrated
+    assert_eq!(h.sent_statuses().await, vec![
+        "⚠️ LOW I'm in sugar withdrawls, send help (50 mg/dL)",
+        "Back in range ✅ (112 mg/dL)",
+        "We chillin (112 mg/dL)"
+    ]);
+}
+
+#[tokio::test]
+async fn official_api_source_saves_the_rotated_refresh_token() {
+    let h = Harness::start().await;
+    h.mount_dexcom_oauth().await;
+    h.mount_discord_status(200).await;
+
+    let config = Config {
+        sources: vec![SourceKind::DexcomOauth],
+        dexcom_oauth_client_id: "client".to_string(),
+        dexcom_oauth_client_secret: "secret".to_string(),
+        dexcom_oauth_refresh_token: "refresh-token".to_string(),
+        ..h.config()
+    };
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL)"]);
+
+    // The old refresh token stops working, so the new one is kept for the next run
+    let saved = std::fs::read_to_string(h.cache_dir.path().join("dexcom_oauth_token")).unwrap();
+    assert_eq!(saved, "rotated-refresh-token");
+}
+
+#[tokio::test]
+async fn heartbeat_says_whether_the_status_was_updated() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+
+    let mut app = h.app(Config { heartbeat_interval_secs: 3600, ..h.config() }).await;
+
+    let outcome = app.tick().await;
+    let heartbeat = app.heartbeat(&outcome).unwrap();
+    assert!(heartbeat.starts_with("Heartbeat: 112 mg/dL → ("), "{heartbeat}");
+    assert!(heartbeat.ends_with(", the status was updated"), "{heartbeat}");
+
+    // Skipped readings don't log until the interval passes, and then say nothing was sent
+    let outcome = app.tick().await;
+    assert_eq!(outcome, TickOutcome::Skipped);
+    assert_eq!(app.heartbeat(&outcome), None);
+    app.state.last_heartbeat_at = None;
+    let outcome = app.tick().await;
+    assert!(app.heartbeat(&outcome).unwrap().ends_with(", the status wasn't updated"));
+}
+
+#[tokio::test]
+async fn long_flatlines_are_marked_and_alerted_once() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let config = Config { flatline_minutes: 30, dedupe_readings: false, ..h.config() };
+    let mut app = h.app(config).await;
+    // Half an hour of readings within 2 mg/dL of the fixture's 112 mg/dL
+    for (minutes_before, value) in [(30, 111), (25, 113), (20, 112), (15, 112), (10, 113), (5, 111)] {
+        let date = format!("Date({})", 1723472400000i64 - minutes_before * 60_000);
+        app.state.push_reading(dexcom::GlucoseMeasurement { wt: date.clone(), st: date.clone(), dt: date, value, trend: "Flat".to_string(), raw_value: None }, TimeDelta::zero());
+    }
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["We chillin (112 mg/dL) (flatline?)"; 2]);
+    let alerts = h.sent_alerts().await;
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0]["flatline"], true);
+    assert_eq!(alerts[0]["level"], 0);
+}
+
+#[tokio::test]
+async fn cgm_error_status_can_show_the_error_kind() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 500, fixtures::INVALID_PASSWORD).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, "<html>Service Unavailable</html>").await;
+    h.mount_discord_status(200).await;
+
+    let config = Config { error_status: "Tell me to change my cgm ({error_kind})".to_string(), error_status_threshold: 1, ..h.config() };
+    let mut app = h.app(config).await;
+
+    app.tick().await;
+    app.tick().await;
+    assert_eq!(h.sent_statuses().await, vec!["Tell me to change my cgm (auth error)", "Tell me to change my cgm (error)"]);
+}
+
+#[tokio::test]
+async fn repeated_rate_limits_pause_the_discord_updates() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status_body(429, r#"{"message": "You are being rate limited.", "retry_after": 5.0}"#).await;
+
+    let config = Config { rate_limit_pause_after: 2, dedupe_readings: false, ..h.config() };
+    let mut app = h.app(config).await;
+    for _ in 0..4 {
+        assert_eq!(app.tick().await, TickOutcome::Skipped);
+    }
+
+    // The sources are still polled during the cooldown, but discord isn't
+    assert_eq!(h.dexcom_requests(dexcom::MEASURE_GLUCOSE_PATH).await, 4);
+    assert_eq!(h.sent_statuses().await.len(), 2);
+    assert!(app.state.discord_paused_until.is_some());
+}
+
+#[tokio::test]
+async fn readings_with_wobbling_timestamps_are_deduped() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_WOBBLE).await;
+    h.mount_discord_status(200).await;
+
+    // The second response is the same reading with timestamps a couple of seconds later
+    let mut app = h.app(h.config()).await;
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(app.state.readings.len(), 1);
+
+    // Without the tolerance, it counts as a new reading
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_WOBBLE).await;
+    h.mount_discord_status(200).await;
+    let mut app = h.app(Config { same_reading_tolerance_secs: 0, ..h.config() }).await;
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.state.readings.len(), 2);
+}
+
+#[tokio::test]
+async fn long_outages_take_the_status_down() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_EMPTY).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_EMPTY).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 500, "{}").await;
+    h.mount_discord_status(200).await;
+
+    let offline_status = Some("Sensor offline".to_string());
+    let mut app = h.app(Config { clear_after_no_data_secs: 3600, offline_status, ..h.config() }).await;
+    app.state.received_reading = true;
+    app.state.without_reading_since = Some(chrono::Utc::now() - TimeDelta::hours(2));
+
+    // The offline status is only sent once, and a reading brings the usual status back
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Skipped);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await, vec!["Sensor offline", "We chillin (112 mg/dL)"]);
+
+    // Failing sources count as an outage too, and without an offline status it's cleared
+    app.config.offline_status = None;
+    app.state.without_reading_since = Some(chrono::Utc::now() - TimeDelta::hours(2));
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.sent_statuses().await.last().unwrap(), "");
+}
+
+#[tokio::test]
+async fn alert_rules_are_tiered_and_snoozed_separately() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_dexcom_once(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_VERY_LOW).await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_LOW).await;
+    h.mount_discord_status(200).await;
+    h.mount_webhook().await;
+
+    let alert_rules = vec![
+        alert::AlertRule {
+            direction: alert::AlertDirection::Low,
+            threshold: 80,
+            severity: "heads-up".to_string(),
+            message: Some("Heads up, {value} {unit}".to_string()),
+            color: Some("#ffaa00".to_string())
+        },
+        alert::AlertRule { direction: alert::AlertDirection::Low, threshold: 55, severity: "urgent".to_string(), message: None, color: None }
+    ];
+    let mut app = h.app(Config { alert_rules, ..h.config() }).await;
+    for _ in 0..3 {
+        app.tick().await;
+    }
+
+    // Going back up to 65 mg/dL is still within the snoozed heads up rule, so it isn't alerted about again
+    let alerts = h.sent_alerts().await;
+    assert_eq!(alerts.len(), 2);
+    assert_eq!(alerts[0]["content"], "Heads up, 65 mg/dL");
+    assert_eq!(alerts[0]["severity"], "heads-up");
+    assert_eq!(alerts[0]["color"], "#ffaa00");
+    assert_eq!(alerts[1]["value"], 50);
+    assert_eq!(alerts[1]["severity"], "urgent");
+    assert!(alerts[1]["color"].is_null());
+    assert_eq!(app.state.rule_snoozes.keys().collect::<Vec<_>>(), vec![&0]);
+}
+
+#[tokio::test]
+async fn the_interval_still_backs_off_with_jitter() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    let mut app = h.app(Config { retry_jitter: true, ..h.config() }).await;
+    let schedule = Schedule::new(LoopTiming::Interval, MissedTicks::Skip);
+    assert_eq!(schedule.sleep_for(&app, Duration::ZERO, app.poll_interval()), None);
+
+    // The jittered interval can come out as short as the usual one, but the loop keeps sleeping for it instead of ticking
+    app.state.consecutive_failures = app.config.breaker_threshold;
+    for _ in 0..100 {
+        let poll_interval = app.poll_interval();
+        assert_eq!(schedule.sleep_for(&app, Duration::from_secs(1), poll_interval), Some(poll_interval - Duration::from_secs(1)));
+    }
+}
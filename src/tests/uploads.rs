@@ -0,0 +1,59 @@
+//
+// End-to-end tests of the Nightscout uploads
+//
+
+use serde_json::json;
+use crate::{app::TickOutcome, dexcom, Config};
+use super::{fixtures, Harness};
+
+#[tokio::test]
+async fn backfill_skips_existing_entries() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE_HISTORY).await;
+    h.mount_nightscout(json!([{ "date": 1723472100000i64 }])).await;
+
+    let config = Config { nightscout_url: Some(h.nightscout.uri()), nightscout_backfill_hours: 3, ..h.config() };
+    let mut app = h.app(config).await;
+    app.backfill_nightscout().await;
+
+    // The oldest reading is uploaded first, and the one nightscout already has is skipped
+    let entries = h.uploaded_entries().await;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["sgv"], 101);
+    assert_eq!(entries[0]["direction"], "NOT COMPUTABLE");
+    assert_eq!(entries[1]["sgv"], 112);
+    assert_eq!(entries[1]["dateString"], "2024-08-12T14:20:00.000Z");
+}
+
+#[tokio::test]
+async fn uploads_new_readings_once() {
+    let h = Harness::start().await;
+    h.mount_dexcom_auth().await;
+    h.mount_dexcom(dexcom::MEASURE_GLUCOSE_PATH, 200, fixtures::GLUCOSE).await;
+    h.mount_discord_status(200).await;
+    h.mount_nightscout(json!([])).await;
+
+    let config = Config {
+        nightscout_url: Some(h.nightscout.uri()),
+        nightscout_api_secret: Some("secret".to_string()),
+        dedupe_readings: false,
+        ..h.config()
+    };
+    let mut app = h.app(config).await;
+
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(app.tick().await, TickOutcome::Updated);
+    assert_eq!(h.uploaded_entries().await, vec![json!({
+        "type": "sgv",
+        "sgv": 112,
+        "date": 1723472400000i64,
+        "dateString": "2024-08-12T14:20:00.000Z",
+        "direction": "Flat",
+        "device": "dexcord"
+    })]);
+
+    // The secret is hashed before it's sent
+    let request = &h.nightscout.received_requests().await.unwrap()[0];
+    assert_eq!(request.headers["api-secret"], "e5e9fa1ba31ecd1ae84f75caaa474f3a663f05f4");
+}
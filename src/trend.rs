@@ -0,0 +1,287 @@
+//
+// Calculations over recent glucose readings (rate of change, estimates, etc.)
+//
+
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use crate::dexcom::{GlucoseMeasurement, TimestampSource};
+
+/// The direction the glucose is heading, as reported by the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trend {
+    DoubleUp,
+    SingleUp,
+    FortyFiveUp,
+    Flat,
+    FortyFiveDown,
+    SingleDown,
+    DoubleDown,
+    /// The trend couldn't be calculated (e.g. there aren't enough readings)
+    NotComputable,
+    /// The glucose is changing faster than the sensor can measure
+    RateOutOfRange
+}
+impl Trend {
+    /// Parses the trend name used by the Dexcom share API (e.g. `"FortyFiveUp"`)
+    /// 
+    /// - NOTE: Unrecognized names (including `"None"`) are treated as `NotComputable`
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "DoubleUp" => Self::DoubleUp,
+            "SingleUp" => Self::SingleUp,
+            "FortyFiveUp" => Self::FortyFiveUp,
+            "Flat" => Self::Flat,
+            "FortyFiveDown" => Self::FortyFiveDown,
+            "SingleDown" => Self::SingleDown,
+            "DoubleDown" => Self::DoubleDown,
+            "RateOutOfRange" => Self::RateOutOfRange,
+            _ => Self::NotComputable
+        }
+    }
+
+    /// Returns the arrow for the trend in the provided style (empty if there's no direction)
+    pub fn arrow(self, display: TrendDisplay) -> &'static str {
+        match display {
+            TrendDisplay::TextArrow => match self {
+                Self::DoubleUp => "⇈",
+                Self::SingleUp => "↑",
+                Self::FortyFiveUp => "↗",
+                Self::Flat => "→",
+                Self::FortyFiveDown => "↘",
+                Self::SingleDown => "↓",
+                Self::DoubleDown => "⇊",
+                Self::NotComputable | Self::RateOutOfRange => ""
+            },
+            TrendDisplay::Emoji => match self {
+                Self::DoubleUp => "⏫",
+                Self::SingleUp => "⬆️",
+                Self::FortyFiveUp => "↗️",
+                Self::Flat => "➡️",
+                Self::FortyFiveDown => "↘️",
+                Self::SingleDown => "⬇️",
+                Self::DoubleDown => "⏬",
+                Self::NotComputable | Self::RateOutOfRange => ""
+            }
+        }
+    }
+}
+
+/// How the trend arrow is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrendDisplay {
+    /// Unicode arrows (e.g. `↗`), which render differently on every platform
+    TextArrow,
+    /// Colored emoji arrows (e.g. `↗️`)
+    Emoji
+}
+
+/// Returns the rate of change (in mg/dL per minute) over the `window` most recent readings
+/// 
+/// - NOTE: The rate is a least-squares fit, unless there are only two readings (or the window is 2 or less)
+/// - NOTE: This returns `None` if there aren't two readings with distinct timestamps
+pub fn rate(readings: &VecDeque<GlucoseMeasurement>, window: usize, source: TimestampSource) -> Option<f64> {
+    match points(readings, window.max(2), source)?.as_slice() {
+        [current, previous] => change(current, previous),
+        points if points.len() > 2 => slope(points),
+        _ => None
+    }
+}
+
+/// Returns whether the latest change turned against the slope of the `window` readings before it (e.g. rising, then dropping)
+/// 
+/// - NOTE: Both the slope and the latest change have to be at least `min_rate` (in mg/dL per minute), so noise isn't a reversal
+/// - NOTE: This needs at least three readings, and the slope uses at least two
+pub fn reversed(readings: &VecDeque<GlucoseMeasurement>, window: usize, source: TimestampSource, min_rate: f64) -> bool {
+    let Some(points) = points(readings, window.max(2) + 1, source) else {
+        return false;
+    };
+    let [current, previous, _, ..] = points.as_slice() else {
+        return false;
+    };
+
+    let (Some(latest), Some(earlier)) = (change(current, previous), slope(&points[1..])) else {
+        return false;
+    };
+    latest.abs() >= min_rate && earlier.abs() >= min_rate && latest.signum() != earlier.signum()
+}
+
+/// Returns the (minutes, value) pairs of the `count` newest readings (newest first), relative to the newest one
+fn points(readings: &VecDeque<GlucoseMeasurement>, count: usize, source: TimestampSource) -> Option<Vec<(f64, f64)>> {
+    let newest = readings.back()?.timestamp_from(source)?;
+    Some(readings
+    .iter()
+    .rev()
+    .take(count)
+    .filter_map(|r| Some(((r.timestamp_from(source)? - newest).num_seconds() as f64 / 60.0, r.value as f64)))
+    .collect())
+}
+
+/// Returns the rate of change between two points
+/// 
+/// - NOTE: This returns `None` if the current point isn't after the previous one
+fn change(current: &(f64, f64), previous: &(f64, f64)) -> Option<f64> {
+    let minutes = current.0 - previous.0;
+    if minutes <= 0.0 {
+        return None;
+    }
+    Some((current.1 - previous.1) / minutes)
+}
+
+/// Returns how long (in minutes) the newest readings have stayed within `tolerance` mg/dL of each other
+/// 
+/// - NOTE: This returns `None` unless at least the two newest readings are within the tolerance
+pub fn flatline_minutes(readings: &VecDeque<GlucoseMeasurement>, tolerance: u32, source: TimestampSource) -> Option<i64> {
+    let newest = readings.back()?;
+    let (mut min, mut max) = (newest.value, newest.value);
+    let oldest = readings.iter().rev().skip(1)
+    .take_while(|r| {
+        (min, max) = (min.min(r.value), max.max(r.value));
+        max - min <= tolerance
+    })
+    .last()?;
+    Some((newest.timestamp_from(source)? - oldest.timestamp_from(source)?).num_minutes())
+}
+
+/// Estimates the A1C (in %) from the average glucose, using the ADAG formula (eAG = 28.7 × A1C − 46.7)
+/// 
+/// - NOTE: This returns `None` if there are no values
+pub fn estimated_a1c(values: &VecDeque<u32>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let average = values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64;
+    Some((average + 46.7) / 28.7)
+}
+
+/// Returns the least-squares slope of the points
+/// 
+/// - NOTE: This returns `None` if every point has the same x value
+pub fn slope(points: &[(f64, f64)]) -> Option<f64> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+
+    let covariance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let variance: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    if variance == 0.0 {
+        return None;
+    }
+
+    Some(covariance / variance)
+}
+
+/// Estimates how many minutes it'll take to drop below the low threshold at the current rate
+/// 
+/// - NOTE: This returns `None` unless we're dropping toward the threshold and will cross it within `horizon` minutes
+pub fn minutes_to_low(value: u32, rate: f64, low: u32, horizon: u32) -> Option<u32> {
+    // We're not dropping, or we're already low
+    if rate >= 0.0 || value < low {
+        return None;
+    }
+
+    let minutes = (value - low) as f64 / -rate;
+    if !minutes.is_finite() || minutes > horizon as f64 {
+        return None;
+    }
+
+    // Never show "~0 min", since we aren't low yet
+    Some((minutes.round() as u32).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_minutes_when_dropping() {
+        assert_eq!(minutes_to_low(100, -2.0, 70, 60), Some(15));
+    }
+
+    #[test]
+    fn ignores_rising_or_flat_glucose() {
+        assert_eq!(minutes_to_low(100, 2.0, 70, 60), None);
+        assert_eq!(minutes_to_low(100, 0.0, 70, 60), None);
+    }
+
+    #[test]
+    fn ignores_crossings_beyond_the_horizon() {
+        assert_eq!(minutes_to_low(200, -1.0, 70, 60), None);
+    }
+
+    #[test]
+    fn ignores_values_that_are_already_low() {
+        assert_eq!(minutes_to_low(60, -3.0, 70, 60), None);
+    }
+
+    #[test]
+    fn clamps_imminent_crossings_to_one_minute() {
+        assert_eq!(minutes_to_low(70, -5.0, 70, 60), Some(1));
+    }
+
+    fn reading(minutes: i64, value: u32) -> GlucoseMeasurement {
+        let date = format!("Date({})", 1723472400000 + minutes * 60_000);
+        GlucoseMeasurement { wt: date.clone(), st: date.clone(), dt: date, value, trend: "Flat".to_string(), raw_value: None }
+    }
+
+    #[test]
+    fn rate_uses_two_points_by_default() {
+        let readings = VecDeque::from([reading(0, 100), reading(5, 120), reading(10, 110)]);
+        assert_eq!(rate(&readings, 2, TimestampSource::Wt), Some(-2.0));
+    }
+
+    #[test]
+    fn rate_fits_a_line_over_the_window() {
+        let readings = VecDeque::from([reading(0, 100), reading(5, 120), reading(10, 110)]);
+        assert_eq!(rate(&readings, 3, TimestampSource::Wt), Some(1.0));
+    }
+
+    #[test]
+    fn rate_needs_two_readings() {
+        assert_eq!(rate(&VecDeque::from([reading(0, 100)]), 3, TimestampSource::Wt), None);
+    }
+
+    #[test]
+    fn detects_sharp_reversals() {
+        // Rising 2 mg/dL per minute, then dropping 3 mg/dL per minute
+        let readings = VecDeque::from([reading(0, 100), reading(5, 110), reading(10, 120), reading(15, 105)]);
+        assert!(reversed(&readings, 3, TimestampSource::Wt, 1.0));
+        assert!(!reversed(&readings, 3, TimestampSource::Wt, 3.5));
+
+        // Still rising, just slower
+        let readings = VecDeque::from([reading(0, 100), reading(5, 110), reading(10, 120), reading(15, 125)]);
+        assert!(!reversed(&readings, 3, TimestampSource::Wt, 0.5));
+
+        // One change isn't enough to have a direction to reverse
+        assert!(!reversed(&VecDeque::from([reading(0, 100), reading(5, 110)]), 3, TimestampSource::Wt, 0.5));
+    }
+
+    #[test]
+    fn flatlines_end_at_the_first_reading_outside_the_tolerance() {
+        let readings = VecDeque::from([reading(0, 140), reading(5, 112), reading(10, 114), reading(15, 113)]);
+        assert_eq!(flatline_minutes(&readings, 2, TimestampSource::Wt), Some(10));
+        assert_eq!(flatline_minutes(&readings, 0, TimestampSource::Wt), None);
+        assert_eq!(flatline_minutes(&VecDeque::from([reading(0, 112)]), 2, TimestampSource::Wt), None);
+    }
+
+    #[test]
+    fn estimates_a1c_from_the_average() {
+        // An average of 126 mg/dL is an A1C of 6%
+        let a1c = estimated_a1c(&VecDeque::from([116, 136])).unwrap();
+        assert!((a1c - 6.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn maps_trend_names_to_arrows() {
+        assert_eq!(Trend::from_name("FortyFiveUp").arrow(TrendDisplay::TextArrow), "↗");
+        assert_eq!(Trend::from_name("FortyFiveUp").arrow(TrendDisplay::Emoji), "↗️");
+    }
+
+    #[test]
+    fn shows_nothing_without_a_direction() {
+        for name in ["NotComputable", "RateOutOfRange", "None", "garbage"] {
+            assert_eq!(Trend::from_name(name).arrow(TrendDisplay::Emoji), "");
+        }
+    }
+}